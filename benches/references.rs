@@ -0,0 +1,124 @@
+//! Measures how fast references can be created and dropped, for workloads with many short-lived
+//! references, like code that walks large objects.
+//!
+//! Run with `cargo bench --bench references`.  The number of references per workload defaults to
+//! a million, and can be passed as an argument.
+//!
+//! The `legacy stash` workloads emulate how references were stored before the handle table: each
+//! reference got a new index in the heap stash from an ever increasing counter, and deleted it
+//! when dropped.
+use std::env;
+use std::hint;
+use std::time;
+
+/// The number of references that are alive at once in the batched workloads.
+const BATCH: usize = 1000;
+
+fn main() {
+    let count = env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(1_000_000);
+
+    let ctx = duk::Context::new();
+    run("reference, one at a time", count, || {
+        for i in 0..count {
+            hint::black_box(duk::Value::Number(i as f64).to_reference(&ctx));
+        }
+    });
+    run("reference, batched", count, || {
+        let mut refs = Vec::with_capacity(BATCH);
+        for i in 0..count {
+            refs.push(duk::Value::Number(i as f64).to_reference(&ctx));
+            if refs.len() == BATCH {
+                refs.clear();
+            }
+        }
+    });
+    let object = ctx.eval_string("({value: {}})").unwrap();
+    run("reference, property get", count, || {
+        for _ in 0..count {
+            hint::black_box(object.get("value").unwrap());
+        }
+    });
+    drop(object);
+    drop(ctx);
+
+    let legacy = legacy::Stash::new();
+    run("legacy stash, one at a time", count, || {
+        for i in 0..count {
+            legacy.drop_ref(hint::black_box(legacy.number_ref(i as f64)));
+        }
+    });
+    run("legacy stash, batched", count, || {
+        let mut refs = Vec::with_capacity(BATCH);
+        for i in 0..count {
+            refs.push(legacy.number_ref(i as f64));
+            if refs.len() == BATCH {
+                for idx in refs.drain(..) {
+                    legacy.drop_ref(idx);
+                }
+            }
+        }
+    });
+}
+
+fn run<F: FnOnce()>(name: &str, count: usize, workload: F) {
+    let start = time::Instant::now();
+    workload();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<32} {:>10.3?} {:>8.1} ns/reference",
+        name,
+        elapsed,
+        elapsed.as_nanos() as f64 / count as f64
+    );
+}
+
+mod legacy {
+    use std::cell;
+    use std::ptr;
+
+    pub struct Stash {
+        raw: *mut duk_sys::duk_context,
+        next_idx: cell::Cell<duk_sys::duk_uarridx_t>,
+    }
+
+    impl Stash {
+        pub fn new() -> Stash {
+            let raw = unsafe { duk_sys::duk_create_heap(None, None, None, ptr::null_mut(), None) };
+            assert!(!raw.is_null());
+            Stash {
+                raw,
+                next_idx: cell::Cell::new(0),
+            }
+        }
+
+        pub fn number_ref(&self, number: f64) -> duk_sys::duk_uarridx_t {
+            let idx = self.next_idx.get();
+            self.next_idx.set(idx + 1);
+            unsafe {
+                duk_sys::duk_push_number(self.raw, number);
+                duk_sys::duk_push_heap_stash(self.raw);
+                duk_sys::duk_dup(self.raw, -2);
+                duk_sys::duk_put_prop_index(self.raw, -2, idx);
+                duk_sys::duk_pop_2(self.raw);
+            }
+            idx
+        }
+
+        pub fn drop_ref(&self, idx: duk_sys::duk_uarridx_t) {
+            unsafe {
+                duk_sys::duk_push_heap_stash(self.raw);
+                duk_sys::duk_del_prop_index(self.raw, -1, idx);
+                duk_sys::duk_pop(self.raw);
+            }
+        }
+    }
+
+    impl Drop for Stash {
+        fn drop(&mut self) {
+            unsafe { duk_sys::duk_destroy_heap(self.raw) }
+        }
+    }
+}
@@ -1,4 +1,13 @@
+#[cfg(feature = "bindgen")]
+extern crate bindgen;
 extern crate cc;
+#[cfg(feature = "system")]
+extern crate pkg_config;
+
+use std::env;
+use std::fs;
+use std::path;
+use std::process;
 
 fn main() {
     let mut config = cc::Build::new();
@@ -20,14 +29,452 @@ fn main() {
         config.define("DUK_OPT_DEBUG_WRITE", Some("__duktape_sys_debug_write"));
     }
 
-    config.include("duktape/src");
+    // Watching environment variables stops cargo from watching the package, so list its inputs.
+    for input in &["build.rs", "duktape", "src/wrapper.c", "src/wrapper.h", "src/ffi.rs"] {
+        println!("cargo:rerun-if-changed={}", input);
+    }
+
+    check_features();
+    let overrides = config_overrides();
+    let declarations = config_declarations();
+
+    // A prebuilt library may be called `duktape` too, so the wrapper gets a name of its own.
+    let (include_dir, output) = match duktape() {
+        Duktape::Sources(dir) => {
+            let src_dir = configure(&dir, &overrides, &declarations);
+            config.file(src_dir.join("duktape.c"));
+            (src_dir, "libduktape.a")
+        }
+        Duktape::Library(include_dir) => {
+            if !overrides.is_empty() || builtins_changed() {
+                panic!(
+                    "the configuration of a prebuilt Duktape can't be changed, so build it with \
+                     the DUK_USE_* options that the enabled features need instead"
+                );
+            }
+            (include_dir, "libduktape-wrapper.a")
+        }
+    };
+    check_version(&include_dir);
+    #[cfg(feature = "bindgen")]
+    generate_bindings(&include_dir);
+
+    config.include(&include_dir);
     config.include("duktape/extras/logging");
     config.include("duktape/extras/module-node");
     config.flag("-std=c99");
-    config.file("duktape/src/duktape.c");
     config.file("duktape/extras/logging/duk_logging.c");
     config.file("duktape/extras/module-node/duk_module_node.c");
     config.file("src/wrapper.c");
 
-    config.compile("libduktape.a");
+    if cfg!(feature = "alloc-pool") {
+        config.include("duktape/extras/alloc-pool");
+        config.define("DUK_ALLOC_POOL_TRACK_WASTE", None);
+        config.file("duktape/extras/alloc-pool/duk_alloc_pool.c");
+    }
+
+    config.compile(output);
+}
+
+/// Where Duktape comes from.
+enum Duktape {
+    /// A directory with `duktape.c`, `duktape.h` and `duk_config.h`, to compile.
+    Sources(path::PathBuf),
+    /// A prebuilt library that is already set up for linking, with the directory of its headers.
+    Library(path::PathBuf),
+}
+
+/// Finds Duktape, which is one of these, in order of precedence:
+///
+/// - A prebuilt library in `DUKTAPE_LIB_DIR`, named `DUKTAPE_LIB_NAME` or `duktape`, with its
+///   headers in `DUKTAPE_INCLUDE_DIR` or next to it.  `DUKTAPE_STATIC` links it statically.
+/// - The system library, found with `pkg-config`, if the `system` feature is enabled.
+/// - Sources in `DUKTAPE_SRC_DIR`, like the `src` directory of a Duktape distribution.
+/// - The vendored sources.
+fn duktape() -> Duktape {
+    for var in &[
+        "DUKTAPE_LIB_DIR",
+        "DUKTAPE_LIB_NAME",
+        "DUKTAPE_INCLUDE_DIR",
+        "DUKTAPE_STATIC",
+        "DUKTAPE_SRC_DIR",
+    ] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+
+    if let Some(lib_dir) = env::var_os("DUKTAPE_LIB_DIR") {
+        let lib_dir = path::PathBuf::from(lib_dir);
+        let name = env::var("DUKTAPE_LIB_NAME").unwrap_or_else(|_| "duktape".to_owned());
+        let kind = if env::var_os("DUKTAPE_STATIC").is_some() {
+            "static"
+        } else {
+            "dylib"
+        };
+        println!("cargo:rustc-link-search=native={}", lib_dir.display());
+        println!("cargo:rustc-link-lib={}={}", kind, name);
+        let include_dir = env::var_os("DUKTAPE_INCLUDE_DIR")
+            .map(path::PathBuf::from)
+            .unwrap_or(lib_dir);
+        return Duktape::Library(include_dir);
+    }
+
+    #[cfg(feature = "system")]
+    {
+        let library = pkg_config::Config::new()
+            .probe("duktape")
+            .expect("could not find Duktape with pkg-config");
+        let include_dir = library
+            .include_paths
+            .iter()
+            .find(|dir| dir.join("duktape.h").exists())
+            .cloned()
+            .expect("pkg-config found Duktape without duktape.h");
+        return Duktape::Library(include_dir);
+    }
+
+    #[allow(unreachable_code)]
+    match env::var_os("DUKTAPE_SRC_DIR") {
+        Some(src_dir) => Duktape::Sources(path::PathBuf::from(src_dir)),
+        None => Duktape::Sources(path::PathBuf::from("duktape/src")),
+    }
+}
+
+/// Fails the build if the Duktape headers are for another version than the one that `ffi.rs`
+/// was generated for.
+fn check_version(include_dir: &path::Path) {
+    let header = include_dir.join("duktape.h");
+    println!("cargo:rerun-if-changed={}", header.display());
+    let header = fs::read_to_string(&header)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", header.display(), e));
+    let found = header
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("#define DUK_VERSION "))
+        .map(|value| value.trim().trim_end_matches('L'))
+        .next()
+        .expect("duktape.h does not define DUK_VERSION");
+
+    let ffi = fs::read_to_string("src/ffi.rs").expect("could not read src/ffi.rs");
+    let expected = ffi
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("pub const DUK_VERSION: u32 = "))
+        .map(|value| value.trim_end_matches(';'))
+        .next()
+        .expect("src/ffi.rs does not define DUK_VERSION");
+
+    if found != expected {
+        panic!(
+            "Duktape has DUK_VERSION {}, but the bindings were generated for {}",
+            found, expected
+        );
+    }
+}
+
+/// Generates `ffi.rs` in `OUT_DIR` from the headers that are compiled, so that the constants that
+/// are derived from macros match the configuration.  The settings are those of
+/// `examples/gen-wrapper.rs`, which generates the checked-in bindings.
+#[cfg(feature = "bindgen")]
+fn generate_bindings(include_dir: &path::Path) {
+    let out_dir = path::PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    bindgen::Builder::default()
+        .header("src/wrapper.h")
+        .clang_arg(format!("-I{}", include_dir.display()))
+        .clang_arg("-Iduktape/extras/logging")
+        .clang_arg("-Iduktape/extras/module-node")
+        .clang_arg("-std=c99")
+        .whitelist_function("duk_.*")
+        .whitelist_function("DUK_.*")
+        .whitelist_var("duk_.*")
+        .whitelist_var("DUK_.*")
+        .whitelist_type("duk_.*")
+        .whitelist_type("DUK_.*")
+        .derive_debug(true)
+        .generate()
+        .expect("could not generate the Duktape bindings")
+        .write_to_file(out_dir.join("ffi.rs"))
+        .expect("could not write the Duktape bindings");
+}
+
+/// How a `DUK_USE_*` option is set in the generated `duk_config.h`.
+enum Setting {
+    Defined,
+    Undefined,
+    Value(&'static str),
+}
+
+/// Cargo features that can't be combined, and why.
+const CONFLICTS: &[(&str, &str, &str)] = &[(
+    "low-memory",
+    "debugger",
+    "the debugger needs the line number tables that low-memory builds drop",
+)];
+
+/// Fails the build for features that would produce a broken Duktape configuration.
+///
+/// Conflicts between the resulting `DUK_USE_*` options themselves, like `DUK_USE_HEAPPTR16` with
+/// `DUK_USE_DEBUG`, are rejected by the checks in `duk_config.h`, which run after the overrides.
+fn check_features() {
+    let enabled = |feature: &str| {
+        let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+        env::var_os(var).is_some()
+    };
+    for &(a, b, reason) in CONFLICTS {
+        if enabled(a) && enabled(b) {
+            panic!("the `{}` and `{}` features can't be combined: {}", a, b, reason);
+        }
+    }
+}
+
+/// The `DUK_USE_*` options that differ from the vendored `duk_config.h`, and how they should be
+/// set.  Options with parameters include them in their name.
+fn config_overrides() -> Vec<(&'static str, Setting)> {
+    use Setting::*;
+
+    let mut overrides = Vec::new();
+
+    if cfg!(feature = "debugger") {
+        overrides.push(("DUK_USE_DEBUGGER_SUPPORT", Defined));
+        overrides.push(("DUK_USE_DEBUGGER_INSPECT", Defined));
+        overrides.push(("DUK_USE_DEBUGGER_PAUSE_UNCAUGHT", Defined));
+        overrides.push(("DUK_USE_INTERRUPT_COUNTER", Defined));
+    }
+
+    if cfg!(feature = "fastint") {
+        overrides.push(("DUK_USE_FASTINT", Defined));
+    }
+
+    if cfg!(feature = "exec-timeout") {
+        overrides.push(("DUK_USE_INTERRUPT_COUNTER", Defined));
+        overrides.push((
+            "DUK_USE_EXEC_TIMEOUT_CHECK(udata)",
+            Value("__duktape_sys_exec_timeout_check((udata))"),
+        ));
+    }
+
+    if cfg!(feature = "no-regexp") {
+        overrides.push(("DUK_USE_REGEXP_SUPPORT", Undefined));
+    }
+
+    if cfg!(feature = "no-proxy") {
+        overrides.push(("DUK_USE_ES6_PROXY", Undefined));
+    }
+
+    if cfg!(feature = "no-symbols") {
+        overrides.push(("DUK_USE_SYMBOL_BUILTIN", Undefined));
+    }
+
+    if cfg!(feature = "no-cbor") {
+        overrides.push(("DUK_USE_CBOR_SUPPORT", Undefined));
+        overrides.push(("DUK_USE_CBOR_BUILTIN", Undefined));
+    }
+
+    // Based on `config/examples/rom_builtins.yaml`.  Globals that scripts define go to a RAM
+    // global object that inherits from the ROM one.
+    if cfg!(feature = "rom-builtins") {
+        overrides.push(("DUK_USE_ROM_STRINGS", Defined));
+        overrides.push(("DUK_USE_ROM_OBJECTS", Defined));
+        overrides.push(("DUK_USE_ROM_GLOBAL_INHERIT", Defined));
+    }
+
+    // Based on `extras/alloc-pool/ptrcomp.yaml`.  Heap pointers are compressed relative to the
+    // single pool that was initialized last.
+    if cfg!(feature = "alloc-pool-ptrcomp") {
+        overrides.push(("DUK_USE_REFCOUNT16", Defined));
+        overrides.push(("DUK_USE_STRHASH16", Defined));
+        overrides.push(("DUK_USE_STRLEN16", Defined));
+        overrides.push(("DUK_USE_BUFLEN16", Defined));
+        overrides.push(("DUK_USE_OBJSIZES16", Defined));
+        overrides.push(("DUK_USE_HSTRING_CLEN", Undefined));
+        overrides.push(("DUK_USE_HOBJECT_HASH_PART", Undefined));
+        overrides.push(("DUK_USE_HEAPPTR16", Defined));
+        overrides.push((
+            "DUK_USE_HEAPPTR_ENC16(ud,p)",
+            Value("duk_alloc_pool_enc16((p))"),
+        ));
+        overrides.push((
+            "DUK_USE_HEAPPTR_DEC16(ud,p)",
+            Value("duk_alloc_pool_dec16((p))"),
+        ));
+    }
+
+    // Based on `config/examples/low_memory.yaml`, but keeping the error messages and the parts of
+    // the API that the wrapper relies on.
+    if cfg!(feature = "low-memory") {
+        overrides.push(("DUK_USE_PREFER_SIZE", Defined));
+        overrides.push(("DUK_USE_EXEC_PREFER_SIZE", Defined));
+        overrides.push(("DUK_USE_FAST_REFCOUNT_DEFAULT", Undefined));
+        overrides.push(("DUK_USE_JSON_QUOTESTRING_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_JSON_DECSTRING_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_JSON_DECNUMBER_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_JSON_EATWHITE_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_BASE64_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_HEX_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_IDCHAR_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_ARRAY_PROP_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_ARRAY_FASTPATH", Undefined));
+        overrides.push(("DUK_USE_LEXER_SLIDING_WINDOW", Undefined));
+        overrides.push(("DUK_USE_PC2LINE", Undefined));
+        overrides.push(("DUK_USE_SOURCE_NONBMP", Undefined));
+        overrides.push(("DUK_USE_STRTAB_MINSIZE", Value("128")));
+        overrides.push(("DUK_USE_STRTAB_MAXSIZE", Value("128")));
+        overrides.push(("DUK_USE_STRTAB_SHRINK_LIMIT", Value("0")));
+        overrides.push(("DUK_USE_STRTAB_GROW_LIMIT", Value("65536")));
+        overrides.push(("DUK_USE_LITCACHE_SIZE", Undefined));
+        overrides.push(("DUK_USE_HSTRING_ARRIDX", Undefined));
+        overrides.push(("DUK_USE_HSTRING_LAZY_CLEN", Undefined));
+        overrides.push(("DUK_USE_HOBJECT_HASH_PROP_LIMIT", Value("64")));
+        overrides.push(("DUK_USE_CACHE_ACTIVATION", Undefined));
+        overrides.push(("DUK_USE_CACHE_CATCHER", Undefined));
+        overrides.push(("DUK_USE_REGEXP_CANON_BITMAP", Undefined));
+        overrides.push(("DUK_USE_VALSTACK_UNSAFE", Defined));
+        overrides.push(("DUK_USE_FATAL_MAXLEN", Value("64")));
+    }
+
+    overrides
+}
+
+/// Declarations that the overridden options refer to.
+fn config_declarations() -> Vec<&'static str> {
+    let mut declarations = Vec::new();
+
+    if cfg!(feature = "exec-timeout") {
+        declarations.push("duk_bool_t __duktape_sys_exec_timeout_check(void *udata);");
+    }
+
+    // The pointer compression functions are inline functions in this header.
+    if cfg!(feature = "alloc-pool-ptrcomp") {
+        declarations.push("#include \"duk_alloc_pool.h\"");
+    }
+
+    declarations
+}
+
+/// Whether the features remove built-in objects, whose tables in the vendored `duktape.c` were
+/// generated for the default configuration.
+fn builtins_changed() -> bool {
+    cfg!(feature = "no-regexp")
+        || cfg!(feature = "no-proxy")
+        || cfg!(feature = "no-symbols")
+        || cfg!(feature = "no-cbor")
+        || cfg!(feature = "rom-builtins")
+        || !builtin_files().is_empty()
+}
+
+/// The YAML files with built-in objects and strings to apply over the default ones, from the
+/// `DUKTAPE_BUILTIN_FILES` path list, as `tools/configure.py --builtin-file` takes them.
+fn builtin_files() -> Vec<path::PathBuf> {
+    println!("cargo:rerun-if-env-changed=DUKTAPE_BUILTIN_FILES");
+    match env::var_os("DUKTAPE_BUILTIN_FILES") {
+        Some(files) => env::split_paths(&files)
+            .filter(|file| !file.as_os_str().is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the directory with the Duktape sources to compile.
+///
+/// If any options are overridden, new sources are put in `OUT_DIR`, since `duktape.c` always
+/// includes the config header next to it.
+fn configure(
+    sources: &path::Path,
+    overrides: &[(&str, Setting)],
+    declarations: &[&str],
+) -> path::PathBuf {
+    if overrides.is_empty() && !builtins_changed() {
+        return sources.to_owned();
+    }
+
+    let out_dir = path::PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    let src_dir = out_dir.join("duktape");
+    fs::create_dir_all(&src_dir).expect("could not create the Duktape source directory");
+    if builtins_changed() {
+        if env::var_os("DUKTAPE_SRC_DIR").is_some() {
+            panic!("the built-ins can only be changed for the vendored Duktape");
+        }
+        run_configure_py(&src_dir, overrides, declarations);
+    } else {
+        write_config(sources, &src_dir, overrides, declarations);
+    }
+    src_dir
+}
+
+/// Copies the sources with a `duk_config.h` that sets the options at its
+/// `__OVERRIDE_DEFINES__` marker, like `tools/configure.py` does.
+fn write_config(
+    sources: &path::Path,
+    src_dir: &path::Path,
+    overrides: &[(&str, Setting)],
+    declarations: &[&str],
+) {
+    for file in &["duktape.c", "duktape.h"] {
+        fs::copy(sources.join(file), src_dir.join(file)).expect("could not copy Duktape sources");
+    }
+
+    let mut defines = String::new();
+    for (option, setting) in overrides {
+        let name = option.split('(').next().unwrap();
+        defines.push_str(&format!("#undef {}\n", name));
+        match *setting {
+            Setting::Defined => defines.push_str(&format!("#define {}\n", option)),
+            Setting::Undefined => {}
+            Setting::Value(value) => defines.push_str(&format!("#define {} {}\n", option, value)),
+        }
+    }
+    // Declarations may depend on the options, like `tools/configure.py` fixup lines can.
+    for declaration in declarations {
+        defines.push_str(declaration);
+        defines.push('\n');
+    }
+
+    let marker = "/* __OVERRIDE_DEFINES__ */\n";
+    let duk_config = fs::read_to_string(sources.join("duk_config.h"))
+        .expect("could not read duk_config.h");
+    if !duk_config.contains(marker) {
+        panic!("duk_config.h has no override marker");
+    }
+    let duk_config = duk_config.replacen(marker, &format!("{}{}", marker, defines), 1);
+    fs::write(src_dir.join("duk_config.h"), duk_config).expect("could not write duk_config.h");
+}
+
+/// Generates the sources with the vendored `tools/configure.py`, which also regenerates the
+/// built-in object tables.  It needs Python 2 with PyYAML, and `DUKTAPE_PYTHON` can point at the
+/// interpreter to use.
+fn run_configure_py(src_dir: &path::Path, overrides: &[(&str, Setting)], declarations: &[&str]) {
+    println!("cargo:rerun-if-env-changed=DUKTAPE_PYTHON");
+    let python = env::var("DUKTAPE_PYTHON").unwrap_or_else(|_| "python2".to_owned());
+
+    let mut command = process::Command::new(&python);
+    command
+        .arg("duktape/tools/configure.py")
+        .arg("--quiet")
+        .arg("--output-directory")
+        .arg(src_dir);
+    for (option, setting) in overrides {
+        match *setting {
+            Setting::Defined => command.arg(format!("-D{}", option)),
+            Setting::Undefined => command.arg(format!("-U{}", option)),
+            Setting::Value(value) => command.arg(format!("-D{}={}", option, value)),
+        };
+    }
+    for declaration in declarations {
+        command.arg("--fixup-line").arg(declaration);
+    }
+    if cfg!(feature = "rom-builtins") {
+        command.arg("--rom-support");
+    }
+    for file in builtin_files() {
+        println!("cargo:rerun-if-changed={}", file.display());
+        command.arg("--builtin-file").arg(file);
+    }
+
+    let status = command.status().unwrap_or_else(|e| {
+        panic!(
+            "could not run {} for tools/configure.py, set DUKTAPE_PYTHON to a Python 2 \
+             interpreter with PyYAML: {}",
+            python, e
+        )
+    });
+    if !status.success() {
+        panic!("tools/configure.py failed with {}", status);
+    }
 }
@@ -0,0 +1,96 @@
+//! Bindings for the pool allocator in `extras/alloc-pool`, which serves all allocations from a
+//! caller provided memory region.
+//!
+//! The extra is compiled with `DUK_ALLOC_POOL_TRACK_WASTE`, but without
+//! `DUK_ALLOC_POOL_TRACK_HIGHWATER`, so the high water marks in the statistics are always zero.
+use libc::{c_char, c_int, c_uint, c_void, size_t};
+
+use ffi::duk_size_t;
+
+/// The configuration of the pool for one block size.  A pool gets `(a * t + b) / size` blocks,
+/// where `t` is the largest scale for which all pools fit in the region.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct duk_pool_config {
+    /// The block size, which must be divisible by 4 and at least the size of a pointer.
+    pub size: c_uint,
+    pub a: c_uint,
+    pub b: c_uint,
+}
+
+/// An entry in the free list of a pool.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct duk_pool_free {
+    pub next: *mut duk_pool_free,
+}
+
+/// The state of the pool for one block size.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct duk_pool_state {
+    pub first: *mut duk_pool_free,
+    pub alloc_end: *mut c_char,
+    pub size: c_uint,
+    pub count: c_uint,
+}
+
+/// Statistics for the pool of one block size.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct duk_pool_stats {
+    pub used_count: size_t,
+    pub used_bytes: size_t,
+    pub free_count: size_t,
+    pub free_bytes: size_t,
+    pub waste_bytes: size_t,
+    pub hwm_used_count: size_t,
+}
+
+/// The state of all pools, which is the user data of the allocation functions.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct duk_pool_global {
+    pub num_pools: c_int,
+    pub states: *mut duk_pool_state,
+}
+
+/// Statistics for all pools together.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct duk_pool_global_stats {
+    pub used_bytes: size_t,
+    pub free_bytes: size_t,
+    pub waste_bytes: size_t,
+    pub hwm_used_bytes: size_t,
+    pub hwm_waste_bytes: size_t,
+}
+
+extern "C" {
+    /// Carves the pools out of `buffer`, which must be aligned to 4 bytes.  The configurations
+    /// must be in ascending block size, and `configs`, `states` and `global` must stay valid as
+    /// long as the pools are used.  Returns the user data for the allocation functions, or null
+    /// if even the base allocations don't fit.
+    pub fn duk_alloc_pool_init(
+        buffer: *mut c_char,
+        size: size_t,
+        configs: *const duk_pool_config,
+        states: *mut duk_pool_state,
+        num_pools: c_int,
+        global: *mut duk_pool_global,
+    ) -> *mut c_void;
+
+    pub fn duk_alloc_pool(udata: *mut c_void, size: duk_size_t) -> *mut c_void;
+
+    pub fn duk_realloc_pool(udata: *mut c_void, ptr: *mut c_void, size: duk_size_t)
+        -> *mut c_void;
+
+    pub fn duk_free_pool(udata: *mut c_void, ptr: *mut c_void);
+
+    pub fn duk_alloc_pool_get_pool_stats(s: *mut duk_pool_state, res: *mut duk_pool_stats);
+
+    pub fn duk_alloc_pool_get_global_stats(
+        g: *mut duk_pool_global,
+        res: *mut duk_pool_global_stats,
+    );
+}
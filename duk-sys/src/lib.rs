@@ -11,10 +11,49 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
+#[cfg(not(feature = "bindgen"))]
 mod ffi;
+#[cfg(feature = "bindgen")]
+mod ffi {
+    include!(concat!(env!("OUT_DIR"), "/ffi.rs"));
+}
 
 pub use ffi::*;
 
+#[cfg(feature = "alloc-pool")]
+mod alloc_pool;
+#[cfg(feature = "alloc-pool")]
+pub use alloc_pool::*;
+
+/// The source of the Promise polyfill from the Duktape distribution, for hosts that want to
+/// provide Promises.
+pub const PROMISE_POLYFILL: &str = include_str!("../duktape/polyfills/promise.js");
+
+/// Decides whether the script running in the heap with the specified user data has run for too
+/// long, and should be aborted with a `RangeError`.
+#[cfg(feature = "exec-timeout")]
+pub type ExecTimeoutCheck = fn(udata: *mut libc::c_void) -> bool;
+
+#[cfg(feature = "exec-timeout")]
+static EXEC_TIMEOUT_CHECK: ::std::sync::OnceLock<ExecTimeoutCheck> = ::std::sync::OnceLock::new();
+
+/// Installs the function that Duktape calls periodically while executing scripts, as
+/// `DUK_USE_EXEC_TIMEOUT_CHECK`.  It applies to all heaps, and can only be installed once, so
+/// this returns `false` if another function was installed already.
+#[cfg(feature = "exec-timeout")]
+pub fn set_exec_timeout_check(check: ExecTimeoutCheck) -> bool {
+    EXEC_TIMEOUT_CHECK.set(check).is_ok()
+}
+
+#[cfg(feature = "exec-timeout")]
+#[no_mangle]
+unsafe extern "C" fn __duktape_sys_exec_timeout_check(udata: *mut libc::c_void) -> duk_bool_t {
+    match EXEC_TIMEOUT_CHECK.get() {
+        Some(check) => check(udata) as duk_bool_t,
+        None => 0,
+    }
+}
+
 #[cfg(any(feature = "debug", feature = "trace", feature = "spam"))]
 #[no_mangle]
 unsafe extern "C" fn __duktape_sys_debug_write(
@@ -0,0 +1,479 @@
+//! A `console` global that forwards to the log sink or the `log` crate, modelled after Duktape's
+//! `extras/console`.
+use std::cell;
+use std::collections;
+use std::fmt::Write;
+use std::time;
+
+use crate::logging;
+use crate::Context;
+use crate::LogRecord;
+use crate::LogSource;
+use crate::Value;
+
+/// The log target of console messages, unless configured with
+/// `ContextBuilder::with_console_target`.
+pub const DEFAULT_CONSOLE_TARGET: &str = "duk:console";
+
+/// Formats objects as JX, like `extras/console`.  Scripts can replace `console.format`.
+const FORMAT: &str = "(function(E) {\n\
+    return function format(v) {\n\
+        try {\n\
+            return E('jx', v);\n\
+        } catch (e) {\n\
+            return String(v);\n\
+        }\n\
+    };\n\
+})(Duktape.enc)";
+
+/// The state of the `console` global of a context.
+pub(crate) struct Console {
+    target: String,
+    group_depth: cell::Cell<usize>,
+    timers: cell::RefCell<collections::HashMap<String, time::Instant>>,
+}
+
+impl Console {
+    pub(crate) fn new(target: Option<String>) -> Console {
+        Console {
+            target: target.unwrap_or_else(|| DEFAULT_CONSOLE_TARGET.to_owned()),
+            group_depth: cell::Cell::new(0),
+            timers: cell::RefCell::new(collections::HashMap::new()),
+        }
+    }
+}
+
+pub(crate) unsafe fn install(ctx: *mut duk_sys::duk_context) {
+    use duk_sys::*;
+
+    duk_push_object(ctx);
+
+    if 0 != duk_peval_lstring(ctx, FORMAT.as_ptr() as *const i8, FORMAT.len()) {
+        panic!("console.format failed to load: {}", crate::get_str(ctx, -1));
+    }
+    duk_put_prop_string(ctx, -2, crate::nul_str(b"format\0"));
+
+    let info = log::Level::Info as i32;
+    let functions: [(&[u8], duk_c_function, i32); 18] = [
+        (b"log\0", Some(log_handler), info),
+        (b"info\0", Some(log_handler), info),
+        (b"dir\0", Some(log_handler), info),
+        (b"debug\0", Some(log_handler), log::Level::Debug as i32),
+        (b"warn\0", Some(log_handler), log::Level::Warn as i32),
+        (b"error\0", Some(log_handler), log::Level::Error as i32),
+        (b"exception\0", Some(log_handler), log::Level::Error as i32),
+        (b"trace\0", Some(trace_handler), log::Level::Trace as i32),
+        (b"assert\0", Some(assert_handler), log::Level::Error as i32),
+        (b"table\0", Some(table_handler), info),
+        (b"group\0", Some(group_handler), GROUP_START),
+        (b"groupCollapsed\0", Some(group_handler), GROUP_START),
+        (b"groupEnd\0", Some(group_handler), GROUP_END),
+        (b"time\0", Some(time_handler), TIME_START),
+        (b"timeLog\0", Some(time_handler), TIME_LOG),
+        (b"timeEnd\0", Some(time_handler), TIME_END),
+        (b"count\0", Some(count_handler), COUNT),
+        (b"countReset\0", Some(count_handler), COUNT_RESET),
+    ];
+    for &(name, handler, magic) in functions.iter() {
+        duk_push_c_function(ctx, handler, DUK_VARARGS);
+        // The name shows up in stack traces.
+        duk_push_string(ctx, crate::nul_str(b"name\0"));
+        duk_push_string(ctx, crate::nul_str(name));
+        duk_def_prop(ctx, -3, DUK_DEFPROP_HAVE_VALUE | DUK_DEFPROP_FORCE);
+        duk_set_magic(ctx, -1, magic);
+        duk_put_prop_string(ctx, -2, crate::nul_str(name));
+    }
+
+    duk_put_global_string(ctx, crate::nul_str(b"console\0"));
+}
+
+const GROUP_START: i32 = 0;
+const GROUP_END: i32 = 1;
+const TIME_START: i32 = 0;
+const TIME_LOG: i32 = 1;
+const TIME_END: i32 = 2;
+const COUNT: i32 = 0;
+const COUNT_RESET: i32 = 1;
+
+fn level_from_magic(magic: i32) -> log::Level {
+    match magic {
+        1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+unsafe fn with_console<F, R>(ctx: *mut duk_sys::duk_context, action: F) -> R
+where
+    F: FnOnce(&Console) -> R,
+{
+    Context::with_raw(ctx, |ctx| action(&ctx.shared.console))
+}
+
+/// Logs a message for the running console function, indented by the current group depth.
+///
+/// Only the arguments of the call may be on the stack.
+unsafe fn emit(ctx: *mut duk_sys::duk_context, level: log::Level, message: &str) {
+    let (logger, depth) = with_console(ctx, |console| {
+        (console.target.clone(), console.group_depth.get())
+    });
+    let indent = "  ".repeat(depth);
+    let message = message
+        .lines()
+        .map(|line| format!("{}{}", indent, line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (file_name, line_number) = logging::caller_location(ctx);
+    let record = LogRecord {
+        level,
+        source: LogSource::Console,
+        logger,
+        message,
+        args: logging::get_args(ctx, duk_sys::duk_get_top(ctx)),
+        file_name,
+        line_number,
+    };
+    logging::emit(ctx, record);
+}
+
+/// Converts a value to a string without throwing, also for symbols and objects with throwing
+/// `toString` methods, or panicking on unpaired surrogates.
+unsafe fn safe_string(ctx: *mut duk_sys::duk_context, index: duk_sys::duk_idx_t) -> String {
+    duk_sys::duk_dup(ctx, index);
+    duk_sys::duk_safe_to_string(ctx, -1);
+    let s = crate::get_string_lossy(ctx, -1);
+    duk_sys::duk_pop(ctx);
+    s
+}
+
+/// Formats the arguments from `start` on, separated by spaces, formatting objects other than
+/// errors with `console.format`.
+unsafe fn format_args(ctx: *mut duk_sys::duk_context, start: duk_sys::duk_idx_t) -> String {
+    let top = duk_sys::duk_get_top(ctx);
+    let mut parts = Vec::new();
+    for i in start..top {
+        if 0 == duk_sys::duk_check_type_mask(ctx, i, duk_sys::DUK_TYPE_MASK_OBJECT)
+            || 0 != duk_sys::duk_get_error_code(ctx, i)
+        {
+            parts.push(safe_string(ctx, i));
+            continue;
+        }
+        duk_sys::duk_get_global_string(ctx, crate::nul_str(b"console\0"));
+        let formatted = if 1 == duk_sys::duk_is_object(ctx, -1) {
+            duk_sys::duk_get_prop_string(ctx, -1, crate::nul_str(b"format\0"));
+            duk_sys::duk_dup(ctx, i);
+            // Stack: [ ... console format arg ]
+            if 0 == duk_sys::duk_pcall(ctx, 1) {
+                Some(safe_string(ctx, -1))
+            } else {
+                None
+            }
+        } else {
+            duk_sys::duk_push_undefined(ctx);
+            None
+        };
+        duk_sys::duk_pop_2(ctx);
+        parts.push(formatted.unwrap_or_else(|| safe_string(ctx, i)));
+    }
+    parts.join(" ")
+}
+
+/// The label argument of the timer and counter functions.
+unsafe fn label(ctx: *mut duk_sys::duk_context) -> String {
+    if duk_sys::duk_get_top(ctx) == 0 || 1 == duk_sys::duk_is_undefined(ctx, 0) {
+        "default".to_owned()
+    } else {
+        safe_string(ctx, 0)
+    }
+}
+
+unsafe extern "C" fn log_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let level = level_from_magic(duk_sys::duk_get_current_magic(ctx));
+    let message = format_args(ctx, 0);
+    emit(ctx, level, &message);
+    0
+}
+
+unsafe extern "C" fn trace_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let message = format_args(ctx, 0);
+    {
+        let message = std::ffi::CString::new(message).unwrap_or_default();
+        duk_sys::duk_push_error_object_raw(
+            ctx,
+            duk_sys::DUK_ERR_ERROR as i32,
+            std::ptr::null(),
+            0,
+            crate::nul_str(b"%s\0"),
+            message.as_ptr(),
+        );
+    }
+    duk_sys::duk_push_string(ctx, crate::nul_str(b"name\0"));
+    duk_sys::duk_push_string(ctx, crate::nul_str(b"Trace\0"));
+    duk_sys::duk_def_prop(
+        ctx,
+        -3,
+        duk_sys::DUK_DEFPROP_HAVE_VALUE | duk_sys::DUK_DEFPROP_FORCE,
+    );
+    let stack = crate::get_string_property(ctx, -1, "stack").unwrap_or_default();
+    duk_sys::duk_pop(ctx);
+    emit(ctx, log::Level::Trace, &stack);
+    0
+}
+
+unsafe extern "C" fn assert_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    if duk_sys::duk_get_top(ctx) > 0 && 1 == duk_sys::duk_to_boolean(ctx, 0) {
+        return 0;
+    }
+    let message = if duk_sys::duk_get_top(ctx) > 1 {
+        format!("Assertion failed: {}", format_args(ctx, 1))
+    } else {
+        "Assertion failed".to_owned()
+    };
+    emit(ctx, log::Level::Error, &message);
+    0
+}
+
+unsafe extern "C" fn group_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let start = duk_sys::duk_get_current_magic(ctx) == GROUP_START;
+    let label = if start && duk_sys::duk_get_top(ctx) > 0 {
+        Some(format_args(ctx, 0))
+    } else {
+        None
+    };
+    if let Some(ref label) = label {
+        emit(ctx, log::Level::Info, label);
+    }
+    with_console(ctx, |console| {
+        let depth = console.group_depth.get();
+        if start {
+            console.group_depth.set(depth + 1);
+        } else {
+            console.group_depth.set(depth.saturating_sub(1));
+        }
+    });
+    0
+}
+
+unsafe extern "C" fn time_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let op = duk_sys::duk_get_current_magic(ctx);
+    let label = label(ctx);
+    let extra = if op == TIME_LOG {
+        format_args(ctx, 1)
+    } else {
+        String::new()
+    };
+    let message = with_console(ctx, |console| {
+        let mut timers = console.timers.borrow_mut();
+        if op == TIME_START {
+            return match timers.entry(label) {
+                collections::hash_map::Entry::Occupied(entry) => Some((
+                    log::Level::Warn,
+                    format!("Timer '{}' already exists", entry.key()),
+                )),
+                collections::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(time::Instant::now());
+                    None
+                }
+            };
+        }
+        let started = if op == TIME_END {
+            timers.remove(&label)
+        } else {
+            timers.get(&label).cloned()
+        };
+        match started {
+            Some(started) => {
+                let elapsed = started.elapsed().as_secs_f64() * 1000.0;
+                let mut message = format!("{}: {:.3}ms", label, elapsed);
+                if !extra.is_empty() {
+                    message.push(' ');
+                    message.push_str(&extra);
+                }
+                Some((log::Level::Info, message))
+            }
+            None => Some((
+                log::Level::Warn,
+                format!("Timer '{}' does not exist", label),
+            )),
+        }
+    });
+    if let Some((level, message)) = message {
+        emit(ctx, level, &message);
+    }
+    0
+}
+
+unsafe extern "C" fn count_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let op = duk_sys::duk_get_current_magic(ctx);
+    let label = label(ctx);
+    // Counters are kept in the heap, since they are plain numbers.
+    duk_sys::duk_push_global_stash(ctx);
+    if 0 == duk_sys::duk_get_prop_string(ctx, -1, crate::nul_str(b"consoleCounts\0")) {
+        duk_sys::duk_pop(ctx);
+        duk_sys::duk_push_object(ctx);
+        duk_sys::duk_dup_top(ctx);
+        duk_sys::duk_put_prop_string(ctx, -3, crate::nul_str(b"consoleCounts\0"));
+    }
+    // Stack: [ ... stash counts ]
+    duk_sys::duk_push_lstring(ctx, label.as_ptr() as *const i8, label.len());
+    if op == COUNT {
+        duk_sys::duk_dup_top(ctx);
+        duk_sys::duk_get_prop(ctx, -3);
+        let count = duk_sys::duk_get_uint(ctx, -1) + 1;
+        duk_sys::duk_pop(ctx);
+        duk_sys::duk_push_uint(ctx, count);
+        duk_sys::duk_put_prop(ctx, -3);
+        duk_sys::duk_pop_2(ctx);
+        emit(ctx, log::Level::Info, &format!("{}: {}", label, count));
+    } else {
+        duk_sys::duk_del_prop(ctx, -2);
+        duk_sys::duk_pop_2(ctx);
+    }
+    0
+}
+
+unsafe extern "C" fn table_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let table = if 1 == duk_sys::duk_is_object(ctx, 0) && 0 == duk_sys::duk_is_function(ctx, 0) {
+        let data = crate::copy::get_value(ctx, 0);
+        let columns = if 1 == duk_sys::duk_is_array(ctx, 1) {
+            match crate::copy::get_value(ctx, 1) {
+                Value::Array(columns) => Some(columns.iter().map(format_cell).collect()),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        render_table(&data, columns)
+    } else {
+        None
+    };
+    let message = match table {
+        Some(table) => table,
+        None => format_args(ctx, 0),
+    };
+    emit(ctx, log::Level::Info, &message);
+    0
+}
+
+/// Renders the rows of an array or object as a text table, with a column per property of the
+/// rows, or only the specified columns.  Rows that are not objects go in a `Values` column.
+fn render_table(data: &Value, columns: Option<Vec<String>>) -> Option<String> {
+    let rows: Vec<(String, &Value)> = match *data {
+        Value::Array(ref array) => array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i.to_string(), v))
+            .collect(),
+        Value::Object(ref object) => object.iter().map(|(k, v)| (k.clone(), v)).collect(),
+        _ => return None,
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut has_values = false;
+    for &(_, row) in &rows {
+        match *row {
+            Value::Object(ref object) => {
+                for key in object.keys() {
+                    if !keys.contains(key) {
+                        keys.push(key.clone());
+                    }
+                }
+            }
+            Value::Array(ref array) => {
+                for i in 0..array.len() {
+                    let key = i.to_string();
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+            }
+            _ => has_values = true,
+        }
+    }
+    if let Some(columns) = columns {
+        keys = columns;
+    }
+
+    let mut header = vec!["(index)".to_owned()];
+    header.extend(keys.iter().cloned());
+    if has_values {
+        header.push("Values".to_owned());
+    }
+    let mut lines = vec![header];
+    for (index, row) in rows {
+        let mut line = vec![index];
+        for key in &keys {
+            let cell = match *row {
+                Value::Object(ref object) => object.get(key),
+                Value::Array(ref array) => key.parse::<usize>().ok().and_then(|i| array.get(i)),
+                _ => None,
+            };
+            line.push(cell.map(format_cell).unwrap_or_default());
+        }
+        if has_values {
+            line.push(match *row {
+                Value::Object(_) | Value::Array(_) => String::new(),
+                ref value => format_cell(value),
+            });
+        }
+        lines.push(line);
+    }
+
+    let mut widths = vec![0; lines[0].len()];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let cells: Vec<String> = line
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+            .collect();
+        let _ = writeln!(table, "{}", cells.join(" | ").trim_end());
+        if i == 0 {
+            let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+            let _ = writeln!(table, "{}", rule.join("-+-"));
+        }
+    }
+    table.pop();
+    Some(table)
+}
+
+fn format_cell(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref value => format_value(value),
+    }
+}
+
+/// Formats a value like a compact Javascript literal.
+fn format_value(value: &Value) -> String {
+    match *value {
+        Value::Undefined => "undefined".to_owned(),
+        Value::Null => "null".to_owned(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Number(n) if n.is_infinite() => {
+            if n > 0.0 { "Infinity" } else { "-Infinity" }.to_owned()
+        }
+        Value::Number(n) => n.to_string(),
+        Value::String(ref s) => format!("{:?}", s),
+        Value::Array(ref array) => {
+            let items: Vec<String> = array.iter().map(format_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(ref object) => {
+            let items: Vec<String> = object
+                .iter()
+                .map(|(k, v)| format!("{}: {}", k, format_value(v)))
+                .collect();
+            format!("{{{}}}", items.join(", "))
+        }
+        Value::Bytes(ref bytes) => format!("[buffer {}]", bytes.len()),
+        Value::Foreign(name) => format!("[{}]", name),
+    }
+}
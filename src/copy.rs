@@ -0,0 +1,129 @@
+//! Copying script values into Rust without throwing or panicking on what scripts pass.
+use std::collections;
+use std::os;
+use std::ptr;
+use std::slice;
+
+use crate::Value;
+
+/// How deeply `get_value` copies nested arrays and objects.
+const MAX_VALUE_DEPTH: usize = 16;
+
+/// Copies a script value without throwing or panicking on what scripts may pass.
+///
+/// Holes of arrays become `undefined`, and strings with unpaired surrogates are converted lossily.
+/// Values that can't be copied become `Value::Foreign`: objects that contain themselves are
+/// `"cycle"`, objects nested too deeply are `"nested too deep"`, and properties whose getters or
+/// proxy traps throw are `"inaccessible"`.
+pub(crate) unsafe fn get_value(ctx: *mut duk_sys::duk_context, index: duk_sys::duk_idx_t) -> Value {
+    let index = duk_sys::duk_normalize_index(ctx, index);
+    get_value_at(ctx, index, &mut Vec::new())
+}
+
+/// Copies the value at a normalized index, where `path` holds the objects that contain it.
+unsafe fn get_value_at(
+    ctx: *mut duk_sys::duk_context,
+    index: duk_sys::duk_idx_t,
+    path: &mut Vec<*mut os::raw::c_void>,
+) -> Value {
+    let t = duk_sys::duk_get_type(ctx, index) as u32;
+    match t {
+        duk_sys::DUK_TYPE_NULL => Value::Null,
+        duk_sys::DUK_TYPE_BOOLEAN => Value::Boolean(duk_sys::duk_get_boolean(ctx, index) != 0),
+        duk_sys::DUK_TYPE_NUMBER => Value::Number(duk_sys::duk_get_number(ctx, index)),
+        duk_sys::DUK_TYPE_STRING => Value::String(crate::get_string_lossy(ctx, index)),
+        duk_sys::DUK_TYPE_BUFFER => {
+            let mut size = 0;
+            let data = duk_sys::duk_get_buffer(ctx, index, &mut size);
+            if data.is_null() {
+                Value::Bytes(Vec::new())
+            } else {
+                Value::Bytes(slice::from_raw_parts(data as *const u8, size).to_vec())
+            }
+        }
+        duk_sys::DUK_TYPE_POINTER => Value::Foreign("pointer"),
+        duk_sys::DUK_TYPE_LIGHTFUNC => Value::Foreign("lightfunc"),
+        duk_sys::DUK_TYPE_OBJECT => {
+            let ptr = duk_sys::duk_get_heapptr(ctx, index);
+            if path.contains(&ptr) {
+                return Value::Foreign("cycle");
+            }
+            // Each level holds the object, its keys, and a property with its key.
+            if path.len() == MAX_VALUE_DEPTH || 0 == duk_sys::duk_check_stack(ctx, 4) {
+                return Value::Foreign("nested too deep");
+            }
+            path.push(ptr);
+            let value = if 1 == duk_sys::duk_is_array(ctx, index) {
+                let len = duk_sys::duk_get_length(ctx, index);
+                let mut array = Vec::with_capacity(len);
+                for i in 0..len {
+                    duk_sys::duk_push_uint(ctx, i as duk_sys::duk_uint_t);
+                    array.push(get_property(ctx, index, path));
+                }
+                Value::Array(array)
+            } else {
+                let mut object = collections::BTreeMap::new();
+                duk_sys::duk_dup(ctx, index);
+                if 0 == duk_sys::duk_safe_call(ctx, Some(own_keys), ptr::null_mut(), 1, 1) {
+                    // Stack: [ ... keys ]
+                    let keys = duk_sys::duk_get_top_index(ctx);
+                    for i in 0..duk_sys::duk_get_length(ctx, keys) {
+                        duk_sys::duk_get_prop_index(ctx, keys, i as duk_sys::duk_uarridx_t);
+                        let key = crate::get_string_lossy(ctx, -1);
+                        object.insert(key, get_property(ctx, index, path));
+                    }
+                }
+                duk_sys::duk_pop(ctx);
+                Value::Object(object)
+            };
+            path.pop();
+            value
+        }
+        _ => Value::Undefined,
+    }
+}
+
+/// Pops the key at the top of the stack, and copies the property of the object at `index` with
+/// that key.
+unsafe fn get_property(
+    ctx: *mut duk_sys::duk_context,
+    index: duk_sys::duk_idx_t,
+    path: &mut Vec<*mut os::raw::c_void>,
+) -> Value {
+    duk_sys::duk_dup(ctx, index);
+    duk_sys::duk_swap_top(ctx, -2);
+    // Stack: [ ... object key ]
+    let value = if 0 == duk_sys::duk_safe_call(ctx, Some(read_property), ptr::null_mut(), 2, 1) {
+        get_value_at(ctx, duk_sys::duk_get_top_index(ctx), path)
+    } else {
+        Value::Foreign("inaccessible")
+    };
+    duk_sys::duk_pop(ctx);
+    value
+}
+
+/// Replaces an object and a key with the property of the object, in a safe call, which sees the
+/// whole value stack.
+unsafe extern "C" fn read_property(
+    ctx: *mut duk_sys::duk_context,
+    _udata: *mut os::raw::c_void,
+) -> duk_sys::duk_ret_t {
+    duk_sys::duk_get_prop(ctx, -2);
+    1
+}
+
+/// Replaces an object with an array of its own enumerable string keys, in a safe call.
+unsafe extern "C" fn own_keys(
+    ctx: *mut duk_sys::duk_context,
+    _udata: *mut os::raw::c_void,
+) -> duk_sys::duk_ret_t {
+    duk_sys::duk_enum(ctx, -1, duk_sys::DUK_ENUM_OWN_PROPERTIES_ONLY);
+    duk_sys::duk_push_array(ctx);
+    let mut i = 0;
+    // Stack: [ ... object enum keys ]
+    while 1 == duk_sys::duk_next(ctx, -2, 0) {
+        duk_sys::duk_put_prop_index(ctx, -2, i);
+        i += 1;
+    }
+    1
+}
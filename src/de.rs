@@ -518,6 +518,11 @@ impl<'de> SeqAccess<'de> for SeqAccessor {
     }
 }
 
+/// Deserializes the value at `index` on the value stack of `ctx`.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context.
 pub unsafe fn deserialize_from_stack<'de, T: serde::Deserialize<'de>>(
     ctx: *mut duk_sys::duk_context,
     index: i32,
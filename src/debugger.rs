@@ -0,0 +1,233 @@
+//! Support for the Duktape debug protocol.
+//!
+//! Duktape speaks its [binary debug protocol][1] with a debug client over a transport provided by
+//! the host.  The protocol itself is implemented by Duktape; a `DebugTransport` only has to move
+//! bytes.  `Debugger` is a client for the protocol, for driving a debugged context from Rust.
+//!
+//! [1]: https://github.com/svaarala/duktape/blob/master/doc/debugger.rst
+use std::io;
+use std::os;
+use std::result;
+use std::slice;
+use std::str;
+
+use crate::Context;
+
+mod client;
+mod dvalue;
+mod tcp;
+
+/// A handler for `AppRequest` payloads, as installed by `Context::set_debugger_request_handler`.
+pub(crate) type RequestHandler = dyn FnMut(&str) -> result::Result<String, String>;
+
+pub use self::client::Breakpoint;
+pub use self::client::DebugFrame;
+pub use self::client::DebugNotification;
+pub use self::client::DebugStatus;
+pub use self::client::DebugTargetInfo;
+pub use self::client::Debugger;
+pub use self::client::DebuggerError;
+pub use self::client::DebuggerResult;
+pub use self::dvalue::DValue;
+pub use self::tcp::TcpDebugListener;
+pub use self::tcp::TcpDebugTransport;
+pub use self::tcp::DEFAULT_DEBUG_PORT;
+
+/// A connection to a debug client.
+///
+/// Duktape calls into the transport from the thread that runs the context, both while code is
+/// running and from `Context::debugger_cooperate`.  Returning an error (or zero bytes) from `read`
+/// or `write` detaches the debugger.
+pub trait DebugTransport {
+    /// Reads at least one byte into `buf`, blocking until data is available, and returns the
+    /// number of bytes read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes at least one byte from `buf`, blocking until that is possible, and returns the
+    /// number of bytes written.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Returns the number of bytes that can be read without blocking.
+    fn peek(&mut self) -> io::Result<usize>;
+
+    /// Called when Duktape is done reading for now, which can be used to flush read buffers.
+    fn read_flush(&mut self) {}
+
+    /// Called when Duktape is done writing for now; buffered writes should be sent to the client.
+    fn write_flush(&mut self) {}
+
+    /// Called once the debugger has been detached from the context, after which the transport is
+    /// dropped.
+    fn detached(&mut self) {}
+}
+
+impl Context {
+    /// Attaches a debugger that communicates with a debug client over the specified transport.
+    ///
+    /// Duktape immediately writes its version identification line to the transport, and execution
+    /// starts out paused until the debug client resumes it.  A debugger that is already attached
+    /// is detached first.  The transport is dropped when the debugger is detached, including when
+    /// the context is dropped.
+    pub fn attach_debugger<T>(&self, transport: T)
+    where
+        T: DebugTransport + 'static,
+    {
+        // Duktape would overwrite the callbacks of an attached debugger, and leak its transport.
+        if self.debugger_attached() {
+            self.detach_debugger();
+        }
+        let transport: Box<Box<dyn DebugTransport>> = Box::new(Box::new(transport));
+        unsafe {
+            duk_sys::duk_debugger_attach(
+                self.raw,
+                Some(debug_read),
+                Some(debug_write),
+                Some(debug_peek),
+                Some(debug_read_flush),
+                Some(debug_write_flush),
+                Some(debug_request),
+                Some(debug_detached),
+                Box::into_raw(transport) as *mut os::raw::c_void,
+            );
+        }
+        self.shared.debugger_attached.set(true);
+    }
+
+    /// Whether a debugger is currently attached.
+    ///
+    /// Debug clients can detach by themselves, or by disconnecting, so hosts that want to accept
+    /// new debug clients can check this to decide when to attach a new transport.
+    pub fn debugger_attached(&self) -> bool {
+        self.shared.debugger_attached.get()
+    }
+
+    /// Detaches the attached debugger, if any.
+    pub fn detach_debugger(&self) {
+        unsafe { duk_sys::duk_debugger_detach(self.raw) }
+    }
+
+    /// Processes pending debug messages while no code is running.
+    ///
+    /// While code runs, Duktape checks for debug messages by itself.  Hosts that are idle should
+    /// call this regularly so that the debug client stays responsive.
+    pub fn debugger_cooperate(&self) {
+        unsafe { duk_sys::duk_debugger_cooperate(self.raw) }
+    }
+
+    /// Requests that execution is paused at the next opportunity, as if the debug client had sent
+    /// a pause command.
+    pub fn debugger_pause(&self) {
+        unsafe { duk_sys::duk_debugger_pause(self.raw) }
+    }
+
+    /// Sends an `AppNotify` notification to the debug client, carrying the payload as a JSON
+    /// string.  Returns whether the notification was probably delivered, which it is not if no
+    /// debugger is attached.
+    ///
+    /// `Debugger` clients can decode the payload with `DebugNotification::app_payload`.
+    pub fn debugger_notify<T>(&self, payload: &T) -> DebuggerResult<bool>
+    where
+        T: serde::Serialize,
+    {
+        let payload = serde_json::to_string(payload)?;
+        unsafe {
+            duk_sys::duk_push_lstring(self.raw, payload.as_ptr() as *const i8, payload.len());
+            Ok(1 == duk_sys::duk_debugger_notify(self.raw, 1))
+        }
+    }
+
+    /// Sets the handler for `AppRequest` commands from the debug client, replacing any previous
+    /// handler.
+    ///
+    /// Requests and replies are carried as JSON strings, like the payloads of `debugger_notify`.
+    /// Errors returned by the handler are sent to the client as error replies.  The handler stays
+    /// in place when debuggers are detached and attached again.
+    pub fn set_debugger_request_handler<Req, Rep, F>(&self, mut handler: F)
+    where
+        Req: serde::de::DeserializeOwned,
+        Rep: serde::Serialize,
+        F: FnMut(Req) -> result::Result<Rep, String> + 'static,
+    {
+        let handler: Box<RequestHandler> = Box::new(move |request| {
+            let request = serde_json::from_str(request).map_err(|e| e.to_string())?;
+            let reply = handler(request)?;
+            serde_json::to_string(&reply).map_err(|e| e.to_string())
+        });
+        *self.shared.debugger_request_handler.borrow_mut() = Some(handler);
+    }
+}
+
+unsafe fn get_transport<'a>(udata: *mut os::raw::c_void) -> &'a mut dyn DebugTransport {
+    &mut **(udata as *mut Box<dyn DebugTransport>)
+}
+
+unsafe extern "C" fn debug_read(
+    udata: *mut os::raw::c_void,
+    buffer: *mut os::raw::c_char,
+    length: duk_sys::duk_size_t,
+) -> duk_sys::duk_size_t {
+    let buf = slice::from_raw_parts_mut(buffer as *mut u8, length);
+    get_transport(udata).read(buf).unwrap_or(0)
+}
+
+unsafe extern "C" fn debug_write(
+    udata: *mut os::raw::c_void,
+    buffer: *const os::raw::c_char,
+    length: duk_sys::duk_size_t,
+) -> duk_sys::duk_size_t {
+    let buf = slice::from_raw_parts(buffer as *const u8, length);
+    get_transport(udata).write(buf).unwrap_or(0)
+}
+
+unsafe extern "C" fn debug_peek(udata: *mut os::raw::c_void) -> duk_sys::duk_size_t {
+    get_transport(udata).peek().unwrap_or(0)
+}
+
+unsafe extern "C" fn debug_read_flush(udata: *mut os::raw::c_void) {
+    get_transport(udata).read_flush()
+}
+
+unsafe extern "C" fn debug_write_flush(udata: *mut os::raw::c_void) {
+    get_transport(udata).write_flush()
+}
+
+unsafe extern "C" fn debug_request(
+    ctx: *mut duk_sys::duk_context,
+    _udata: *mut os::raw::c_void,
+    nvalues: duk_sys::duk_idx_t,
+) -> duk_sys::duk_idx_t {
+    let reply = Context::with_raw(ctx, |ctx| {
+        if nvalues != 1 || 1 != duk_sys::duk_is_string(ctx.raw, -1) {
+            return Err("expected a single JSON string".to_owned());
+        }
+        let mut len = 0;
+        let data = duk_sys::duk_get_lstring(ctx.raw, -1, &mut len);
+        let request = str::from_utf8(slice::from_raw_parts(data as *const u8, len))
+            .map_err(|e| format!("request is not valid UTF-8: {}", e))?;
+        // The handler is taken out while it runs, so that it can replace itself.
+        let handler = ctx.shared.debugger_request_handler.borrow_mut().take();
+        let mut handler = handler.ok_or_else(|| "no request handler".to_owned())?;
+        let reply = handler(request);
+        let mut slot = ctx.shared.debugger_request_handler.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(handler);
+        }
+        reply
+    });
+    match reply {
+        Ok(reply) => {
+            duk_sys::duk_push_lstring(ctx, reply.as_ptr() as *const i8, reply.len());
+            1
+        }
+        Err(message) => {
+            duk_sys::duk_push_lstring(ctx, message.as_ptr() as *const i8, message.len());
+            -1
+        }
+    }
+}
+
+unsafe extern "C" fn debug_detached(ctx: *mut duk_sys::duk_context, udata: *mut os::raw::c_void) {
+    Context::with_raw(ctx, |ctx| ctx.shared.debugger_attached.set(false));
+    let mut transport = Box::from_raw(udata as *mut Box<dyn DebugTransport>);
+    transport.detached();
+}
@@ -0,0 +1,521 @@
+//! A debug client that drives a debugged context from Rust.
+use std::collections;
+use std::io;
+use std::io::BufRead;
+use std::net;
+use std::result;
+
+use crate::debugger::dvalue::DValue;
+use crate::debugger::dvalue::Item;
+use crate::debugger::dvalue::Marker;
+
+const CMD_STATUS: i32 = 0x01;
+const CMD_THROW: i32 = 0x05;
+const CMD_DETACHING: i32 = 0x06;
+const CMD_APP_NOTIFY: i32 = 0x07;
+
+const CMD_BASIC_INFO: i32 = 0x10;
+const CMD_TRIGGER_STATUS: i32 = 0x11;
+const CMD_PAUSE: i32 = 0x12;
+const CMD_RESUME: i32 = 0x13;
+const CMD_STEP_INTO: i32 = 0x14;
+const CMD_STEP_OVER: i32 = 0x15;
+const CMD_STEP_OUT: i32 = 0x16;
+const CMD_LIST_BREAK: i32 = 0x17;
+const CMD_ADD_BREAK: i32 = 0x18;
+const CMD_DEL_BREAK: i32 = 0x19;
+const CMD_GET_VAR: i32 = 0x1a;
+const CMD_PUT_VAR: i32 = 0x1b;
+const CMD_GET_CALL_STACK: i32 = 0x1c;
+const CMD_GET_LOCALS: i32 = 0x1d;
+const CMD_EVAL: i32 = 0x1e;
+const CMD_DETACH: i32 = 0x1f;
+const CMD_APP_REQUEST: i32 = 0x22;
+
+/// A debug client for a context that a debugger is attached to.
+///
+/// The client talks to the debug target over a byte stream, usually a TCP connection to a
+/// `TcpDebugListener`.  Duktape blocks while it is paused, so the client has to run on another
+/// thread than the debugged context.
+///
+/// Notifications that arrive while waiting for the reply to a request are queued, and are
+/// returned by `next_notification` in order.
+#[derive(Debug)]
+pub struct Debugger<S = net::TcpStream>
+where
+    S: io::Read + io::Write,
+{
+    reader: io::BufReader<S>,
+    version_line: String,
+    notifications: collections::VecDeque<DebugNotification>,
+    pause_on_exception: bool,
+    uncaught_error: bool,
+}
+
+/// The type of errors that can occur while debugging.
+#[derive(Debug, failure::Fail)]
+pub enum DebuggerError {
+    /// The connection to the debug target failed.
+    #[fail(display = "{}", raw)]
+    Io { raw: io::Error },
+    /// The debug target rejected a request.
+    #[fail(display = "debug request failed ({}): {}", code, message)]
+    Request { code: i32, message: String },
+    /// The debug target sent a message that does not follow the debug protocol.
+    #[fail(display = "debug protocol violation: {}", message)]
+    Protocol { message: String },
+    /// An `AppNotify` or `AppRequest` payload could not be converted from or to JSON.
+    #[fail(display = "invalid payload: {}", raw)]
+    Payload { raw: serde_json::Error },
+}
+
+pub type DebuggerResult<A> = result::Result<A, DebuggerError>;
+
+/// A message that the debug target sent by itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DebugNotification {
+    /// The execution status changed.
+    Status(DebugStatus),
+    /// An error was thrown.
+    Throw {
+        /// Whether the error is not going to be caught.
+        fatal: bool,
+        message: String,
+        file_name: String,
+        line: u32,
+    },
+    /// The debugger is about to be detached, for the specified reason; `0` is a normal detach.
+    Detaching { reason: i32 },
+    /// A notification sent by the host with `Context::debugger_notify`, with its JSON payload.
+    AppNotify { payload: String },
+    /// A notification that this client does not know about.
+    Other { command: i32, values: Vec<DValue> },
+}
+
+/// The execution status of the debug target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugStatus {
+    pub paused: bool,
+    /// The file of the function that is executing, or an empty string if no code is running.
+    pub file_name: String,
+    pub function_name: String,
+    /// The line that will be executed next.
+    pub line: u32,
+    pub pc: u32,
+}
+
+/// A frame of the call stack, with the innermost frame first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugFrame {
+    pub file_name: String,
+    pub function_name: String,
+    pub line: u32,
+    pub pc: u32,
+}
+
+/// A breakpoint, identified by its index in the list of breakpoints.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub file_name: String,
+    pub line: u32,
+}
+
+/// Information about the debug target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugTargetInfo {
+    /// The Duktape version, like `20500` for 2.5.0.
+    pub version: i64,
+    pub git_describe: String,
+    pub target_info: String,
+    /// The pointer size of the target, in bytes.
+    pub pointer_size: i64,
+}
+
+impl Debugger<net::TcpStream> {
+    /// Connects to a `TcpDebugListener`.
+    pub fn connect<A>(addr: A) -> DebuggerResult<Debugger<net::TcpStream>>
+    where
+        A: net::ToSocketAddrs,
+    {
+        let stream = net::TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Debugger::new(stream)
+    }
+}
+
+impl<S> Debugger<S>
+where
+    S: io::Read + io::Write,
+{
+    /// Starts a debug session over the specified stream, reading the version identification line
+    /// of the target.
+    pub fn new(stream: S) -> DebuggerResult<Debugger<S>> {
+        let mut reader = io::BufReader::new(stream);
+        let mut version_line = String::new();
+        reader.read_line(&mut version_line)?;
+        let version_line = version_line.trim_end().to_owned();
+
+        let version = version_line.split(' ').next().unwrap_or("");
+        if version != duk_sys::DUK_DEBUG_PROTOCOL_VERSION.to_string() {
+            return Err(DebuggerError::Protocol {
+                message: format!("unsupported version identification {:?}", version_line),
+            });
+        }
+
+        Ok(Debugger {
+            reader,
+            version_line,
+            notifications: collections::VecDeque::new(),
+            pause_on_exception: true,
+            uncaught_error: false,
+        })
+    }
+
+    /// The version identification line that the target sent, without the line break.
+    pub fn version_line(&self) -> &str {
+        &self.version_line
+    }
+
+    /// Sets whether `wait_for_pause` should return when the target pauses for an uncaught error.
+    ///
+    /// Duktape always pauses before an uncaught error is thrown; when this is disabled, the
+    /// client resumes such pauses by itself.  Defaults to `true`.
+    pub fn set_pause_on_exception(&mut self, pause_on_exception: bool) {
+        self.pause_on_exception = pause_on_exception;
+    }
+
+    pub fn basic_info(&mut self) -> DebuggerResult<DebugTargetInfo> {
+        let values = self.request(CMD_BASIC_INFO, &[])?;
+        Ok(DebugTargetInfo {
+            version: int_at(&values, 0)?,
+            git_describe: string_at(&values, 1),
+            target_info: string_at(&values, 2),
+            pointer_size: int_at(&values, 4)?,
+        })
+    }
+
+    /// Asks the target to send a `Status` notification.
+    pub fn trigger_status(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_TRIGGER_STATUS, &[]).map(|_| ())
+    }
+
+    pub fn pause(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_PAUSE, &[]).map(|_| ())
+    }
+
+    pub fn resume(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_RESUME, &[]).map(|_| ())
+    }
+
+    /// Resumes until the next line, entering called functions.
+    pub fn step_into(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_STEP_INTO, &[]).map(|_| ())
+    }
+
+    /// Resumes until the next line of the current function.
+    pub fn step_over(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_STEP_OVER, &[]).map(|_| ())
+    }
+
+    /// Resumes until the current function returns.
+    pub fn step_out(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_STEP_OUT, &[]).map(|_| ())
+    }
+
+    /// Adds a breakpoint, returning its index.
+    pub fn add_breakpoint(&mut self, file_name: &str, line: u32) -> DebuggerResult<usize> {
+        let values = self.request(
+            CMD_ADD_BREAK,
+            &[file_name.into(), DValue::Integer(line as i32)],
+        )?;
+        Ok(int_at(&values, 0)? as usize)
+    }
+
+    /// Removes the breakpoint with the specified index.  The indices of later breakpoints shift
+    /// down by one.
+    pub fn remove_breakpoint(&mut self, index: usize) -> DebuggerResult<()> {
+        self.request(CMD_DEL_BREAK, &[DValue::Integer(index as i32)])
+            .map(|_| ())
+    }
+
+    pub fn breakpoints(&mut self) -> DebuggerResult<Vec<Breakpoint>> {
+        let values = self.request(CMD_LIST_BREAK, &[])?;
+        values
+            .chunks(2)
+            .map(|chunk| {
+                Ok(Breakpoint {
+                    file_name: string_at(chunk, 0),
+                    line: int_at(chunk, 1)? as u32,
+                })
+            })
+            .collect()
+    }
+
+    /// Lists the frames of the call stack, innermost first.
+    pub fn call_stack(&mut self) -> DebuggerResult<Vec<DebugFrame>> {
+        let values = self.request(CMD_GET_CALL_STACK, &[])?;
+        values
+            .chunks(4)
+            .map(|chunk| {
+                Ok(DebugFrame {
+                    file_name: string_at(chunk, 0),
+                    function_name: string_at(chunk, 1),
+                    line: int_at(chunk, 2)? as u32,
+                    pc: int_at(chunk, 3)? as u32,
+                })
+            })
+            .collect()
+    }
+
+    /// Lists the local variables of a frame, where `0` is the innermost frame as returned by
+    /// `call_stack`.
+    pub fn locals(&mut self, frame: usize) -> DebuggerResult<Vec<(String, DValue)>> {
+        let values = self.request(CMD_GET_LOCALS, &[level(frame)])?;
+        Ok(values
+            .chunks(2)
+            .map(|chunk| {
+                (
+                    string_at(chunk, 0),
+                    chunk.get(1).cloned().unwrap_or(DValue::Unused),
+                )
+            })
+            .collect())
+    }
+
+    /// Looks up a variable in the scope of a frame, returning `None` if it is not defined.
+    pub fn get_var(&mut self, frame: usize, name: &str) -> DebuggerResult<Option<DValue>> {
+        let values = self.request(CMD_GET_VAR, &[level(frame), name.into()])?;
+        if int_at(&values, 0)? == 0 {
+            Ok(None)
+        } else {
+            Ok(values.get(1).cloned())
+        }
+    }
+
+    /// Assigns a variable in the scope of a frame.
+    pub fn put_var(&mut self, frame: usize, name: &str, value: DValue) -> DebuggerResult<()> {
+        self.request(CMD_PUT_VAR, &[level(frame), name.into(), value])
+            .map(|_| ())
+    }
+
+    /// Evaluates an expression in the scope of a frame, or in the global scope if `frame` is
+    /// `None`.  Errors thrown by the expression are returned as `Err` with the stringified error.
+    pub fn eval(
+        &mut self,
+        frame: Option<usize>,
+        expression: &str,
+    ) -> DebuggerResult<result::Result<DValue, String>> {
+        let frame = frame.map_or(DValue::Null, level);
+        let values = self.request(CMD_EVAL, &[frame, expression.into()])?;
+        let value = values.get(1).cloned().unwrap_or(DValue::Unused);
+        if int_at(&values, 0)? == 0 {
+            Ok(Ok(value))
+        } else {
+            Ok(Err(value.as_str().unwrap_or("").to_owned()))
+        }
+    }
+
+    /// Sends an `AppRequest` to the host, which handles it with the handler that was set with
+    /// `Context::set_debugger_request_handler`.  The request and reply are carried as JSON.
+    pub fn app_request<Req, Rep>(&mut self, request: &Req) -> DebuggerResult<Rep>
+    where
+        Req: serde::Serialize,
+        Rep: serde::de::DeserializeOwned,
+    {
+        let request = serde_json::to_string(request)?;
+        let values = self.request(CMD_APP_REQUEST, &[DValue::String(request)])?;
+        match values.first() {
+            Some(DValue::String(reply)) => Ok(serde_json::from_str(reply)?),
+            _ => Err(DebuggerError::Protocol {
+                message: format!("expected a JSON string reply, got {:?}", values),
+            }),
+        }
+    }
+
+    /// Asks the target to detach the debugger.
+    pub fn detach(&mut self) -> DebuggerResult<()> {
+        self.request(CMD_DETACH, &[]).map(|_| ())
+    }
+
+    /// Waits for the next notification from the target.
+    pub fn next_notification(&mut self) -> DebuggerResult<DebugNotification> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(notification);
+        }
+        loop {
+            let (marker, values) = self.read_message()?;
+            if marker == Marker::Notify {
+                return self.notification(values);
+            }
+        }
+    }
+
+    /// Waits until the target pauses, for example at a breakpoint or after a step, and returns
+    /// the status at that point.
+    pub fn wait_for_pause(&mut self) -> DebuggerResult<DebugStatus> {
+        loop {
+            match self.next_notification()? {
+                DebugNotification::Throw { fatal: true, .. } => self.uncaught_error = true,
+                DebugNotification::Status(status) => {
+                    if !status.paused {
+                        continue;
+                    }
+                    if self.uncaught_error && !self.pause_on_exception {
+                        self.uncaught_error = false;
+                        self.resume()?;
+                        continue;
+                    }
+                    self.uncaught_error = false;
+                    return Ok(status);
+                }
+                DebugNotification::Detaching { .. } => {
+                    return Err(DebuggerError::Io {
+                        raw: io::Error::new(io::ErrorKind::ConnectionAborted, "detached"),
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a request and waits for its reply, queueing notifications that arrive in between.
+    fn request(&mut self, command: i32, args: &[DValue]) -> DebuggerResult<Vec<DValue>> {
+        let mut message = Vec::new();
+        Item::Marker(Marker::Request).write(&mut message)?;
+        DValue::Integer(command).write(&mut message)?;
+        for arg in args {
+            arg.write(&mut message)?;
+        }
+        Item::Eom.write(&mut message)?;
+        let stream = self.reader.get_mut();
+        stream.write_all(&message)?;
+        stream.flush()?;
+
+        loop {
+            let (marker, values) = self.read_message()?;
+            match marker {
+                Marker::Reply => return Ok(values),
+                Marker::Error => {
+                    return Err(DebuggerError::Request {
+                        code: int_at(&values, 0).unwrap_or(0) as i32,
+                        message: string_at(&values, 1),
+                    })
+                }
+                Marker::Notify => {
+                    let notification = self.notification(values)?;
+                    self.notifications.push_back(notification);
+                }
+                Marker::Request => {
+                    return Err(DebuggerError::Protocol {
+                        message: "unexpected request from the debug target".to_owned(),
+                    })
+                }
+            }
+        }
+    }
+
+    fn notification(&mut self, values: Vec<DValue>) -> DebuggerResult<DebugNotification> {
+        Ok(match int_at(&values, 0)? as i32 {
+            CMD_STATUS => DebugNotification::Status(DebugStatus {
+                paused: int_at(&values, 1)? != 0,
+                file_name: string_at(&values, 2),
+                function_name: string_at(&values, 3),
+                line: int_at(&values, 4)? as u32,
+                pc: int_at(&values, 5)? as u32,
+            }),
+            CMD_THROW => DebugNotification::Throw {
+                fatal: int_at(&values, 1)? != 0,
+                message: string_at(&values, 2),
+                file_name: string_at(&values, 3),
+                line: int_at(&values, 4)? as u32,
+            },
+            CMD_DETACHING => DebugNotification::Detaching {
+                reason: int_at(&values, 1)? as i32,
+            },
+            CMD_APP_NOTIFY if values.len() == 2 && values[1].as_str().is_some() => {
+                DebugNotification::AppNotify {
+                    payload: string_at(&values, 1),
+                }
+            }
+            command => DebugNotification::Other {
+                command,
+                values: values.into_iter().skip(1).collect(),
+            },
+        })
+    }
+
+    /// Reads a whole message, returning its marker and the values before the end marker.
+    fn read_message(&mut self) -> DebuggerResult<(Marker, Vec<DValue>)> {
+        let marker = match Item::read(&mut self.reader)? {
+            Item::Marker(marker) => marker,
+            item => {
+                return Err(DebuggerError::Protocol {
+                    message: format!("expected the start of a message, got {:?}", item),
+                })
+            }
+        };
+        let mut values = Vec::new();
+        loop {
+            match Item::read(&mut self.reader)? {
+                Item::Value(value) => values.push(value),
+                Item::Eom => return Ok((marker, values)),
+                Item::Marker(_) => {
+                    return Err(DebuggerError::Protocol {
+                        message: "unterminated message".to_owned(),
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl DebugNotification {
+    /// Decodes the payload of an `AppNotify` notification, or returns `None` for other
+    /// notifications.
+    pub fn app_payload<T>(&self) -> Option<DebuggerResult<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match *self {
+            DebugNotification::AppNotify { ref payload } => {
+                Some(serde_json::from_str(payload).map_err(DebuggerError::from))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for DebuggerError {
+    fn from(e: serde_json::Error) -> Self {
+        DebuggerError::Payload { raw: e }
+    }
+}
+
+impl From<io::Error> for DebuggerError {
+    fn from(e: io::Error) -> Self {
+        DebuggerError::Io { raw: e }
+    }
+}
+
+/// The protocol's call stack level for a frame index, where `-1` is the innermost frame.
+fn level(frame: usize) -> DValue {
+    DValue::Integer(-(frame as i32) - 1)
+}
+
+fn int_at(values: &[DValue], index: usize) -> DebuggerResult<i64> {
+    values
+        .get(index)
+        .and_then(DValue::as_i64)
+        .ok_or_else(|| DebuggerError::Protocol {
+            message: format!("expected an integer at position {} of {:?}", index, values),
+        })
+}
+
+/// The string at an index, which may also be `undefined` for missing file and function names.
+fn string_at(values: &[DValue], index: usize) -> String {
+    values
+        .get(index)
+        .and_then(DValue::as_str)
+        .unwrap_or("")
+        .to_owned()
+}
@@ -0,0 +1,296 @@
+//! Encoding and decoding of the dvalues that debug messages are made of.
+use std::io;
+
+const IB_EOM: u8 = 0x00;
+const IB_REQUEST: u8 = 0x01;
+const IB_REPLY: u8 = 0x02;
+const IB_ERROR: u8 = 0x03;
+const IB_NOTIFY: u8 = 0x04;
+const IB_INT4: u8 = 0x10;
+const IB_STR4: u8 = 0x11;
+const IB_STR2: u8 = 0x12;
+const IB_BUF4: u8 = 0x13;
+const IB_BUF2: u8 = 0x14;
+const IB_UNUSED: u8 = 0x15;
+const IB_UNDEFINED: u8 = 0x16;
+const IB_NULL: u8 = 0x17;
+const IB_TRUE: u8 = 0x18;
+const IB_FALSE: u8 = 0x19;
+const IB_NUMBER: u8 = 0x1a;
+const IB_OBJECT: u8 = 0x1b;
+const IB_POINTER: u8 = 0x1c;
+const IB_LIGHTFUNC: u8 = 0x1d;
+const IB_HEAPPTR: u8 = 0x1e;
+
+/// A value as it is represented in the debug protocol.
+///
+/// Values that do not have a representation of their own, like objects and pointers, are
+/// identified by the pointers of the debug target, which are opaque to the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DValue {
+    /// Marks a missing value, like a variable that was not found.
+    Unused,
+    Undefined,
+    Null,
+    Boolean(bool),
+    /// An integer.  Duktape sends integral fields this way, as well as numbers that are integral.
+    Integer(i32),
+    /// A Javascript number, sent as an IEEE double.
+    Number(f64),
+    /// A string, converted lossily from Duktape's internal string representation.
+    String(String),
+    /// A byte buffer.
+    Buffer(Vec<u8>),
+    /// An object with its Duktape class number.
+    Object {
+        class: u8,
+        pointer: Vec<u8>,
+    },
+    /// A raw pointer value.
+    Pointer(Vec<u8>),
+    /// A Duktape lightweight function.
+    LightFunc {
+        flags: u16,
+        pointer: Vec<u8>,
+    },
+    /// A pointer to a heap allocated value.
+    HeapPtr(Vec<u8>),
+}
+
+/// The initial byte of a debug message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Marker {
+    Request,
+    Reply,
+    Error,
+    Notify,
+}
+
+/// One element of a debug message.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Item {
+    Marker(Marker),
+    Eom,
+    Value(DValue),
+}
+
+impl DValue {
+    /// The value of an integer, or of a number that happens to be integral.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            DValue::Integer(i) => Some(i64::from(i)),
+            DValue::Number(n) if n.fract() == 0.0 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    /// The value of a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            DValue::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Writes this value in its shortest encoding.
+    pub(crate) fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        match *self {
+            DValue::Unused => w.write_all(&[IB_UNUSED]),
+            DValue::Undefined => w.write_all(&[IB_UNDEFINED]),
+            DValue::Null => w.write_all(&[IB_NULL]),
+            DValue::Boolean(true) => w.write_all(&[IB_TRUE]),
+            DValue::Boolean(false) => w.write_all(&[IB_FALSE]),
+            DValue::Integer(i) => match i {
+                0..=0x3f => w.write_all(&[0x80 + i as u8]),
+                0x40..=0x3fff => w.write_all(&[0xc0 + (i >> 8) as u8, i as u8]),
+                _ => {
+                    w.write_all(&[IB_INT4])?;
+                    w.write_all(&i.to_be_bytes())
+                }
+            },
+            DValue::Number(n) => {
+                w.write_all(&[IB_NUMBER])?;
+                w.write_all(&n.to_bits().to_be_bytes())
+            }
+            DValue::String(ref s) => {
+                let len = s.len();
+                if len < 0x20 {
+                    w.write_all(&[0x60 + len as u8])?;
+                } else if len <= 0xffff {
+                    w.write_all(&[IB_STR2])?;
+                    w.write_all(&(len as u16).to_be_bytes())?;
+                } else {
+                    w.write_all(&[IB_STR4])?;
+                    w.write_all(&(len as u32).to_be_bytes())?;
+                }
+                w.write_all(s.as_bytes())
+            }
+            DValue::Buffer(ref b) => {
+                if b.len() <= 0xffff {
+                    w.write_all(&[IB_BUF2])?;
+                    w.write_all(&(b.len() as u16).to_be_bytes())?;
+                } else {
+                    w.write_all(&[IB_BUF4])?;
+                    w.write_all(&(b.len() as u32).to_be_bytes())?;
+                }
+                w.write_all(b)
+            }
+            DValue::Object { class, ref pointer } => {
+                w.write_all(&[IB_OBJECT, class, pointer.len() as u8])?;
+                w.write_all(pointer)
+            }
+            DValue::Pointer(ref pointer) => {
+                w.write_all(&[IB_POINTER, pointer.len() as u8])?;
+                w.write_all(pointer)
+            }
+            DValue::LightFunc { flags, ref pointer } => {
+                w.write_all(&[IB_LIGHTFUNC])?;
+                w.write_all(&flags.to_be_bytes())?;
+                w.write_all(&[pointer.len() as u8])?;
+                w.write_all(pointer)
+            }
+            DValue::HeapPtr(ref pointer) => {
+                w.write_all(&[IB_HEAPPTR, pointer.len() as u8])?;
+                w.write_all(pointer)
+            }
+        }
+    }
+}
+
+impl From<&str> for DValue {
+    fn from(s: &str) -> Self {
+        DValue::String(s.to_owned())
+    }
+}
+
+impl From<i32> for DValue {
+    fn from(i: i32) -> Self {
+        DValue::Integer(i)
+    }
+}
+
+impl Item {
+    pub(crate) fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        match *self {
+            Item::Marker(Marker::Request) => w.write_all(&[IB_REQUEST]),
+            Item::Marker(Marker::Reply) => w.write_all(&[IB_REPLY]),
+            Item::Marker(Marker::Error) => w.write_all(&[IB_ERROR]),
+            Item::Marker(Marker::Notify) => w.write_all(&[IB_NOTIFY]),
+            Item::Eom => w.write_all(&[IB_EOM]),
+            Item::Value(ref value) => value.write(w),
+        }
+    }
+
+    pub(crate) fn read<R>(r: &mut R) -> io::Result<Item>
+    where
+        R: io::Read,
+    {
+        let ib = read_u8(r)?;
+        let value = match ib {
+            IB_EOM => return Ok(Item::Eom),
+            IB_REQUEST => return Ok(Item::Marker(Marker::Request)),
+            IB_REPLY => return Ok(Item::Marker(Marker::Reply)),
+            IB_ERROR => return Ok(Item::Marker(Marker::Error)),
+            IB_NOTIFY => return Ok(Item::Marker(Marker::Notify)),
+            IB_INT4 => DValue::Integer(i32::from_be_bytes(read_array(r)?)),
+            IB_STR4 => {
+                let len = u32::from_be_bytes(read_array(r)?);
+                DValue::String(read_string(r, len as usize)?)
+            }
+            IB_STR2 => {
+                let len = u16::from_be_bytes(read_array(r)?);
+                DValue::String(read_string(r, len as usize)?)
+            }
+            IB_BUF4 => {
+                let len = u32::from_be_bytes(read_array(r)?);
+                DValue::Buffer(read_bytes(r, len as usize)?)
+            }
+            IB_BUF2 => {
+                let len = u16::from_be_bytes(read_array(r)?);
+                DValue::Buffer(read_bytes(r, len as usize)?)
+            }
+            IB_UNUSED => DValue::Unused,
+            IB_UNDEFINED => DValue::Undefined,
+            IB_NULL => DValue::Null,
+            IB_TRUE => DValue::Boolean(true),
+            IB_FALSE => DValue::Boolean(false),
+            IB_NUMBER => DValue::Number(f64::from_bits(u64::from_be_bytes(read_array(r)?))),
+            IB_OBJECT => {
+                let class = read_u8(r)?;
+                let len = read_u8(r)?;
+                DValue::Object {
+                    class,
+                    pointer: read_bytes(r, len as usize)?,
+                }
+            }
+            IB_POINTER => {
+                let len = read_u8(r)?;
+                DValue::Pointer(read_bytes(r, len as usize)?)
+            }
+            IB_LIGHTFUNC => {
+                let flags = u16::from_be_bytes(read_array(r)?);
+                let len = read_u8(r)?;
+                DValue::LightFunc {
+                    flags,
+                    pointer: read_bytes(r, len as usize)?,
+                }
+            }
+            IB_HEAPPTR => {
+                let len = read_u8(r)?;
+                DValue::HeapPtr(read_bytes(r, len as usize)?)
+            }
+            0x60..=0x7f => DValue::String(read_string(r, (ib - 0x60) as usize)?),
+            0x80..=0xbf => DValue::Integer(i32::from(ib - 0x80)),
+            0xc0..=0xff => {
+                let low = read_u8(r)?;
+                DValue::Integer((i32::from(ib - 0xc0) << 8) + i32::from(low))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid dvalue initial byte 0x{:02x}", ib),
+                ))
+            }
+        };
+        Ok(Item::Value(value))
+    }
+}
+
+fn read_u8<R>(r: &mut R) -> io::Result<u8>
+where
+    R: io::Read,
+{
+    let [b] = read_array::<R, 1>(r)?;
+    Ok(b)
+}
+
+fn read_array<R, const N: usize>(r: &mut R) -> io::Result<[u8; N]>
+where
+    R: io::Read,
+{
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_bytes<R>(r: &mut R, len: usize) -> io::Result<Vec<u8>>
+where
+    R: io::Read,
+{
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_string<R>(r: &mut R, len: usize) -> io::Result<String>
+where
+    R: io::Read,
+{
+    Ok(String::from_utf8_lossy(&read_bytes(r, len)?).into_owned())
+}
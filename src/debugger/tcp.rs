@@ -0,0 +1,171 @@
+//! A TCP transport for debug clients like `duk_debug.js` from the Duktape distribution.
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+
+use crate::debugger::DebugTransport;
+use crate::Context;
+
+/// The port that `duk_debug.js` connects to by default.
+pub const DEFAULT_DEBUG_PORT: u16 = 9091;
+
+/// Listens for debug clients on a TCP port.
+///
+/// # Examples
+///
+/// Serving one debug client at a time, accepting a new one whenever the previous one detached:
+///
+/// ```no_run
+/// let ctx = duk::Context::new();
+/// let listener = duk::TcpDebugListener::bind(("127.0.0.1", 9091)).unwrap();
+/// loop {
+///     listener.poll_attach(&ctx).unwrap();
+///     ctx.debugger_cooperate();
+///     std::thread::sleep(std::time::Duration::from_millis(10));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct TcpDebugListener {
+    listener: net::TcpListener,
+}
+
+/// A connection to a debug client over TCP, speaking the binary debug protocol.
+///
+/// The version identification line that Duktape sends when the debugger is attached is checked
+/// against `DUK_DEBUG_PROTOCOL_VERSION`, so that a client never talks to a Duktape build that
+/// speaks a different protocol version than these bindings were generated for.
+#[derive(Debug)]
+pub struct TcpDebugTransport {
+    stream: net::TcpStream,
+    /// The part of the version identification line that has been written so far, until the whole
+    /// line has been checked.
+    handshake: Option<Vec<u8>>,
+}
+
+impl TcpDebugListener {
+    /// Starts listening on the specified address, like `127.0.0.1:9091`.
+    pub fn bind<A>(addr: A) -> io::Result<TcpDebugListener>
+    where
+        A: net::ToSocketAddrs,
+    {
+        let listener = net::TcpListener::bind(addr)?;
+        Ok(TcpDebugListener { listener })
+    }
+
+    /// The address that this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Blocks until a debug client connects.
+    pub fn accept(&self) -> io::Result<TcpDebugTransport> {
+        self.listener.set_nonblocking(false)?;
+        let (stream, _) = self.listener.accept()?;
+        TcpDebugTransport::new(stream)
+    }
+
+    /// Accepts a debug client that is waiting to connect, if any, without blocking.
+    pub fn try_accept(&self) -> io::Result<Option<TcpDebugTransport>> {
+        self.listener.set_nonblocking(true)?;
+        match self.listener.accept() {
+            Ok((stream, _)) => TcpDebugTransport::new(stream).map(Some),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Attaches a waiting debug client to the context, unless a debugger is already attached.
+    /// Returns whether a client was attached.
+    ///
+    /// Calling this regularly lets clients detach and reattach as they like.
+    pub fn poll_attach(&self, ctx: &Context) -> io::Result<bool> {
+        if ctx.debugger_attached() {
+            return Ok(false);
+        }
+        match self.try_accept()? {
+            Some(transport) => {
+                ctx.attach_debugger(transport);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl TcpDebugTransport {
+    /// Creates a transport for an established connection to a debug client.
+    pub fn new(stream: net::TcpStream) -> io::Result<TcpDebugTransport> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpDebugTransport {
+            stream,
+            handshake: Some(Vec::new()),
+        })
+    }
+
+    /// Holds back the version identification line until it is complete, and checks it before
+    /// sending it on.
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        let line = match self.handshake {
+            Some(ref mut line) => {
+                line.extend_from_slice(buf);
+                if !line.contains(&b'\n') {
+                    return Ok(());
+                }
+                self.handshake.take().unwrap_or_default()
+            }
+            None => return self.stream.write_all(buf),
+        };
+
+        let version = line
+            .split(|&b| b == b' ' || b == b'\n')
+            .next()
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse::<u32>().ok());
+        if version == Some(duk_sys::DUK_DEBUG_PROTOCOL_VERSION) {
+            self.stream.write_all(&line)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported debug protocol version in {:?}",
+                    String::from_utf8_lossy(&line)
+                ),
+            ))
+        }
+    }
+}
+
+impl DebugTransport for TcpDebugTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf)?;
+        Ok(buf.len())
+    }
+
+    fn peek(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 256];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.peek(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(n) if n > 0 => Ok(n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            // A closed or broken connection reports one readable byte, so that Duktape tries to
+            // read it and detaches when that fails.
+            _ => Ok(1),
+        }
+    }
+
+    fn write_flush(&mut self) {
+        let _ = self.stream.flush();
+    }
+
+    fn detached(&mut self) {
+        let _ = self.stream.shutdown(net::Shutdown::Both);
+    }
+}
@@ -0,0 +1,648 @@
+//! Rewriting of ES module syntax into the CommonJS shape that `module-node` evaluates.
+//!
+//! This is not a full Javascript parser.  The source is split into tokens so that strings,
+//! comments, regular expressions and templates are skipped correctly, and only `import` and
+//! `export` statements at the top level of the module are rewritten.  Every rewritten statement
+//! keeps its line breaks, so line numbers in errors and stack traces still match the original
+//! source.
+use std::result;
+
+use crate::module::ModuleError;
+
+/// Rewrites the static `import` and `export` statements of an ES module into CommonJS.
+///
+/// * `import d, * as ns from 'm'` and `import d, {a, b as c} from 'm'` become variables that are
+///   initialized from `require('m')` where the statement was.  The default import is the
+///   `default` export of modules that were themselves transformed, and the whole `exports` object
+///   of plain CommonJS modules.  Imported names are bound to the values at the time of the import,
+///   not to live bindings.
+/// * `export var`, `export function`, `export {a, b as c}` and `export default function f` define
+///   getters on `exports`, so exports always reflect the current value of the local binding.
+/// * `export default expr` assigns `exports.default`.
+/// * `export {a as b} from 'm'`, `export * as ns from 'm'` and `export * from 'm'` re-export the
+///   bindings of another module.
+///
+/// Sources without any `import` or `export` statements are returned unchanged.  Transformed
+/// modules are strict mode code, like ES modules are.  Dynamic `import()` is not supported.
+///
+/// # Examples
+///
+/// ```
+/// let source = duk::transform_es_module("import x from './x';\nexport default x + 1;").unwrap();
+/// assert_eq!(2, source.lines().count());
+/// assert!(source.contains("require('./x')"));
+/// ```
+pub fn transform_es_module(source: &str) -> result::Result<String, ModuleError> {
+    let tokens = tokenize(source);
+    let mut transformer = Transformer {
+        source,
+        tokens: &tokens,
+        edits: Vec::new(),
+        exports: Vec::new(),
+        imports: 0,
+        exports_all: false,
+    };
+    transformer.run()?;
+    Ok(transformer.finish())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TokenKind {
+    Ident,
+    Str,
+    Punct,
+    /// Numbers, regular expressions and templates, which are only ever skipped.
+    Other,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+    line: usize,
+    newline_before: bool,
+}
+
+/// A replacement of a part of the source.
+struct Edit {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+struct Transformer<'a> {
+    source: &'a str,
+    tokens: &'a [Token],
+    edits: Vec<Edit>,
+    /// Exported names and the expressions that their getters return.
+    exports: Vec<(String, String)>,
+    imports: usize,
+    exports_all: bool,
+}
+
+impl<'a> Transformer<'a> {
+    fn run(&mut self) -> result::Result<(), ModuleError> {
+        let mut depth = 0usize;
+        let mut i = 0;
+        while i < self.tokens.len() {
+            let token = self.tokens[i];
+            if token.kind == TokenKind::Punct {
+                match self.text(i) {
+                    "(" | "[" | "{" => depth += 1,
+                    ")" | "]" | "}" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+
+            if depth == 0 && token.kind == TokenKind::Ident && self.at_statement_start(i) {
+                match self.text(i) {
+                    "import" => {
+                        i = self.import(i)?;
+                        continue;
+                    }
+                    "export" => {
+                        i = self.export(i)?;
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> String {
+        if self.edits.is_empty() {
+            return self.source.to_owned();
+        }
+
+        let mut prologue = String::from(
+            "\"use strict\";Object.defineProperty(exports, \"__esModule\", {value: true});",
+        );
+        for (name, expr) in &self.exports {
+            prologue.push_str(&format!(
+                "Object.defineProperty(exports, \"{}\", \
+                 {{enumerable: true, get: function () {{ return {}; }}}});",
+                name, expr
+            ));
+        }
+        if self.exports_all {
+            prologue.push_str(
+                "function __esm_export_all(m) { Object.keys(m).forEach(function (k) { \
+                 if (k !== \"default\" && !Object.prototype.hasOwnProperty.call(exports, k)) \
+                 Object.defineProperty(exports, k, \
+                 {enumerable: true, get: function () { return m[k]; }}); }); }",
+            );
+        }
+
+        // The prologue must not end up inside of a shebang comment.
+        let at = if self.source.starts_with("#!") {
+            self.source.find('\n').map_or(self.source.len(), |n| n + 1)
+        } else {
+            0
+        };
+        self.edits.push(Edit {
+            start: at,
+            end: at,
+            text: prologue,
+        });
+        self.edits.sort_by_key(|e| (e.start, e.end));
+
+        let mut result = String::with_capacity(self.source.len() * 2);
+        let mut pos = 0;
+        for edit in &self.edits {
+            result.push_str(&self.source[pos..edit.start]);
+            result.push_str(&edit.text);
+            pos = edit.end;
+        }
+        result.push_str(&self.source[pos..]);
+        result
+    }
+
+    /// Rewrites an `import` statement, returning the index of the token after it.
+    fn import(&mut self, i: usize) -> result::Result<usize, ModuleError> {
+        let start = self.tokens[i].start;
+        let mut pos = i + 1;
+
+        if self.is_punct(pos, "(") || self.is_punct(pos, ".") {
+            return Err(self.error(i, "dynamic import is not supported"));
+        }
+
+        if self.kind(pos) == Some(TokenKind::Str) {
+            let module = self.text(pos).to_owned();
+            let end = self.statement_end(pos + 1, &mut pos);
+            self.replace(start, end, format!("require({});", module));
+            return Ok(pos);
+        }
+
+        let mut default = None;
+        let mut namespace = None;
+        let mut named = Vec::new();
+
+        if self.kind(pos) == Some(TokenKind::Ident) && !self.is_ident(pos, "from") {
+            default = Some(self.text(pos).to_owned());
+            pos += 1;
+            if self.is_punct(pos, ",") {
+                pos += 1;
+            }
+        }
+
+        if self.is_punct(pos, "*") {
+            pos = self.expect_ident(pos + 1, "as")?;
+            namespace = Some(self.binding(pos)?);
+            pos += 1;
+        } else if self.is_punct(pos, "{") {
+            pos = self.specifiers(pos, &mut named)?;
+        }
+
+        pos = self.expect_ident(pos, "from")?;
+        let module = self.module_specifier(pos)?;
+        let end = self.statement_end(pos + 1, &mut pos);
+
+        let var = self.module_var();
+        let mut bindings = vec![format!("{} = require({})", var, module)];
+        if let Some(default) = default {
+            bindings.push(format!(
+                "{} = {v} && {v}.__esModule ? {v}.default : {v}",
+                default,
+                v = var
+            ));
+        }
+        if let Some(namespace) = namespace {
+            bindings.push(format!("{} = {}", namespace, var));
+        }
+        for (name, local) in named {
+            bindings.push(format!("{} = {}.{}", local, var, name));
+        }
+        self.replace(start, end, format!("var {};", bindings.join(", ")));
+        Ok(pos)
+    }
+
+    /// Rewrites an `export` statement, returning the index of the token to continue scanning at.
+    fn export(&mut self, i: usize) -> result::Result<usize, ModuleError> {
+        let start = self.tokens[i].start;
+        let mut pos = i + 1;
+
+        match self.kind(pos).map(|kind| (kind, self.text(pos))) {
+            Some((TokenKind::Ident, "default")) => {
+                if self.is_ident(pos + 1, "function")
+                    && self.kind(pos + 2) == Some(TokenKind::Ident)
+                {
+                    let name = self.text(pos + 2).to_owned();
+                    self.replace(start, self.tokens[pos + 1].start, String::new());
+                    self.exports.push(("default".to_owned(), name));
+                } else {
+                    self.replace(start, self.tokens[pos].end, "exports.default =".to_owned());
+                }
+                Ok(pos + 1)
+            }
+            Some((TokenKind::Ident, "var"))
+            | Some((TokenKind::Ident, "let"))
+            | Some((TokenKind::Ident, "const")) => {
+                for name in self.declared_names(pos + 1)? {
+                    self.exports.push((name.clone(), name));
+                }
+                self.replace(start, self.tokens[pos].start, String::new());
+                Ok(pos + 1)
+            }
+            Some((TokenKind::Ident, "function")) => {
+                let name_pos = if self.is_punct(pos + 1, "*") {
+                    pos + 2
+                } else {
+                    pos + 1
+                };
+                let name = self.binding(name_pos)?;
+                self.exports.push((name.clone(), name));
+                self.replace(start, self.tokens[pos].start, String::new());
+                Ok(pos + 1)
+            }
+            Some((TokenKind::Punct, "{")) => {
+                let mut named = Vec::new();
+                pos = self.specifiers(pos, &mut named)?;
+                let (end, text) = if self.is_ident(pos, "from") {
+                    let module = self.module_specifier(pos + 1)?;
+                    let var = self.module_var();
+                    for (name, alias) in named {
+                        self.exports.push((alias, format!("{}.{}", var, name)));
+                    }
+                    let end = self.statement_end(pos + 2, &mut pos);
+                    (end, format!("var {} = require({});", var, module))
+                } else {
+                    self.exports.extend(named.into_iter().map(|(l, a)| (a, l)));
+                    (self.statement_end(pos, &mut pos), String::new())
+                };
+                self.replace(start, end, text);
+                Ok(pos)
+            }
+            Some((TokenKind::Punct, "*")) => {
+                pos += 1;
+                let namespace = if self.is_ident(pos, "as") {
+                    let namespace = self.binding(pos + 1)?;
+                    pos += 2;
+                    Some(namespace)
+                } else {
+                    None
+                };
+                pos = self.expect_ident(pos, "from")?;
+                let module = self.module_specifier(pos)?;
+                let end = self.statement_end(pos + 1, &mut pos);
+                let text = match namespace {
+                    Some(namespace) => {
+                        let var = self.module_var();
+                        self.exports.push((namespace, var.clone()));
+                        format!("var {} = require({});", var, module)
+                    }
+                    None => {
+                        self.exports_all = true;
+                        format!("__esm_export_all(require({}));", module)
+                    }
+                };
+                self.replace(start, end, text);
+                Ok(pos)
+            }
+            _ => Err(self.error(i, "unsupported export syntax")),
+        }
+    }
+
+    /// Parses `{a, b as c}` starting at the opening brace, returning the index after the closing
+    /// brace.
+    fn specifiers(
+        &self,
+        mut pos: usize,
+        specifiers: &mut Vec<(String, String)>,
+    ) -> result::Result<usize, ModuleError> {
+        pos += 1;
+        loop {
+            if self.is_punct(pos, "}") {
+                return Ok(pos + 1);
+            }
+            if self.kind(pos) != Some(TokenKind::Ident) {
+                return Err(self.error(pos, "expected a binding name"));
+            }
+            let name = self.text(pos).to_owned();
+            pos += 1;
+            let alias = if self.is_ident(pos, "as") {
+                let alias = self.binding(pos + 1)?;
+                pos += 2;
+                alias
+            } else {
+                name.clone()
+            };
+            specifiers.push((name, alias));
+
+            if self.is_punct(pos, ",") {
+                pos += 1;
+            } else if !self.is_punct(pos, "}") {
+                return Err(self.error(pos, "expected ',' or '}'"));
+            }
+        }
+    }
+
+    /// Collects the names declared by a `var`, `let` or `const` statement whose first declarator
+    /// starts at the specified index.
+    fn declared_names(&self, mut pos: usize) -> result::Result<Vec<String>, ModuleError> {
+        let mut names = vec![self.binding(pos)?];
+        let mut depth = 0usize;
+        pos += 1;
+        while pos < self.tokens.len() {
+            let token = self.tokens[pos];
+            if depth == 0 && token.newline_before && !self.continues_statement(pos) {
+                break;
+            }
+            if token.kind == TokenKind::Punct {
+                match self.text(pos) {
+                    "(" | "[" | "{" => depth += 1,
+                    ")" | "]" | "}" => {
+                        if depth == 0 {
+                            break;
+                        }
+                        depth -= 1;
+                    }
+                    ";" if depth == 0 => break,
+                    "," if depth == 0 => {
+                        pos += 1;
+                        names.push(self.binding(pos)?);
+                    }
+                    _ => {}
+                }
+            }
+            pos += 1;
+        }
+        Ok(names)
+    }
+
+    /// Whether the token at `pos` continues the statement before it despite a line break before
+    /// it, using the same rules as automatic semicolon insertion in the common cases.
+    fn continues_statement(&self, pos: usize) -> bool {
+        const OPERATORS: &str = "=,.+-*/%&|^!~?:<>([";
+        let ends_with_operator =
+            self.kind(pos - 1) == Some(TokenKind::Punct) && OPERATORS.contains(self.text(pos - 1));
+        let starts_with_operator =
+            self.kind(pos) == Some(TokenKind::Punct) && OPERATORS.contains(self.text(pos));
+        ends_with_operator || starts_with_operator
+    }
+
+    fn at_statement_start(&self, i: usize) -> bool {
+        if i == 0 {
+            return true;
+        }
+        let previous = self.tokens[i - 1];
+        if previous.kind == TokenKind::Punct {
+            match self.text(i - 1) {
+                ";" | "}" => return true,
+                "." => return false,
+                _ => {}
+            }
+        }
+        self.tokens[i].newline_before && !self.continues_statement(i)
+    }
+
+    /// Finds the end of a statement whose last required token is before `pos`, consuming an
+    /// optional semicolon.  `next` is set to the index of the token after the statement.
+    fn statement_end(&self, pos: usize, next: &mut usize) -> usize {
+        if self.is_punct(pos, ";") {
+            *next = pos + 1;
+            self.tokens[pos].end
+        } else {
+            *next = pos;
+            self.tokens[pos - 1].end
+        }
+    }
+
+    fn replace(&mut self, start: usize, end: usize, text: String) {
+        // Keep all line breaks so that the following lines keep their line numbers.
+        let newlines = self.source[start..end].matches('\n').count();
+        let mut text = text;
+        text.push_str(&"\n".repeat(newlines));
+        self.edits.push(Edit { start, end, text });
+    }
+
+    fn module_var(&mut self) -> String {
+        self.imports += 1;
+        format!("__esm_module_{}", self.imports)
+    }
+
+    fn module_specifier(&self, pos: usize) -> result::Result<String, ModuleError> {
+        if self.kind(pos) == Some(TokenKind::Str) {
+            Ok(self.text(pos).to_owned())
+        } else {
+            Err(self.error(pos, "expected a module specifier string"))
+        }
+    }
+
+    fn binding(&self, pos: usize) -> result::Result<String, ModuleError> {
+        if self.kind(pos) == Some(TokenKind::Ident) {
+            Ok(self.text(pos).to_owned())
+        } else {
+            Err(self.error(pos, "expected a binding name"))
+        }
+    }
+
+    fn expect_ident(&self, pos: usize, ident: &str) -> result::Result<usize, ModuleError> {
+        if self.is_ident(pos, ident) {
+            Ok(pos + 1)
+        } else {
+            Err(self.error(pos, &format!("expected '{}'", ident)))
+        }
+    }
+
+    fn error(&self, pos: usize, message: &str) -> ModuleError {
+        let line = self
+            .tokens
+            .get(pos)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |t| t.line);
+        ModuleError::Other {
+            message: format!("line {}: {}", line, message),
+        }
+    }
+
+    fn kind(&self, pos: usize) -> Option<TokenKind> {
+        self.tokens.get(pos).map(|t| t.kind)
+    }
+
+    fn text(&self, pos: usize) -> &'a str {
+        let token = self.tokens[pos];
+        &self.source[token.start..token.end]
+    }
+
+    fn is_ident(&self, pos: usize, ident: &str) -> bool {
+        self.kind(pos) == Some(TokenKind::Ident) && self.text(pos) == ident
+    }
+
+    fn is_punct(&self, pos: usize, punct: &str) -> bool {
+        self.kind(pos) == Some(TokenKind::Punct) && self.text(pos) == punct
+    }
+}
+
+/// Splits a source into tokens, skipping whitespace and comments.
+fn tokenize(source: &str) -> Vec<Token> {
+    let bytes = source.as_bytes();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    let mut newline_before = true;
+
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        let start = pos;
+        let start_line = line;
+
+        let kind = match c {
+            b'\n' => {
+                line += 1;
+                newline_before = true;
+                pos += 1;
+                continue;
+            }
+            _ if c.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'/') => {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                pos += 2;
+                while pos < bytes.len() && !bytes[pos..].starts_with(b"*/") {
+                    if bytes[pos] == b'\n' {
+                        line += 1;
+                        newline_before = true;
+                    }
+                    pos += 1;
+                }
+                pos = (pos + 2).min(bytes.len());
+                continue;
+            }
+            b'\'' | b'"' => {
+                pos += 1;
+                while pos < bytes.len() && bytes[pos] != c && bytes[pos] != b'\n' {
+                    if bytes[pos] == b'\\' {
+                        pos += 1;
+                        if bytes.get(pos) == Some(&b'\n') {
+                            line += 1;
+                        }
+                    }
+                    pos += 1;
+                }
+                pos = (pos + 1).min(bytes.len());
+                TokenKind::Str
+            }
+            b'`' => {
+                pos += 1;
+                let mut depth = 0usize;
+                while pos < bytes.len() && (depth > 0 || bytes[pos] != b'`') {
+                    match bytes[pos] {
+                        b'\\' => pos += 1,
+                        b'\n' => line += 1,
+                        b'$' if depth == 0 && bytes.get(pos + 1) == Some(&b'{') => {
+                            depth += 1;
+                            pos += 1;
+                        }
+                        b'{' if depth > 0 => depth += 1,
+                        b'}' if depth > 0 => depth -= 1,
+                        _ => {}
+                    }
+                    pos += 1;
+                }
+                pos = (pos + 1).min(bytes.len());
+                TokenKind::Other
+            }
+            b'/' if regex_allowed(source, tokens.last()) => {
+                pos += 1;
+                let mut in_class = false;
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    match bytes[pos] {
+                        b'\\' => pos += 1,
+                        b'[' => in_class = true,
+                        b']' => in_class = false,
+                        b'/' if !in_class => break,
+                        _ => {}
+                    }
+                    pos += 1;
+                }
+                pos += 1;
+                while pos < bytes.len() && is_ident_byte(bytes[pos]) {
+                    pos += 1;
+                }
+                pos = pos.min(bytes.len());
+                TokenKind::Other
+            }
+            _ if c.is_ascii_digit()
+                || (c == b'.' && bytes.get(pos + 1).is_some_and(u8::is_ascii_digit)) =>
+            {
+                pos += 1;
+                while pos < bytes.len()
+                    && (is_ident_byte(bytes[pos])
+                        || bytes[pos] == b'.'
+                        || ((bytes[pos] == b'+' || bytes[pos] == b'-')
+                            && matches!(bytes[pos - 1], b'e' | b'E')
+                            && !source[start..pos].starts_with("0x")))
+                {
+                    pos += 1;
+                }
+                TokenKind::Other
+            }
+            _ if is_ident_byte(c) => {
+                while pos < bytes.len() && is_ident_byte(bytes[pos]) {
+                    pos += 1;
+                }
+                TokenKind::Ident
+            }
+            _ => {
+                pos += 1;
+                TokenKind::Punct
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            start,
+            end: pos,
+            line: start_line,
+            newline_before,
+        });
+        newline_before = false;
+    }
+
+    tokens
+}
+
+fn is_ident_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+/// Whether a `/` after the specified token starts a regular expression rather than a division.
+fn regex_allowed(source: &str, previous: Option<&Token>) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "return",
+        "typeof",
+        "instanceof",
+        "in",
+        "of",
+        "new",
+        "delete",
+        "void",
+        "throw",
+        "case",
+        "do",
+        "else",
+        "yield",
+    ];
+    match previous {
+        None => true,
+        Some(token) => {
+            let text = &source[token.start..token.end];
+            match token.kind {
+                TokenKind::Punct => text != ")" && text != "]",
+                TokenKind::Ident => KEYWORDS.contains(&text),
+                TokenKind::Str | TokenKind::Other => false,
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! An event loop that runs timer callbacks, along the lines of Duktape's `examples/eventloop`.
+use std::cell;
+use std::collections;
+use std::os;
+use std::thread;
+use std::time;
+
+use crate::Context;
+use crate::Error;
+use crate::Result;
+
+/// Intervals shorter than this are stretched, so that `setInterval(f, 0)` can't starve a loop
+/// that runs for a limited time.
+const MIN_INTERVAL: time::Duration = time::Duration::from_millis(1);
+
+/// Runs the callbacks that scripts schedule with `setTimeout` and `setInterval`.
+///
+/// Creating an event loop installs the `setTimeout`, `setInterval`, `clearTimeout` and
+/// `clearInterval` globals into its context.  Callbacks run when the loop is run, ordered by the
+/// time they are due and, for callbacks that are due at the same time, by the order in which they
+/// were scheduled.
+///
+/// For contexts with promise support, microtasks are run before the first callback and after
+/// every callback, so that Promise reactions run before the next timer fires.
+///
+/// The loop either follows the real clock, sleeping until the next callback is due, or a virtual
+/// clock that jumps straight to the time the next callback is due, which makes it possible to
+/// test timing dependent scripts without waiting.
+///
+/// # Examples
+///
+/// ```
+/// let event_loop = duk::EventLoop::with_virtual_clock(duk::Context::new());
+/// let ctx = event_loop.context();
+/// ctx.eval_string("var log = []; \
+///                  setTimeout(function(x) { log.push(x); }, 20, 'late'); \
+///                  setTimeout(function(x) { log.push(x); }, 10, 'early');")
+///     .unwrap();
+///
+/// assert!(event_loop.run_until_idle().is_empty());
+/// assert_eq!(
+///     duk::Value::String("early,late".to_owned()),
+///     ctx.eval_string("log.join()").unwrap().to_value()
+/// );
+/// assert_eq!(std::time::Duration::from_millis(20), event_loop.now());
+/// ```
+pub struct EventLoop {
+    // The context is dropped first, so that no timer global outlives the timer state.
+    ctx: Context,
+    timers: Box<cell::RefCell<Timers>>,
+}
+
+/// An error that was thrown by a timer callback, or by the microtasks that it queued.
+#[derive(Debug)]
+pub struct TimerError {
+    /// The id of the timer, as returned by `setTimeout` or `setInterval`, or 0 for microtasks that
+    /// were queued before the loop was run.
+    pub id: u32,
+    pub error: Error,
+}
+
+struct Timers {
+    clock: Clock,
+    next_id: u32,
+    next_seq: u64,
+    /// Scheduled timers, keyed by due time and scheduling order.
+    queue: collections::BTreeMap<(time::Duration, u64), Timer>,
+}
+
+enum Clock {
+    Real(time::Instant),
+    Virtual(time::Duration),
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: u32,
+    interval: Option<time::Duration>,
+}
+
+impl EventLoop {
+    /// Creates an event loop for the specified context that follows the real clock.
+    pub fn new(ctx: Context) -> EventLoop {
+        EventLoop::with_clock(ctx, Clock::Real(time::Instant::now()))
+    }
+
+    /// Creates an event loop for the specified context with a virtual clock, which starts at zero
+    /// and only advances while the loop runs.
+    pub fn with_virtual_clock(ctx: Context) -> EventLoop {
+        EventLoop::with_clock(ctx, Clock::Virtual(time::Duration::from_secs(0)))
+    }
+
+    fn with_clock(ctx: Context, clock: Clock) -> EventLoop {
+        let timers = Box::new(cell::RefCell::new(Timers {
+            clock,
+            next_id: 1,
+            next_seq: 0,
+            queue: collections::BTreeMap::new(),
+        }));
+        unsafe { install(ctx.raw, &*timers as *const _ as *mut os::raw::c_void) };
+        EventLoop { ctx, timers }
+    }
+
+    /// The context that this event loop runs callbacks in.
+    pub fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    /// The time that passed on the clock of this event loop since it was created.
+    pub fn now(&self) -> time::Duration {
+        self.timers.borrow().now()
+    }
+
+    /// The number of timers that are still scheduled.
+    pub fn pending_timers(&self) -> usize {
+        self.timers.borrow().queue.len()
+    }
+
+    /// Runs callbacks until no timers are left, and returns the errors that they threw.
+    ///
+    /// This never returns while an interval is scheduled that no callback clears.
+    pub fn run_until_idle(&self) -> Vec<TimerError> {
+        self.run(None)
+    }
+
+    /// Runs the callbacks that are due within the specified duration from now, and returns the
+    /// errors that they threw.
+    ///
+    /// When this returns, the clock has advanced by the duration.
+    pub fn run_for(&self, duration: time::Duration) -> Vec<TimerError> {
+        let deadline = self.now() + duration;
+        self.run(Some(deadline))
+    }
+
+    fn run(&self, deadline: Option<time::Duration>) -> Vec<TimerError> {
+        let mut errors = Vec::new();
+        if let Err(error) = self.ctx.run_microtasks() {
+            errors.push(TimerError { id: 0, error });
+        }
+        loop {
+            let next_due = self.timers.borrow().next_due();
+            match next_due {
+                Some(due) if deadline.is_none_or(|deadline| due <= deadline) => {
+                    self.wait_until(due);
+                    let timer = self.timers.borrow_mut().pop_next();
+                    if let Err(error) = unsafe { self.fire(timer) } {
+                        errors.push(TimerError {
+                            id: timer.id,
+                            error,
+                        });
+                    }
+                }
+                _ => {
+                    if let Some(deadline) = deadline {
+                        self.wait_until(deadline);
+                    }
+                    return errors;
+                }
+            }
+        }
+    }
+
+    fn wait_until(&self, time: time::Duration) {
+        let mut timers = self.timers.borrow_mut();
+        match timers.clock {
+            Clock::Real(start) => {
+                drop(timers);
+                if let Some(remaining) = time.checked_sub(start.elapsed()) {
+                    thread::sleep(remaining);
+                }
+            }
+            Clock::Virtual(ref mut now) => {
+                if *now < time {
+                    *now = time;
+                }
+            }
+        }
+    }
+
+    /// Calls the callback of a timer that was taken off the queue.  The callbacks of timeouts are
+    /// forgotten before they are called, those of intervals stay around until they are cleared.
+    /// The microtasks that the callback queued are run right after it.
+    unsafe fn fire(&self, timer: Timer) -> Result<()> {
+        let raw = self.ctx.raw;
+        push_callbacks(raw);
+        if 0 == duk_sys::duk_get_prop_index(raw, -1, timer.id) {
+            // Cleared while it was due.
+            duk_sys::duk_pop_2(raw);
+            return Ok(());
+        }
+        if timer.interval.is_none() {
+            duk_sys::duk_del_prop_index(raw, -2, timer.id);
+        }
+
+        // Stack: [ callbacks [ callback args... ] ]
+        let entry = duk_sys::duk_get_top_index(raw);
+        let len = duk_sys::duk_get_length(raw, entry) as duk_sys::duk_idx_t;
+        for i in 0..len {
+            duk_sys::duk_get_prop_index(raw, entry, i as duk_sys::duk_uarridx_t);
+        }
+        let ret = duk_sys::duk_pcall(raw, len - 1);
+        duk_sys::duk_remove(raw, entry);
+        duk_sys::duk_remove(raw, entry - 1);
+
+        let result = if ret == 0 {
+            duk_sys::duk_pop(raw);
+            Ok(())
+        } else {
+            Err(self.ctx.pop_error())
+        };
+        let microtasks = self.ctx.run_microtasks();
+        result.and(microtasks)
+    }
+}
+
+impl Timers {
+    fn now(&self) -> time::Duration {
+        match self.clock {
+            Clock::Real(start) => start.elapsed(),
+            Clock::Virtual(now) => now,
+        }
+    }
+
+    fn next_due(&self) -> Option<time::Duration> {
+        self.queue.keys().next().map(|&(due, _)| due)
+    }
+
+    fn schedule(&mut self, delay: time::Duration, repeat: bool) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let interval = if repeat {
+            Some(delay.max(MIN_INTERVAL))
+        } else {
+            None
+        };
+        self.insert(self.now() + delay, Timer { id, interval });
+        id
+    }
+
+    fn insert(&mut self, due: time::Duration, timer: Timer) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.insert((due, seq), timer);
+    }
+
+    /// Takes the timer that is due next off the queue, scheduling it again if it is an interval.
+    fn pop_next(&mut self) -> Timer {
+        let (_, timer) = self.queue.pop_first().expect("no timer is scheduled");
+        if let Some(interval) = timer.interval {
+            self.insert(self.now() + interval, timer);
+        }
+        timer
+    }
+
+    fn clear(&mut self, id: u32) {
+        self.queue.retain(|_, timer| timer.id != id);
+    }
+}
+
+unsafe fn install(raw: *mut duk_sys::duk_context, timers: *mut os::raw::c_void) {
+    duk_sys::duk_push_global_stash(raw);
+    duk_sys::duk_push_object(raw);
+    duk_sys::duk_put_prop_string(raw, -2, crate::nul_str(b"timerCallbacks\0"));
+    duk_sys::duk_pop(raw);
+
+    let globals: [(&[u8], duk_sys::duk_c_function); 4] = [
+        (b"setTimeout\0", Some(set_timeout_handler)),
+        (b"setInterval\0", Some(set_interval_handler)),
+        (b"clearTimeout\0", Some(clear_timer_handler)),
+        (b"clearInterval\0", Some(clear_timer_handler)),
+    ];
+    for &(name, handler) in globals.iter() {
+        duk_sys::duk_push_c_function(raw, handler, duk_sys::DUK_VARARGS);
+        duk_sys::duk_push_pointer(raw, timers);
+        duk_sys::duk_put_prop_string(raw, -2, crate::nul_str(b"closure\0"));
+        duk_sys::duk_put_global_string(raw, crate::nul_str(name));
+    }
+}
+
+/// Pushes the object that holds the callbacks and arguments of the scheduled timers by id.
+unsafe fn push_callbacks(raw: *mut duk_sys::duk_context) {
+    duk_sys::duk_push_global_stash(raw);
+    duk_sys::duk_get_prop_string(raw, -1, crate::nul_str(b"timerCallbacks\0"));
+    duk_sys::duk_remove(raw, -2);
+}
+
+unsafe extern "C" fn set_timeout_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    schedule_handler(ctx, false)
+}
+
+unsafe extern "C" fn set_interval_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    schedule_handler(ctx, true)
+}
+
+unsafe fn schedule_handler(ctx: *mut duk_sys::duk_context, repeat: bool) -> duk_sys::duk_ret_t {
+    if 0 == duk_sys::duk_is_callable(ctx, 0) {
+        return crate::throw_error(
+            ctx,
+            duk_sys::DUK_ERR_TYPE_ERROR as i32,
+            "timer callback is not callable".to_owned(),
+        );
+    }
+    // Stack: [ callback delay args... ]
+    let nargs = duk_sys::duk_get_top(ctx);
+    let delay = if nargs > 1 {
+        duk_sys::duk_to_number(ctx, 1)
+    } else {
+        0.0
+    };
+    let delay = if delay > 0.0 {
+        time::Duration::from_secs_f64(delay.min(u32::MAX as f64) / 1000.0)
+    } else {
+        time::Duration::from_secs(0)
+    };
+
+    let id = (*crate::get_closure::<cell::RefCell<Timers>>(ctx))
+        .borrow_mut()
+        .schedule(delay, repeat);
+
+    push_callbacks(ctx);
+    duk_sys::duk_push_array(ctx);
+    duk_sys::duk_dup(ctx, 0);
+    duk_sys::duk_put_prop_index(ctx, -2, 0);
+    for i in 2..nargs {
+        duk_sys::duk_dup(ctx, i);
+        duk_sys::duk_put_prop_index(ctx, -2, (i - 1) as duk_sys::duk_uarridx_t);
+    }
+    duk_sys::duk_put_prop_index(ctx, -2, id);
+    duk_sys::duk_pop(ctx);
+
+    duk_sys::duk_push_uint(ctx, id);
+    1
+}
+
+unsafe extern "C" fn clear_timer_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    if 0 == duk_sys::duk_is_number(ctx, 0) {
+        return 0;
+    }
+    let id = duk_sys::duk_get_uint(ctx, 0);
+    (*crate::get_closure::<cell::RefCell<Timers>>(ctx))
+        .borrow_mut()
+        .clear(id);
+
+    push_callbacks(ctx);
+    duk_sys::duk_del_prop_index(ctx, -1, id);
+    duk_sys::duk_pop(ctx);
+    0
+}
@@ -0,0 +1,78 @@
+//! The table that keeps the values of `Reference`s reachable.
+//!
+//! The table is an array in the heap stash, which Rust code reaches through its heap pointer.
+//! Slots of dropped references are cleared and reused, so the array stays dense and Duktape keeps
+//! it in the array part, where reading and writing an index doesn't need a string key.
+use std::cell;
+use std::os;
+use std::ptr;
+
+/// The key of the table in the heap stash.
+const TABLE_KEY: &[u8] = b"handles\0";
+
+/// The handles of the references of a context.
+pub(crate) struct Handles {
+    /// The heap pointer of the table, which stays valid because the heap stash holds the table.
+    table: cell::Cell<*mut os::raw::c_void>,
+    /// The slots of dropped references, which are reused before the table grows.
+    free: cell::RefCell<Vec<duk_sys::duk_uarridx_t>>,
+    /// The number of slots in the table.
+    len: cell::Cell<duk_sys::duk_uarridx_t>,
+}
+
+impl Handles {
+    pub(crate) fn new() -> Handles {
+        Handles {
+            table: cell::Cell::new(ptr::null_mut()),
+            free: cell::RefCell::new(Vec::new()),
+            len: cell::Cell::new(0),
+        }
+    }
+
+    /// Creates the table in a new heap, before any references are created.
+    pub(crate) unsafe fn install(&self, ctx: *mut duk_sys::duk_context) {
+        duk_sys::duk_push_heap_stash(ctx);
+        duk_sys::duk_push_array(ctx);
+        self.table.set(duk_sys::duk_get_heapptr(ctx, -1));
+        duk_sys::duk_put_prop_string(ctx, -2, crate::nul_str(TABLE_KEY));
+        duk_sys::duk_pop(ctx);
+    }
+
+    /// Pops the value at the top of the stack into a slot of the table, and returns the slot.
+    pub(crate) unsafe fn insert(&self, ctx: *mut duk_sys::duk_context) -> duk_sys::duk_uarridx_t {
+        let handle = self.free.borrow_mut().pop().unwrap_or_else(|| {
+            let len = self.len.get();
+            self.len.set(len + 1);
+            len
+        });
+        duk_sys::duk_push_heapptr(ctx, self.table.get());
+        duk_sys::duk_swap_top(ctx, -2);
+        duk_sys::duk_put_prop_index(ctx, -2, handle);
+        duk_sys::duk_pop(ctx);
+        handle
+    }
+
+    /// Pushes the value in a slot of the table.
+    pub(crate) unsafe fn push(
+        &self,
+        ctx: *mut duk_sys::duk_context,
+        handle: duk_sys::duk_uarridx_t,
+    ) {
+        duk_sys::duk_push_heapptr(ctx, self.table.get());
+        duk_sys::duk_get_prop_index(ctx, -1, handle);
+        duk_sys::duk_remove(ctx, -2);
+    }
+
+    /// Clears a slot of the table, so that its value can be collected, and reuses it later.
+    pub(crate) unsafe fn remove(
+        &self,
+        ctx: *mut duk_sys::duk_context,
+        handle: duk_sys::duk_uarridx_t,
+    ) {
+        duk_sys::duk_push_heapptr(ctx, self.table.get());
+        duk_sys::duk_push_undefined(ctx);
+        duk_sys::duk_put_prop_index(ctx, -2, handle);
+        duk_sys::duk_pop(ctx);
+        self.free.borrow_mut().push(handle);
+    }
+}
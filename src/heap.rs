@@ -0,0 +1,120 @@
+//! Control over garbage collection, and inspection of the memory that values use.
+use crate::Context;
+use crate::Reference;
+
+/// Information about the heap allocation of a value, from `duk_inspect_value`, as returned by
+/// `Reference::heap_info`.
+///
+/// Duktape only reports on individual values, so heap-wide figures like the number of objects
+/// aren't available.  Hosts that watch for leaks can track the values they suspect instead, or
+/// use the statistics of a pool allocator.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HeapInfo {
+    /// The reference count, if Duktape counts references.  It includes the reference from the
+    /// handle table that backs the `Reference`, and the copy on the value stack while inspecting.
+    pub refcount: Option<usize>,
+    /// The size of the main allocation, which holds the data of strings and fixed buffers.
+    pub header_bytes: usize,
+    /// The internal class number of an object.
+    pub class: Option<u32>,
+    /// The size of the separate allocation for the properties of an object.
+    pub property_bytes: Option<usize>,
+    /// The number of slots in the entry part of an object.
+    pub entry_size: Option<usize>,
+    /// The number of used slots in the entry part of an object, including deleted properties.
+    pub entry_next: Option<usize>,
+    /// The number of slots in the array part of an object.
+    pub array_size: Option<usize>,
+    /// The number of slots in the hash part of an object.
+    pub hash_size: Option<usize>,
+    /// The size of the bytecode of a compiled function.
+    pub bytecode_bytes: Option<usize>,
+    /// The size of the data of a dynamic or external buffer.
+    pub data_bytes: Option<usize>,
+}
+
+impl HeapInfo {
+    /// The bytes that the value itself allocates from the heap, not counting values that it
+    /// refers to.
+    pub fn allocated_bytes(&self) -> usize {
+        self.header_bytes + self.property_bytes.unwrap_or(0) + self.data_bytes.unwrap_or(0)
+    }
+}
+
+impl Context {
+    /// Runs a mark-and-sweep garbage collection, which frees unreachable values that reference
+    /// counting can't, like reference cycles.
+    ///
+    /// Objects with finalizers are only freed by a later collection, after their finalizers have
+    /// run, so a full collection takes two calls.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("var a = {}; a.self = a; a = null;").unwrap();
+    /// ctx.gc();
+    /// ```
+    pub fn gc(&self) {
+        unsafe { duk_sys::duk_gc(self.raw, 0) }
+    }
+
+    /// Runs a garbage collection like `gc`, and also compacts the property tables of all objects
+    /// to release unused slots.  This is slower, so it is for idle moments, like between the
+    /// requests that a long-running host serves.
+    pub fn gc_compact(&self) {
+        unsafe { duk_sys::duk_gc(self.raw, duk_sys::DUK_GC_COMPACT) }
+    }
+}
+
+impl<'a> Reference<'a> {
+    /// Shrinks the property table of the object that this reference points to, to fit the
+    /// properties that it has now.  Useful for objects that won't get more properties.  Values
+    /// that aren't objects are left alone.
+    pub fn compact(&self) {
+        self.with_value(|| unsafe { duk_sys::duk_compact(self.ctx.raw, -1) })
+    }
+
+    /// Inspects the heap allocation of the value that this reference points to, or returns
+    /// `None` for values that aren't allocated from the heap, like numbers.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let array = ctx.eval_string("[1, 2, 3]").unwrap();
+    /// let info = array.heap_info().unwrap();
+    /// assert!(info.array_size.unwrap() >= 3);
+    ///
+    /// assert_eq!(None, ctx.eval_string("42").unwrap().heap_info());
+    /// ```
+    pub fn heap_info(&self) -> Option<HeapInfo> {
+        self.with_value(|| unsafe {
+            let raw = self.ctx.raw;
+            duk_sys::duk_inspect_value(raw, -1);
+            let info = if 1 == duk_sys::duk_has_prop_string(raw, -1, crate::nul_str(b"hptr\0")) {
+                let get = |name: &str| {
+                    crate::get_number_property(raw, -1, name)
+                        .filter(|&n| n >= 0.0)
+                        .map(|n| n as usize)
+                };
+                Some(HeapInfo {
+                    refcount: get("refc"),
+                    header_bytes: get("hbytes").unwrap_or(0),
+                    class: get("class").map(|n| n as u32),
+                    property_bytes: get("pbytes"),
+                    entry_size: get("esize"),
+                    entry_next: get("enext"),
+                    array_size: get("asize"),
+                    hash_size: get("hsize"),
+                    bytecode_bytes: get("bcbytes"),
+                    data_bytes: get("dbytes"),
+                })
+            } else {
+                None
+            };
+            duk_sys::duk_pop(raw);
+            info
+        })
+    }
+}
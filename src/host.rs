@@ -0,0 +1,156 @@
+//! Javascript objects that own Rust values, which are dropped when the objects are finalized.
+use std::any;
+use std::cell;
+use std::collections;
+use std::os;
+
+use crate::Context;
+use crate::Reference;
+
+/// The hidden symbol that holds the id of the Rust value of a host object.  Scripts can't access
+/// hidden symbols.
+const HOST_ID: &[u8] = b"\xFFhostId\0";
+
+/// The Rust values of the host objects of a context.
+#[derive(Default)]
+pub(crate) struct HostObjects {
+    values: cell::RefCell<collections::HashMap<u64, HostValue>>,
+    /// The number of references that lent out each value with `Reference::host_data`.
+    pins: cell::RefCell<collections::HashMap<u64, usize>>,
+    next_id: cell::Cell<u64>,
+}
+
+/// The Rust value of a host object.
+struct HostValue {
+    /// The heap pointer of the host object.  Objects that inherit from it see its id and
+    /// finalizer too, but don't own the value.
+    owner: *mut os::raw::c_void,
+    value: Box<dyn any::Any>,
+}
+
+impl HostObjects {
+    /// Drops the values of the host objects that weren't finalized, once the heap is destroyed.
+    pub(crate) fn clear(&self) {
+        let values = std::mem::take(&mut *self.values.borrow_mut());
+        drop(values);
+    }
+}
+
+impl Context {
+    /// Creates an object that owns `value`, and returns a reference to it.
+    ///
+    /// The value can be borrowed with `Reference::host_data`, and is dropped exactly once: when
+    /// the object is finalized after it became unreachable, or when the context is dropped.
+    /// Scripts only see an empty object, which they can add properties to, or pass back to Rust.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// struct Counter(std::cell::Cell<u32>);
+    ///
+    /// let ctx = duk::Context::new();
+    /// let counter = ctx.push_host_object(Counter(std::cell::Cell::new(0)));
+    /// let data = counter.host_data::<Counter>().unwrap();
+    /// data.0.set(data.0.get() + 1);
+    /// assert_eq!(1, data.0.get());
+    /// assert!(counter.host_data::<String>().is_none());
+    /// ```
+    pub fn push_host_object<T: 'static>(&self, value: T) -> Reference<'_> {
+        let host = &self.shared.host_objects;
+        let id = host.next_id.get();
+        host.next_id.set(id + 1);
+        unsafe {
+            duk_sys::duk_push_object(self.raw);
+            let owner = duk_sys::duk_get_heapptr(self.raw, -1);
+            host.values.borrow_mut().insert(
+                id,
+                HostValue {
+                    owner,
+                    value: Box::new(value),
+                },
+            );
+            duk_sys::duk_push_number(self.raw, id as f64);
+            duk_sys::duk_put_prop_string(self.raw, -2, crate::nul_str(HOST_ID));
+            duk_sys::duk_push_c_function(self.raw, Some(host_finalizer), 2);
+            duk_sys::duk_set_finalizer(self.raw, -2);
+            self.pop_reference()
+        }
+    }
+
+    /// Stops lending out the value of a host object from a reference that is dropped.
+    pub(crate) fn unpin_host_data(&self, id: u64) {
+        let mut pins = self.shared.host_objects.pins.borrow_mut();
+        if let collections::hash_map::Entry::Occupied(mut entry) = pins.entry(id) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+impl<'a> Reference<'a> {
+    /// Borrows the Rust value of the host object that this reference points to, if it is one, and
+    /// its value is a `T`.
+    ///
+    /// The value stays alive while it is borrowed, even if a script gets hold of the finalizer of
+    /// the object and calls it.
+    pub fn host_data<T: 'static>(&self) -> Option<&T> {
+        let host = &self.ctx.shared.host_objects;
+        let id = self.with_value(|| unsafe { host_id(self.ctx.raw, host, -1) })?;
+        let value = {
+            let values = host.values.borrow();
+            let value = values.get(&id)?.value.downcast_ref::<T>()?;
+            // The box doesn't move when the map changes, and the value isn't dropped while this
+            // reference pins it.
+            value as *const T
+        };
+        if self.host_pin.get().is_none() {
+            self.host_pin.set(Some(id));
+            *host.pins.borrow_mut().entry(id).or_insert(0) += 1;
+        }
+        Some(unsafe { &*value })
+    }
+}
+
+/// The id of the Rust value of the host object at the specified index, if it is one, and not
+/// only an object that inherits from one.
+unsafe fn host_id(
+    ctx: *mut duk_sys::duk_context,
+    host: &HostObjects,
+    index: duk_sys::duk_idx_t,
+) -> Option<u64> {
+    if 0 == duk_sys::duk_is_object(ctx, index) {
+        return None;
+    }
+    let id = if 1 == duk_sys::duk_get_prop_string(ctx, index, crate::nul_str(HOST_ID)) {
+        Some(duk_sys::duk_get_number(ctx, -1) as u64)
+    } else {
+        None
+    };
+    duk_sys::duk_pop(ctx);
+    let owner = duk_sys::duk_get_heapptr(ctx, index);
+    id.filter(|id| {
+        host.values
+            .borrow()
+            .get(id)
+            .is_some_and(|value| value.owner == owner)
+    })
+}
+
+/// Drops the Rust value of the finalized host object.  Scripts may call this through
+/// `Duktape.fin`, so values that are lent out are kept.
+unsafe extern "C" fn host_finalizer(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let value = Context::with_raw(ctx, |ctx| {
+        let host = &ctx.shared.host_objects;
+        let id = host_id(ctx.raw, host, 0)?;
+        if host.pins.borrow().contains_key(&id) {
+            None
+        } else {
+            host.values.borrow_mut().remove(&id)
+        }
+    });
+    // The value may run arbitrary code when dropped, so no borrows are held.
+    drop(value);
+    0
+}
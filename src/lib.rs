@@ -11,6 +11,9 @@
 //! implemented.
 //!
 //! [1]: http://duktape.org/
+// `failure::Fail` derives expand to impls nested inside constants.
+#![allow(non_local_definitions)]
+use std::cell;
 use std::collections;
 use std::ffi;
 use std::fmt;
@@ -18,46 +21,159 @@ use std::mem;
 use std::os;
 use std::path;
 use std::ptr;
+use std::rc;
 use std::result;
 use std::slice;
 use std::str;
-use std::sync::atomic;
+use std::thread;
 
+#[cfg(feature = "logging")]
+mod console;
+mod copy;
 #[cfg(feature = "serde")]
 mod de;
+#[cfg(feature = "debugger")]
+mod debugger;
+mod esm;
+mod event_loop;
+mod handle;
+mod heap;
+mod host;
+#[cfg(feature = "logging")]
+mod logging;
+mod module;
+#[cfg(feature = "alloc-pool")]
+mod pool;
+mod promise;
+mod scope;
 #[cfg(feature = "serde")]
 mod ser;
+mod stack;
+mod task;
 
+#[cfg(feature = "logging")]
+pub use crate::console::DEFAULT_CONSOLE_TARGET;
 #[cfg(feature = "serde")]
 pub use crate::de::deserialize_from_stack;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::Breakpoint;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DValue;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebugFrame;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebugNotification;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebugStatus;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebugTargetInfo;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebugTransport;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::Debugger;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebuggerError;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DebuggerResult;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::TcpDebugListener;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::TcpDebugTransport;
+#[cfg(feature = "debugger")]
+pub use crate::debugger::DEFAULT_DEBUG_PORT;
+pub use crate::esm::transform_es_module;
+pub use crate::event_loop::EventLoop;
+pub use crate::event_loop::TimerError;
+pub use crate::heap::HeapInfo;
+#[cfg(feature = "logging")]
+pub use crate::logging::LogRecord;
+#[cfg(feature = "logging")]
+pub use crate::logging::LogSink;
+#[cfg(feature = "logging")]
+pub use crate::logging::LogSource;
+pub use crate::module::FsModuleResolver;
+pub use crate::module::ModuleError;
+pub use crate::module::ModuleLoader;
+pub use crate::module::ModuleResolver;
+pub use crate::module::ModuleSource;
+pub use crate::module::ModuleTransformer;
+pub use crate::module::NativeModule;
+#[cfg(feature = "alloc-pool")]
+pub use crate::pool::PoolAllocatorConfig;
+#[cfg(feature = "alloc-pool")]
+pub use crate::pool::PoolAllocatorStats;
+#[cfg(feature = "alloc-pool")]
+pub use crate::pool::PoolStats;
+pub use crate::scope::Local;
+pub use crate::scope::Scope;
 #[cfg(feature = "serde")]
 pub use crate::ser::serialize_to_stack;
+pub use crate::stack::Stack;
+pub use crate::stack::Type;
+pub use crate::task::TaskDriver;
 #[cfg(feature = "duk-derive")]
 pub use duk_derive::*;
 #[cfg(feature = "derive")]
 pub use duk_sys;
 
-pub type ModuleResolver = dyn Fn(String, String) -> String;
-pub type ModuleLoader = dyn Fn(String) -> Option<String>;
-
 /// A context corresponding to a thread of script execution.
 pub struct Context {
     raw: *mut duk_sys::duk_context,
-    next_stash_idx: atomic::AtomicUsize,
-    module_resolver: Option<*mut Box<ModuleResolver>>,
-    module_loader: Option<*mut Box<ModuleLoader>>,
+    shared: rc::Rc<Shared>,
+    modules: Option<*mut module::Modules>,
 }
 
 #[derive(Default)]
 pub struct ContextBuilder {
-    module_resolver: Option<Box<ModuleResolver>>,
-    module_loader: Option<Box<ModuleLoader>>,
+    modules: module::Modules,
+    promises: bool,
+    #[cfg(feature = "logging")]
+    console_target: Option<String>,
+    #[cfg(feature = "logging")]
+    log_sink: Option<Box<logging::LogSink>>,
+    #[cfg(feature = "alloc-pool")]
+    pool_allocator: Option<PoolAllocatorConfig>,
+}
+
+/// State that is shared between a `Context` and the native callbacks running within its heap.
+///
+/// The heap user data points at this, so that callbacks can recover a `Context` for the raw
+/// context they are handed.
+struct Shared {
+    handles: handle::Handles,
+    promises: bool,
+    // Native functions point at the inner boxes, so they must not move.
+    #[allow(clippy::vec_box)]
+    async_fns: cell::RefCell<Vec<Box<Box<task::AsyncFn>>>>,
+    tasks: cell::RefCell<Vec<task::Task>>,
+    next_task_id: cell::Cell<u32>,
+    #[cfg(feature = "logging")]
+    console: console::Console,
+    #[cfg(feature = "logging")]
+    log_sink: Option<Box<logging::LogSink>>,
+    #[cfg(feature = "logging")]
+    logger_levels: cell::RefCell<collections::HashMap<String, log::LevelFilter>>,
+    #[cfg(feature = "exec-timeout")]
+    exec_deadline: cell::Cell<Option<std::time::Instant>>,
+    #[cfg(feature = "debugger")]
+    debugger_attached: cell::Cell<bool>,
+    #[cfg(feature = "debugger")]
+    debugger_request_handler: cell::RefCell<Option<Box<debugger::RequestHandler>>>,
+    #[cfg(feature = "alloc-pool")]
+    pool: Option<pool::Pool>,
+    host_objects: host::HostObjects,
+    /// The number of nested `Context::scope` calls.
+    scope_depth: cell::Cell<usize>,
 }
 
 /// Something that can be used as an argument when calling into Javascript code.
 pub trait Argument {
     /// Pushes this argument to the stack of the specified context.  This requires interaction with
     /// the internals of the context, and is therefore an unsafe operation.
+    ///
+    /// # Safety
+    ///
+    /// Implementations must push exactly one value onto the value stack of `context`.
     unsafe fn push_to_context(&self, context: &Context);
 }
 
@@ -65,7 +181,9 @@ pub trait Argument {
 #[derive(Debug)]
 pub struct Reference<'a> {
     ctx: &'a Context,
-    stash_idx: duk_sys::duk_uarridx_t,
+    handle: duk_sys::duk_uarridx_t,
+    /// The id of the host object value that this reference lent out, if any.
+    host_pin: cell::Cell<Option<u64>>,
 }
 
 /// A Javascript/Ecmascript value that exists in the Rust world.
@@ -108,6 +226,8 @@ pub enum Error {
     #[cfg(feature = "serde")]
     #[fail(display = "Serialization error: {:?}", raw)]
     Ser { raw: ser::Error },
+    #[fail(display = "Promise error: {}", message)]
+    Promise { message: String },
 }
 
 pub type Result<A> = result::Result<A, Error>;
@@ -147,9 +267,6 @@ pub enum JsErrorKind {
     Uri,
 }
 
-#[cfg(all(test, feature = "logging"))]
-pub static mut LAST_LOG_LEVELS: &'static mut [Option<log::Level>; 16] = &mut [None; 16];
-
 impl Context {
     /// Creates a new context.
     pub fn new() -> Context {
@@ -160,48 +277,123 @@ impl Context {
         ContextBuilder::default()
     }
 
-    fn from_builder(builder: ContextBuilder) -> Context {
+    #[cfg_attr(
+        not(any(feature = "logging", feature = "alloc-pool")),
+        allow(unused_mut)
+    )]
+    fn from_builder(mut builder: ContextBuilder) -> Context {
+        // Compressed pointers only work for heaps in a pool.
+        #[cfg(feature = "alloc-pool-ptrcomp")]
+        let pool_allocator = Some(builder.pool_allocator.take().unwrap_or_default());
+        #[cfg(all(feature = "alloc-pool", not(feature = "alloc-pool-ptrcomp")))]
+        let pool_allocator = builder.pool_allocator.take();
+        let shared = rc::Rc::new(Shared {
+            handles: handle::Handles::new(),
+            promises: builder.promises,
+            async_fns: cell::RefCell::new(Vec::new()),
+            tasks: cell::RefCell::new(Vec::new()),
+            next_task_id: cell::Cell::new(0),
+            #[cfg(feature = "logging")]
+            console: console::Console::new(builder.console_target.take()),
+            #[cfg(feature = "logging")]
+            log_sink: builder.log_sink.take(),
+            #[cfg(feature = "logging")]
+            logger_levels: cell::RefCell::new(collections::HashMap::new()),
+            #[cfg(feature = "exec-timeout")]
+            exec_deadline: cell::Cell::new(None),
+            #[cfg(feature = "debugger")]
+            debugger_attached: cell::Cell::new(false),
+            #[cfg(feature = "debugger")]
+            debugger_request_handler: cell::RefCell::new(None),
+            #[cfg(feature = "alloc-pool")]
+            pool: pool_allocator.map(pool::Pool::new),
+            host_objects: host::HostObjects::default(),
+            scope_depth: cell::Cell::new(0),
+        });
+        #[allow(unused_mut)]
+        let mut funcs = duk_sys::duk_memory_functions {
+            alloc_func: None,
+            realloc_func: None,
+            free_func: None,
+            udata: rc::Rc::as_ptr(&shared) as *mut os::raw::c_void,
+        };
+        #[cfg(feature = "alloc-pool")]
+        if shared.pool.is_some() {
+            funcs.alloc_func = Some(pool::alloc);
+            funcs.realloc_func = Some(pool::realloc);
+            funcs.free_func = Some(pool::free);
+        }
         let raw = unsafe {
-            duk_sys::duk_create_heap(None, None, None, ptr::null_mut(), Some(fatal_handler))
+            duk_sys::duk_create_heap(
+                funcs.alloc_func,
+                funcs.realloc_func,
+                funcs.free_func,
+                funcs.udata,
+                Some(fatal_handler),
+            )
         };
+        assert!(!raw.is_null(), "could not create the Duktape heap");
 
         unsafe {
+            shared.handles.install(raw);
+            #[cfg(feature = "rom-builtins")]
+            Context::shadow_rom_duktape(raw);
             Context::setup_logging(raw);
         }
 
-        let (resolver_ptr, loader_ptr) = match (builder.module_resolver, builder.module_loader) {
-            (Some(module_resolver), Some(module_loader)) => unsafe {
-                let resolver_ptr = Box::into_raw(Box::new(module_resolver));
-                let loader_ptr = Box::into_raw(Box::new(module_loader));
-                duk_sys::duk_push_object(raw);
-
-                duk_sys::duk_push_c_function(
-                    raw,
-                    Some(module_resolve_handler),
-                    duk_sys::DUK_VARARGS,
-                );
-                duk_sys::duk_push_pointer(raw, resolver_ptr as *mut os::raw::c_void);
-                duk_sys::duk_put_prop_string(raw, -2, nul_str(b"closure\0"));
-                duk_sys::duk_put_prop_string(raw, -2, nul_str(b"resolve\0"));
-
-                duk_sys::duk_push_c_function(raw, Some(module_load_handler), duk_sys::DUK_VARARGS);
-                duk_sys::duk_push_pointer(raw, loader_ptr as *mut os::raw::c_void);
-                duk_sys::duk_put_prop_string(raw, -2, nul_str(b"closure\0"));
-                duk_sys::duk_put_prop_string(raw, -2, nul_str(b"load\0"));
-
-                duk_sys::duk_module_node_init(raw);
+        #[cfg(feature = "exec-timeout")]
+        {
+            install_exec_timeout_check();
+            EXEC_TIMEOUT_HEAPS.with(|heaps| heaps.borrow_mut().insert(funcs.udata as usize));
+        }
 
-                (Some(resolver_ptr), Some(loader_ptr))
-            },
-            (_, _) => (None, None),
+        let modules_ptr = if builder.modules.is_enabled() {
+            Some(unsafe { builder.modules.install(raw) })
+        } else {
+            None
         };
 
-        Context {
+        let ctx = Context {
             raw,
-            next_stash_idx: atomic::AtomicUsize::new(0),
-            module_resolver: resolver_ptr,
-            module_loader: loader_ptr,
+            shared,
+            modules: modules_ptr,
+        };
+        if ctx.shared.promises {
+            ctx.install_promises();
         }
+        ctx
+    }
+
+    /// Runs `action` with a non-owning `Context` for a raw context that was handed to a native
+    /// callback.
+    ///
+    /// The raw context must belong to a heap that was created by `from_builder`.
+    unsafe fn with_raw<F, R>(raw: *mut duk_sys::duk_context, action: F) -> R
+    where
+        F: FnOnce(&Context) -> R,
+    {
+        let mut funcs = mem::zeroed::<duk_sys::duk_memory_functions>();
+        duk_sys::duk_get_memory_functions(raw, &mut funcs);
+        assert!(!funcs.udata.is_null());
+
+        // The view must neither destroy the heap nor release the shared state, so it is never
+        // dropped.
+        let ctx = mem::ManuallyDrop::new(Context {
+            raw,
+            shared: rc::Rc::from_raw(funcs.udata as *const Shared),
+            modules: None,
+        });
+        action(&ctx)
+    }
+
+    /// Replaces the `Duktape` global with a RAM object that inherits from the ROM one, since the
+    /// extras add properties like `Duktape.Logger` to it, and ROM objects can't be extended.
+    #[cfg(feature = "rom-builtins")]
+    unsafe fn shadow_rom_duktape(ctx: *mut duk_sys::duk_context) {
+        duk_sys::duk_push_object(ctx);
+        duk_sys::duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+        duk_sys::duk_set_prototype(ctx, -2);
+        duk_sys::duk_put_global_string(ctx, nul_str(b"Duktape\0"));
     }
 
     #[cfg(feature = "logging")]
@@ -241,6 +433,8 @@ impl Context {
 
         // Stack: [ global .Duktape .Logger .prototype ]
         duk_pop_n(ctx, 4);
+
+        console::install(ctx);
     }
 
     #[cfg(not(feature = "logging"))]
@@ -274,7 +468,7 @@ impl Context {
     ///   _ => unreachable!(),
     /// }
     /// ```
-    pub fn eval_string(&self, string: &str) -> Result<Reference> {
+    pub fn eval_string(&self, string: &str) -> Result<Reference<'_>> {
         let ptr = string.as_ptr() as *const i8;
         let len = string.len();
         unsafe {
@@ -285,12 +479,13 @@ impl Context {
 
     /// Like `eval_string`, but sets the file name for all of the evaluated functions to the
     /// specified string.
-    pub fn eval_string_with_filename(&self, filename: &str, string: &str) -> Result<Reference> {
+    pub fn eval_string_with_filename(&self, filename: &str, string: &str) -> Result<Reference<'_>> {
         let filename_ptr = filename.as_ptr() as *const i8;
         let string_ptr = string.as_ptr() as *const i8;
         unsafe {
             duk_sys::duk_push_lstring(self.raw, filename_ptr, filename.len());
-            let flags = duk_sys::DUK_COMPILE_EVAL
+            let flags = COMPILE_FILENAME_ARG
+                | duk_sys::DUK_COMPILE_EVAL
                 | duk_sys::DUK_COMPILE_NOSOURCE
                 | duk_sys::DUK_COMPILE_SAFE;
             let ret = duk_sys::duk_eval_raw(self.raw, string_ptr, string.len(), flags);
@@ -300,7 +495,7 @@ impl Context {
 
     /// Loads and evaluates the specified file within the current
     /// context.
-    pub fn eval_file(&self, path: &path::Path) -> Result<Reference> {
+    pub fn eval_file(&self, path: &path::Path) -> Result<Reference<'_>> {
         let str_path = path.to_string_lossy();
         let ffi_str = ffi::CString::new(&*str_path).unwrap();
         unsafe {
@@ -309,8 +504,58 @@ impl Context {
         }
     }
 
+    /// Compiles the source of a CommonJS module into bytecode that can be loaded with
+    /// `ModuleSource::Bytecode`.
+    ///
+    /// The specified module id is used as the file name of the compiled function.
+    pub fn compile_module(&self, id: &str, source: &str) -> Result<Vec<u8>> {
+        let wrapped = format!(
+            "function main(exports, require, module, __filename, __dirname) {{{}\n}}",
+            source
+        );
+        unsafe {
+            duk_sys::duk_push_lstring(self.raw, id.as_ptr() as *const i8, id.len());
+            let flags = COMPILE_FILENAME_ARG
+                | duk_sys::DUK_COMPILE_FUNCTION
+                | duk_sys::DUK_COMPILE_NOSOURCE
+                | duk_sys::DUK_COMPILE_SAFE;
+            let ret = duk_sys::duk_compile_raw(
+                self.raw,
+                wrapped.as_ptr() as *const i8,
+                wrapped.len(),
+                flags,
+            );
+            if ret != 0 {
+                return Err(self.pop_error());
+            }
+
+            duk_sys::duk_dump_function(self.raw);
+            let mut size = 0;
+            let data = duk_sys::duk_get_buffer_data(self.raw, -1, &mut size);
+            let bytecode = slice::from_raw_parts(data as *const u8, size).to_vec();
+            duk_sys::duk_pop(self.raw);
+            Ok(bytecode)
+        }
+    }
+
+    /// Loads a function from bytecode created by `duk_dump_function`.
+    fn load_bytecode(&self, bytecode: &[u8]) -> Result<Reference<'_>> {
+        unsafe {
+            let data = duk_sys::duk_push_fixed_buffer(self.raw, bytecode.len());
+            ptr::copy(bytecode.as_ptr(), data as *mut u8, bytecode.len());
+            let ret = duk_sys::duk_safe_call(
+                self.raw,
+                Some(load_function_handler),
+                ptr::null_mut(),
+                1,
+                1,
+            );
+            self.pop_reference_or_error(ret)
+        }
+    }
+
     /// Retrieves a reference to the global object.
-    pub fn global_object(&self) -> Reference {
+    pub fn global_object(&self) -> Reference<'_> {
         unsafe {
             duk_sys::duk_push_global_object(self.raw);
             self.pop_reference()
@@ -321,10 +566,41 @@ impl Context {
     /// arguments.
     ///
     /// Behaves like `global_object().call_method(name, args)`.
-    pub fn call_global(&self, name: &str, args: &[&dyn Argument]) -> Result<Reference> {
+    pub fn call_global(&self, name: &str, args: &[&dyn Argument]) -> Result<Reference<'_>> {
         self.global_object().call_method(name, args)
     }
 
+    /// Sets the point in time after which running scripts are aborted with a `RangeError`, or
+    /// removes it.
+    ///
+    /// Duktape checks the deadline periodically while executing bytecode, so native functions
+    /// that block are not interrupted.  The deadline stays in place until it is changed, so it
+    /// applies to all later calls into the context too.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let ctx = duk::Context::new();
+    /// ctx.set_exec_deadline(Some(Instant::now() + Duration::from_millis(10)));
+    /// assert!(ctx.eval_string("while (true) {}").is_err());
+    /// ctx.set_exec_deadline(None);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics when setting a deadline if another user of `duk-sys` installed its own execution
+    /// timeout check, since Duktape would never check the deadline.
+    #[cfg(feature = "exec-timeout")]
+    pub fn set_exec_deadline(&self, deadline: Option<std::time::Instant>) {
+        assert!(
+            deadline.is_none() || install_exec_timeout_check(),
+            "another execution timeout check was installed in duk-sys"
+        );
+        self.shared.exec_deadline.set(deadline);
+    }
+
     #[cfg(test)]
     pub fn assert_clean(&self) {
         unsafe {
@@ -336,20 +612,11 @@ impl Context {
         }
     }
 
-    fn gen_stash_idx(&self) -> duk_sys::duk_uarridx_t {
-        self.next_stash_idx.fetch_add(1, atomic::Ordering::Relaxed) as duk_sys::duk_uarridx_t
-    }
-
-    unsafe fn pop_reference(&self) -> Reference {
-        let idx = self.gen_stash_idx();
-        duk_sys::duk_push_heap_stash(self.raw);
-        duk_sys::duk_dup(self.raw, -2);
-        duk_sys::duk_put_prop_index(self.raw, -2, idx);
-        duk_sys::duk_pop_2(self.raw);
-
+    unsafe fn pop_reference(&self) -> Reference<'_> {
         Reference {
             ctx: self,
-            stash_idx: idx,
+            handle: self.shared.handles.insert(self.raw),
+            host_pin: cell::Cell::new(None),
         }
     }
 
@@ -359,7 +626,7 @@ impl Context {
         e
     }
 
-    unsafe fn pop_reference_or_error(&self, ret: duk_sys::duk_ret_t) -> Result<Reference> {
+    unsafe fn pop_reference_or_error(&self, ret: duk_sys::duk_ret_t) -> Result<Reference<'_>> {
         if ret == 0 {
             Ok(self.pop_reference())
         } else {
@@ -373,12 +640,11 @@ impl Context {
             duk_sys::duk_put_global_lstring(self.raw, F::NAME.as_ptr().cast(), F::NAME.len());
         }
     }
+}
 
-    pub unsafe fn stack_guard(&self) -> StackRAII {
-        StackRAII {
-            ctx: self.raw,
-            idx: duk_sys::duk_get_top(self.raw),
-        }
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
     }
 }
 
@@ -390,11 +656,16 @@ impl fmt::Debug for Context {
 
 impl Drop for Context {
     fn drop(&mut self) {
+        self.cancel_tasks();
         unsafe { duk_sys::duk_destroy_heap(self.raw) };
-        if let Some(ptr) = self.module_resolver {
-            drop(unsafe { Box::from_raw(ptr) });
+        #[cfg(feature = "exec-timeout")]
+        {
+            let udata = rc::Rc::as_ptr(&self.shared) as usize;
+            // The registry is gone already if the context is dropped while its thread exits.
+            let _ = EXEC_TIMEOUT_HEAPS.try_with(|heaps| heaps.borrow_mut().remove(&udata));
         }
-        if let Some(ptr) = self.module_loader {
+        self.shared.host_objects.clear();
+        if let Some(ptr) = self.modules {
             drop(unsafe { Box::from_raw(ptr) });
         }
     }
@@ -402,12 +673,166 @@ impl Drop for Context {
 
 impl ContextBuilder {
     pub fn with_module_resolver(mut self, module_resolver: Box<ModuleResolver>) -> Self {
-        self.module_resolver = Some(module_resolver);
+        self.modules.resolver = Some(module_resolver);
         self
     }
 
     pub fn with_module_loader(mut self, module_loader: Box<ModuleLoader>) -> Self {
-        self.module_loader = Some(module_loader);
+        self.modules.loader = Some(module_loader);
+        self
+    }
+
+    /// Registers a transformer for the sources of modules whose resolved id has the specified file
+    /// extension (without the leading dot).
+    ///
+    /// When a module loader returns `ModuleSource::Js` for such a module, the transformer is
+    /// called with the resolved id and source, and decides how the module is actually loaded.  By
+    /// default, `json` modules are loaded as `ModuleSource::Json`; registering a transformer for
+    /// `json` replaces that behavior.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let loader: Box<duk::ModuleLoader> =
+    ///     Box::new(|_| Ok(duk::ModuleSource::Js("exports.x = ${x};".to_owned())));
+    /// let ctx = duk::Context::builder()
+    ///     .with_module_resolver(Box::new(|id, _| Ok(id)))
+    ///     .with_module_loader(loader)
+    ///     .with_module_transformer(
+    ///         "tmpl",
+    ///         Box::new(|_, source| Ok(duk::ModuleSource::Js(source.replace("${x}", "3")))),
+    ///     )
+    ///     .build();
+    /// let value = ctx.eval_string("require('a.tmpl').x").unwrap().to_value();
+    /// assert_eq!(duk::Value::Number(3.0), value);
+    /// ```
+    pub fn with_module_transformer(
+        mut self,
+        extension: &str,
+        transformer: Box<ModuleTransformer>,
+    ) -> Self {
+        self.modules
+            .transformers
+            .insert(extension.to_owned(), transformer);
+        self
+    }
+
+    /// Enables ES module syntax (`import` and `export`) in loaded Javascript modules.
+    ///
+    /// Modules are rewritten with `transform_es_module` after the transformer for their extension
+    /// has run, so modules can mix ES module syntax with `require`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder()
+    ///     .with_module_loader(Box::new(|id| match id.as_str() {
+    ///         "math" => Ok(duk::ModuleSource::Js("export var answer = 42;".to_owned())),
+    ///         _ => Err(duk::ModuleError::NotFound),
+    ///     }))
+    ///     .with_es_modules()
+    ///     .build();
+    /// let value = ctx.eval_string("require('math').answer").unwrap().to_value();
+    /// assert_eq!(duk::Value::Number(42.0), value);
+    /// ```
+    pub fn with_es_modules(mut self) -> Self {
+        self.modules.es_modules = true;
+        self
+    }
+
+    /// Provides a `Promise` global, implemented by the Promise polyfill from the Duktape
+    /// distribution.
+    ///
+    /// Promise reactions are queued as microtasks, which only run when the host drains the queue
+    /// with `Context::run_microtasks`, waits for a promise with `Context::resolve_promise`, or
+    /// runs an `EventLoop`.
+    pub fn with_promises(mut self) -> Self {
+        self.promises = true;
+        self
+    }
+
+    /// Sets the `log` target that messages logged with the `console` global go to, instead of
+    /// `DEFAULT_CONSOLE_TARGET`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder().with_console_target("plugin").build();
+    /// ctx.eval_string("console.log('hello', {answer: 42})").unwrap();
+    /// ```
+    #[cfg(feature = "logging")]
+    pub fn with_console_target(mut self, target: &str) -> Self {
+        self.console_target = Some(target.to_owned());
+        self
+    }
+
+    /// Hands the messages that scripts log, with `Duktape.Logger` or the `console` global, to
+    /// `sink` instead of the `log` crate.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let records = Rc::new(RefCell::new(Vec::new()));
+    /// let sink = records.clone();
+    /// let ctx = duk::Context::builder()
+    ///     .with_log_sink(move |record| sink.borrow_mut().push(record))
+    ///     .build();
+    /// ctx.eval_string("console.warn('low on', 'memory')").unwrap();
+    ///
+    /// let records = records.borrow();
+    /// assert_eq!(log::Level::Warn, records[0].level);
+    /// assert_eq!("low on memory", records[0].message);
+    /// ```
+    #[cfg(feature = "logging")]
+    pub fn with_log_sink<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(LogRecord) + 'static,
+    {
+        self.log_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Allocates the heap from a memory region of its own, divided into pools as configured,
+    /// instead of with the system allocator.
+    ///
+    /// Allocations fail once the pools that fit them are exhausted, which scripts see as an
+    /// `Error` with the message `alloc failed`.  The usage of the pools can be read with
+    /// `Context::pool_stats`.
+    ///
+    /// # Panics
+    ///
+    /// `build` panics if the pools don't fit in the region, and fails fatally if the context
+    /// doesn't fit in the pools.  With the `alloc-pool-ptrcomp` feature, it also panics if the
+    /// region is too large for compressed pointers, or if another context exists.
+    #[cfg(feature = "alloc-pool")]
+    pub fn with_pool_allocator(mut self, config: PoolAllocatorConfig) -> Self {
+        self.pool_allocator = Some(config);
+        self
+    }
+
+    /// Registers a module implemented in Rust, which `require(id)` resolves to without loading any
+    /// source code.
+    ///
+    /// The `init` function is called with the module's `exports` object the first time the module
+    /// is required, and native modules take precedence over the module resolver and loader.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder()
+    ///     .native_module("answer", |exports| exports.set("value", &duk::Value::Number(42.0)))
+    ///     .build();
+    /// let value = ctx.eval_string("require('answer').value").unwrap().to_value();
+    /// assert_eq!(duk::Value::Number(42.0), value);
+    /// ```
+    pub fn native_module<F>(mut self, id: &str, init: F) -> Self
+    where
+        F: Fn(&Reference) -> Result<()> + 'static,
+    {
+        self.modules.native.insert(id.to_owned(), Box::new(init));
         self
     }
 
@@ -448,6 +873,45 @@ impl<'a> Reference<'a> {
         })
     }
 
+    /// Sets the property with the specified key to the specified value, provided that this
+    /// reference points to an object.
+    pub fn set(&self, name: &str, value: &dyn Argument) -> Result<()> {
+        self.put(name, || unsafe { value.push_to_context(self.ctx) })
+    }
+
+    /// Adds the Rust function `F` as a property named `F::NAME` of the object that this reference
+    /// points to.
+    ///
+    /// This is the equivalent of `Context::add_global_fn` for arbitrary objects, such as the
+    /// `exports` of a native module.
+    pub fn add_fn<F: DukFunction>(&self) -> Result<()> {
+        self.put(F::NAME, || unsafe {
+            duk_sys::duk_push_c_function(self.ctx.raw, Some(F::duk_call), F::NARGS as i32);
+        })
+    }
+
+    fn put<F>(&self, name: &str, push_value: F) -> Result<()>
+    where
+        F: FnOnce(),
+    {
+        self.with_value(|| unsafe {
+            if 0 == duk_sys::duk_is_object(self.ctx.raw, -1) {
+                let msg = ffi::CString::new("value is not an object").unwrap();
+                duk_sys::duk_push_error_object(
+                    self.ctx.raw,
+                    duk_sys::DUK_ERR_TYPE_ERROR as i32,
+                    msg.as_ptr(),
+                );
+                Err(self.ctx.pop_error())
+            } else {
+                duk_sys::duk_push_lstring(self.ctx.raw, name.as_ptr() as *const i8, name.len());
+                push_value();
+                duk_sys::duk_put_prop(self.ctx.raw, -3);
+                Ok(())
+            }
+        })
+    }
+
     /// Calls the function that this reference points to without a `this` binding, using the
     /// specified arguments.
     ///
@@ -536,9 +1000,7 @@ impl<'a> Reference<'a> {
     }
 
     unsafe fn push(&self) {
-        duk_sys::duk_push_heap_stash(self.ctx.raw);
-        duk_sys::duk_get_prop_index(self.ctx.raw, -1, self.stash_idx);
-        duk_sys::duk_remove(self.ctx.raw, -2);
+        self.ctx.shared.handles.push(self.ctx.raw, self.handle);
     }
 
     unsafe fn pop(&self) {
@@ -564,10 +1026,9 @@ impl<'a> PartialEq for Reference<'a> {
 
 impl<'a> Drop for Reference<'a> {
     fn drop(&mut self) {
-        unsafe {
-            duk_sys::duk_push_heap_stash(self.ctx.raw);
-            duk_sys::duk_del_prop_index(self.ctx.raw, -1, self.stash_idx);
-            duk_sys::duk_pop(self.ctx.raw);
+        unsafe { self.ctx.shared.handles.remove(self.ctx.raw, self.handle) };
+        if let Some(id) = self.host_pin.get() {
+            self.ctx.unpin_host_data(id);
         }
     }
 }
@@ -749,6 +1210,9 @@ impl JsErrorKind {
 unsafe fn get_str<'a>(ctx: *mut duk_sys::duk_context, index: duk_sys::duk_idx_t) -> &'a str {
     let mut len = 0;
     let data = duk_sys::duk_get_lstring(ctx, index, &mut len);
+    if data.is_null() {
+        return "";
+    }
     let slice = slice::from_raw_parts(data as *const u8, len);
     str::from_utf8(slice).unwrap()
 }
@@ -757,6 +1221,18 @@ unsafe fn get_string(ctx: *mut duk_sys::duk_context, index: duk_sys::duk_idx_t)
     String::from(get_str(ctx, index))
 }
 
+/// Like `get_string`, but replaces the halves of unpaired surrogates, which Duktape keeps as they
+/// are, instead of panicking on them.
+unsafe fn get_string_lossy(ctx: *mut duk_sys::duk_context, index: duk_sys::duk_idx_t) -> String {
+    let mut len = 0;
+    let data = duk_sys::duk_get_lstring(ctx, index, &mut len);
+    if data.is_null() {
+        return String::new();
+    }
+    let slice = slice::from_raw_parts(data as *const u8, len);
+    String::from_utf8_lossy(slice).into_owned()
+}
+
 unsafe fn get_string_property(
     ctx: *mut duk_sys::duk_context,
     index: duk_sys::duk_idx_t,
@@ -790,54 +1266,55 @@ unsafe fn get_number_property(
     }
 }
 
+/// The number of arguments that `duk_eval_raw` and `duk_compile_raw` take from the value stack,
+/// which goes in the low bits of their flags.  The argument is the file name, and without it
+/// Duktape ignores the file name that was pushed.
+const COMPILE_FILENAME_ARG: duk_sys::duk_uint_t = 1;
+
 unsafe fn nul_str(data: &[u8]) -> *const os::raw::c_char {
     ffi::CStr::from_bytes_with_nul_unchecked(data).as_ptr()
 }
 
-unsafe extern "C" fn module_resolve_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
-    let requested_id = get_string(ctx, 0);
-    let parent_id = get_string(ctx, 1);
-    duk_sys::duk_pop_2(ctx);
-
-    duk_sys::duk_push_current_function(ctx);
-    duk_sys::duk_get_prop_string(ctx, -1, nul_str(b"closure\0"));
-    let ptr = duk_sys::duk_get_pointer(ctx, -1) as *mut Box<ModuleResolver>;
-    assert!(!ptr.is_null());
-    let resolve = Box::from_raw(ptr);
-    duk_sys::duk_pop_2(ctx);
-
-    // Ensure clear stack before entering the Rust wild west
-    let result = resolve(requested_id, parent_id);
-
-    mem::forget(resolve);
-
-    Value::String(result).push(ctx);
-
+unsafe extern "C" fn load_function_handler(
+    ctx: *mut duk_sys::duk_context,
+    _: *mut os::raw::c_void,
+) -> duk_sys::duk_ret_t {
+    duk_sys::duk_load_function(ctx);
     1
 }
 
-unsafe extern "C" fn module_load_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
-    let resolved_id = get_string(ctx, 0);
-    duk_sys::duk_pop_3(ctx); // Discard 'exports' and 'module'
-
+/// Retrieves the `closure` pointer stored on the currently running native function.
+unsafe fn get_closure<A>(ctx: *mut duk_sys::duk_context) -> *mut A {
     duk_sys::duk_push_current_function(ctx);
     duk_sys::duk_get_prop_string(ctx, -1, nul_str(b"closure\0"));
-    let ptr = duk_sys::duk_get_pointer(ctx, -1) as *mut Box<ModuleLoader>;
+    let ptr = duk_sys::duk_get_pointer(ctx, -1) as *mut A;
     assert!(!ptr.is_null());
-    let load = Box::from_raw(ptr);
     duk_sys::duk_pop_2(ctx);
+    ptr
+}
 
-    let result = load(resolved_id);
-
-    mem::forget(load);
-
-    // Ensure clear stack before entering the Rust wild west
-    if let Some(result) = result {
-        Value::String(result).push(ctx);
-        1
-    } else {
-        0
+/// Throws a Javascript error with the specified message from within a native function.
+///
+/// This does not return to the caller, so no values that need to be dropped may be alive in any
+/// of the calling Rust frames.
+unsafe fn throw_error(
+    ctx: *mut duk_sys::duk_context,
+    code: duk_sys::duk_errcode_t,
+    message: String,
+) -> duk_sys::duk_ret_t {
+    {
+        let message = ffi::CString::new(message).unwrap_or_default();
+        duk_sys::duk_push_error_object_raw(
+            ctx,
+            code,
+            ptr::null(),
+            0,
+            nul_str(b"%s\0"),
+            message.as_ptr(),
+        );
     }
+    duk_sys::duk_throw_raw(ctx);
+    0
 }
 
 #[cfg(feature = "logging")]
@@ -860,12 +1337,21 @@ unsafe extern "C" fn log_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk
     duk_get_prop_string(ctx, -1, nul_str(b"l\0"));
     // Stack: [ arg0 ... argN this loggerLevel ]
 
-    // Check if we should log this level with this logger
-    let logger_level = duk_get_int(ctx, -1);
+    duk_get_prop_string(ctx, -2, nul_str(b"n\0"));
+    // Stack: [ arg0 ... argN this loggerLevel loggerName ]
+    duk_to_string(ctx, -1);
+
+    // Check if we should log this level with this logger, before paying for any formatting
+    let logger_name = get_string_lossy(ctx, -1);
+    let logger_level =
+        logging::logger_level(ctx, &logger_name).unwrap_or_else(|| duk_get_int(ctx, -2));
     if level < logger_level {
         return 0;
     }
 
+    let args = logging::get_args(ctx, nargs);
+    let (file_name, line_number) = logging::caller_location(ctx);
+
     let rust_level = if level == DUK_LOG_TRACE as i32 {
         log::Level::Trace
     } else if level == DUK_LOG_DEBUG as i32 {
@@ -878,10 +1364,6 @@ unsafe extern "C" fn log_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk
         log::Level::Error
     };
 
-    duk_get_prop_string(ctx, -2, nul_str(b"n\0"));
-    // Stack: [ arg0 ... argN this loggerLevel loggerName ]
-    duk_to_string(ctx, -1);
-
     let mut total_len = 0;
 
     // Replace all args with equivalent strings, and compute their lengths
@@ -900,60 +1382,74 @@ unsafe extern "C" fn log_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk
 
         duk_to_lstring(ctx, i, &mut arg_len);
 
-        total_len += arg_len as usize;
+        total_len += arg_len;
     }
 
     // Stack: [ arg0String ... argNString this loggerLevel loggerName ]
 
-    let mut name_len = 0;
-    let name_data = duk_get_lstring(ctx, -1, &mut name_len);
-    let name_slice = slice::from_raw_parts(name_data as *const u8, name_len);
-    let name_str = str::from_utf8(name_slice).unwrap();
-
     // Allocate message space; include nargs to allocate spaces
-    let mut msg = String::with_capacity(total_len + name_str.len() + nargs as usize + 1);
-    msg.push_str(name_str);
-    msg.push(':');
+    let mut msg = String::with_capacity(total_len + nargs as usize);
 
     for i in 0..nargs {
-        let mut arg_len = 0;
-        let arg_data = duk_get_lstring(ctx, i, &mut arg_len);
-        let slice = slice::from_raw_parts(arg_data as *const u8, arg_len);
-        let arg_str = str::from_utf8(slice).unwrap();
-
-        msg.push(' ');
-        msg.push_str(arg_str);
+        if i > 0 {
+            msg.push(' ');
+        }
+        msg.push_str(&get_string_lossy(ctx, i));
     }
 
-    // For test
-    stash_log(rust_level, &msg);
+    let record = LogRecord {
+        level: rust_level,
+        source: LogSource::Logger,
+        logger: logger_name,
+        message: msg,
+        args,
+        file_name,
+        line_number,
+    };
 
-    log::log!(
-        target: &format!("{}:{}", module_path!(), name_str),
-        rust_level,
-        "{}",
-        msg
-    );
+    logging::emit(ctx, record);
 
     0
 }
 
-#[cfg(all(test, feature = "logging"))]
-fn stash_log(level: log::Level, msg: &str) {
-    println!("Logged: {} {}", level, msg);
-    unsafe {
-        for l in LAST_LOG_LEVELS.iter_mut() {
-            if l.is_none() {
-                *l = Some(level);
-                break;
-            }
-        }
-    }
+/// Whether `exec_timeout_check` is the check of `duk-sys`, which is installed for the first
+/// context.
+#[cfg(feature = "exec-timeout")]
+static EXEC_TIMEOUT_CHECK_INSTALLED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+#[cfg(feature = "exec-timeout")]
+thread_local! {
+    /// The heap user data of the live contexts of this thread, which are their shared state.
+    /// Contexts can't be sent to other threads, so their heaps only run on this thread.
+    static EXEC_TIMEOUT_HEAPS: cell::RefCell<collections::HashSet<usize>> =
+        cell::RefCell::new(collections::HashSet::new());
 }
 
-#[cfg(all(not(test), feature = "logging"))]
-fn stash_log(_: log::Level, _: &str) {
-    // No-op
+/// Installs `exec_timeout_check` unless another user of `duk-sys` installed its own check, and
+/// returns whether it is installed.
+#[cfg(feature = "exec-timeout")]
+fn install_exec_timeout_check() -> bool {
+    *EXEC_TIMEOUT_CHECK_INSTALLED
+        .get_or_init(|| duk_sys::set_exec_timeout_check(exec_timeout_check))
+}
+
+/// Checks the deadline of the context whose shared state is the heap user data.
+///
+/// The check applies to all heaps of `duk-sys`, so the user data of heaps that aren't contexts of
+/// this crate is left alone.
+#[cfg(feature = "exec-timeout")]
+fn exec_timeout_check(udata: *mut os::raw::c_void) -> bool {
+    let is_context = EXEC_TIMEOUT_HEAPS
+        .try_with(|heaps| heaps.borrow().contains(&(udata as usize)))
+        .unwrap_or(false);
+    if !is_context {
+        return false;
+    }
+    let shared = unsafe { &*(udata as *const Shared) };
+    shared
+        .exec_deadline
+        .get()
+        .is_some_and(|deadline| std::time::Instant::now() >= deadline)
 }
 
 unsafe extern "C" fn fatal_handler(_: *mut os::raw::c_void, msg_raw: *const os::raw::c_char) {
@@ -962,44 +1458,70 @@ unsafe extern "C" fn fatal_handler(_: *mut os::raw::c_void, msg_raw: *const os::
     panic!("Duktape fatal error: {}", msg)
 }
 
-pub struct StackRAII {
+/// Restores the value stack of a raw context to its height at a checkpoint when dropped, for
+/// internal code that works with raw indices.  `Stack` is the safe equivalent.
+pub(crate) struct StackRAII {
     ctx: *mut duk_sys::duk_context,
     idx: i32,
 }
 impl StackRAII {
-    pub unsafe fn new(ctx: *mut duk_sys::duk_context) -> Self {
+    /// Creates a guard for the raw context that restores the current stack height when dropped.
+    ///
+    /// # Safety
+    ///
+    /// `ctx` must be a valid context that outlives the guard.
+    pub(crate) unsafe fn new(ctx: *mut duk_sys::duk_context) -> Self {
         let mut res = StackRAII { ctx, idx: 0 };
         res.checkpoint();
         res
     }
 
-    pub fn checkpoint(&mut self) {
+    pub(crate) fn checkpoint(&mut self) {
         unsafe {
             self.idx = duk_sys::duk_get_top(self.ctx);
         }
     }
 
-    pub fn push(&mut self) {
+    #[cfg(feature = "serde")]
+    /// Moves the checkpoint above a value that is pushed to be kept.
+    pub(crate) fn push(&mut self) {
         self.idx += 1;
     }
 
-    pub fn pop(&mut self) {
-        self.idx -= 1;
-    }
-
-    pub fn idx(&self) -> i32 {
+    #[cfg(feature = "serde")]
+    pub(crate) fn idx(&self) -> i32 {
         self.idx
     }
 }
 impl Drop for StackRAII {
     fn drop(&mut self) {
-        unsafe { duk_sys::duk_pop_n(self.ctx, duk_sys::duk_get_top(self.ctx) - self.idx) }
+        let top = unsafe { duk_sys::duk_get_top(self.ctx) };
+        if !thread::panicking() {
+            debug_assert!(
+                top >= self.idx,
+                "the value stack is unbalanced, values below the checkpoint {} were popped",
+                self.idx
+            );
+        }
+        if top > self.idx {
+            unsafe { duk_sys::duk_pop_n(self.ctx, top - self.idx) }
+        }
     }
 }
 
+/// A Rust function that can be exposed to Javascript, usually implemented with `duktape_fn`.
+///
+/// # Safety
+///
+/// `duk_call` must follow the Duktape/C function calling convention for `NARGS` arguments.
 pub unsafe trait DukFunction {
     const NARGS: usize;
     const NAME: &'static str;
+    /// The Duktape/C entry point of the function.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by Duktape with a valid context.
     unsafe extern "C" fn duk_call(ctx: *mut duk_sys::duk_context) -> i32;
 }
 
@@ -1021,9 +1543,7 @@ mod tests {
 
     use std::collections;
     use std::fmt;
-
-    #[cfg(feature = "logging")]
-    use log;
+    use std::time;
 
     fn assert_js_error<A: fmt::Debug>(
         result: &Result<A>,
@@ -1396,14 +1916,14 @@ mod tests {
         ctx.assert_clean();
     }
 
-    // XXX: this test is super brittle. It must be the only log test for now.
     #[cfg(feature = "logging")]
     #[test]
     fn log_trace() {
-        let _ = env_logger::try_init();
-
-        // We can only verify that this doesn't panic
-        let ctx = Context::new();
+        let records = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = records.clone();
+        let ctx = Context::builder()
+            .with_log_sink(move |record: LogRecord| sink.borrow_mut().push(record))
+            .build();
         ctx.eval_string(
             r"
           var l = new Duktape.Logger('test');
@@ -1418,7 +1938,7 @@ mod tests {
         )
         .unwrap();
 
-        let log_levels = unsafe { LAST_LOG_LEVELS[0..6].to_vec() };
+        let log_levels: Vec<_> = records.borrow().iter().map(|r| Some(r.level)).collect();
 
         assert_eq!(
             log_levels,
@@ -1437,12 +1957,12 @@ mod tests {
     fn load_module() {
         let _ = env_logger::try_init();
 
-        let resolver: Box<ModuleResolver> = Box::new(|a, _| a[..a.len() - 3].to_owned());
+        let resolver: Box<ModuleResolver> = Box::new(|a, _| Ok(a[..a.len() - 3].to_owned()));
         let loader: Box<ModuleLoader> = Box::new(|m| {
             if m == "foo" {
-                Some("exports.num = 3".to_owned())
+                Ok(ModuleSource::Js("exports.num = 3".to_owned()))
             } else {
-                None
+                Err(ModuleError::NotFound)
             }
         });
         let ctx = Context::builder()
@@ -1457,24 +1977,563 @@ mod tests {
         assert_eq!(Value::Number(3.0), value);
     }
 
-    #[cfg_attr(feature = "derive", duktape_fn)]
-    fn test_rust_fn(input: u8) -> String {
-        format!("test {}", input)
-    }
-
-    #[cfg_attr(feature = "derive", duktape_fn)]
-    fn test_rust_complex_fn(input: TestComplexStruct) -> TestComplexStruct {
-        println!("{:?}", input);
-        input
-    }
+    #[test]
+    fn load_module_sources() {
+        let _ = env_logger::try_init();
 
-    #[cfg_attr(feature = "derive", duktape_fn)]
-    fn test_rust_panic_fn() {
-        panic!("panicked successfully")
-    }
+        let bytecode = Context::new()
+            .compile_module("compiled", "exports.num = module.id.length")
+            .unwrap();
+        let loader: Box<ModuleLoader> = Box::new(move |m| match m.as_str() {
+            "config.json" => Ok(ModuleSource::Js(r#"{"num": 1}"#.to_owned())),
+            "data" => Ok(ModuleSource::Json("[2]".to_owned())),
+            "compiled" => Ok(ModuleSource::Bytecode(bytecode.clone())),
+            "native" => Ok(ModuleSource::Native(Box::new(|exports| {
+                exports.set("num", &Value::Number(4.0))
+            }))),
+            "typed.ts" => Ok(ModuleSource::Js("exports.num: number = 5".to_owned())),
+            "broken.json" => Ok(ModuleSource::Js("{".to_owned())),
+            _ => Err(ModuleError::NotFound),
+        });
+        let ctx = Context::builder()
+            .with_module_resolver(Box::new(|id, _| Ok(id)))
+            .with_module_loader(loader)
+            .with_module_transformer(
+                "ts",
+                Box::new(|_, source| Ok(ModuleSource::Js(source.replace(": number", "")))),
+            )
+            .build();
 
-    #[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq)]
-    enum TestEnum {
+        let value = ctx
+            .eval_string(
+                r#"[
+                  require("config.json").num,
+                  require("data")[0],
+                  require("compiled").num,
+                  require("native").num,
+                  require("typed.ts").num
+                ]"#,
+            )
+            .unwrap()
+            .to_value();
+        assert_eq!(
+            Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(8.0),
+                Value::Number(4.0),
+                Value::Number(5.0),
+            ]),
+            value
+        );
+        let value = ctx
+            .eval_string(r#"require.cache["data"].loaded"#)
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Boolean(true), value);
+        assert!(ctx.eval_string(r#"require("broken.json")"#).is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn module_errors_and_defaults() {
+        let _ = env_logger::try_init();
+
+        let loader: Box<ModuleLoader> = Box::new(|id| match id.as_str() {
+            "lib/a" => Ok(ModuleSource::Js("exports.b = require('./b').b".to_owned())),
+            "lib/b" => Ok(ModuleSource::Js("exports.b = 2".to_owned())),
+            "io" => Err(ModuleError::Io {
+                raw: std::io::Error::other("disk on fire"),
+            }),
+            _ => Err(ModuleError::NotFound),
+        });
+        let ctx = Context::builder().with_module_loader(loader).build();
+
+        let value = ctx.eval_string("require('lib/a').b").unwrap().to_value();
+        assert_eq!(Value::Number(2.0), value);
+        let value = ctx.eval_string("require('missing')");
+        assert_js_error(&value, JsErrorKind::Error, "cannot find module 'missing'");
+        let value = ctx.eval_string("require('io')");
+        assert_js_error(
+            &value,
+            JsErrorKind::Error,
+            "cannot load module 'io': disk on fire",
+        );
+        let value = ctx.eval_string("require('../escape')");
+        assert_js_error(&value, JsErrorKind::Error, "cannot find module '../escape'");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn module_resolver_errors() {
+        let resolver: Box<ModuleResolver> = Box::new(|id, _| {
+            if id == "x" {
+                Err(ModuleError::Other {
+                    message: "x is reserved".to_owned(),
+                })
+            } else {
+                Ok(id)
+            }
+        });
+        let ctx = Context::builder().with_module_resolver(resolver).build();
+
+        let value = ctx.eval_string("require('x')");
+        assert_js_error(
+            &value,
+            JsErrorKind::Error,
+            "cannot resolve module 'x': x is reserved",
+        );
+        let value = ctx.eval_string("require('y')");
+        assert_js_error(&value, JsErrorKind::Error, "cannot find module 'y'");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn module_cache() {
+        let _ = env_logger::try_init();
+
+        let loads = std::rc::Rc::new(std::cell::Cell::new(0));
+        let loader_loads = loads.clone();
+        let loader: Box<ModuleLoader> = Box::new(move |_| {
+            loader_loads.set(loader_loads.get() + 1);
+            Ok(ModuleSource::Js(format!(
+                "exports.n = {}",
+                loader_loads.get()
+            )))
+        });
+        let ctx = Context::builder().with_module_loader(loader).build();
+
+        let value = ctx
+            .eval_string("require('a').n + require('a').n")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Number(2.0), value);
+        assert_eq!(vec!["a".to_owned()], ctx.cached_module_ids());
+        let exports = ctx.cached_module("a").unwrap().get("exports").unwrap();
+        assert_eq!(Value::Number(1.0), exports.get("n").unwrap().to_value());
+        assert!(ctx.cached_module("b").is_none());
+
+        assert!(ctx.evict_module("a"));
+        assert!(!ctx.evict_module("a"));
+        let value = ctx.eval_string("require('a').n").unwrap().to_value();
+        assert_eq!(Value::Number(2.0), value);
+        assert_eq!(2, loads.get());
+
+        ctx.eval_string("require('b')").unwrap();
+        ctx.clear_module_cache();
+        assert!(ctx.cached_module_ids().is_empty());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn module_cache_without_modules() {
+        let ctx = Context::new();
+        assert!(ctx.cached_module_ids().is_empty());
+        assert!(!ctx.evict_module("a"));
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn es_modules() {
+        let _ = env_logger::try_init();
+
+        let loader: Box<ModuleLoader> = Box::new(|id| {
+            let source = match id.as_str() {
+                "main" => {
+                    "import answer, {add as plus, counter, bump} from './math';\n\
+                     import * as all from './math';\n\
+                     import legacy from './legacy';\n\
+                     import './side-effect'; // import 'not-a-module';\n\
+                     var text = \"export var nope = 1;\", re = /import x from 'y'/;\n\
+                     bump();\n\
+                     export default [answer, plus(1, 2), counter, all.counter, legacy.name];\n\
+                     export {text, re as pattern};\n\
+                     export * from './math';\n\
+                     export * as math from './math';\n\
+                     export {add as sum, default as total} from './math';"
+                }
+                "math" => {
+                    "export var counter = 0, step = {by: [1, 2]}.by[0];\n\
+                     export function add(a, b) { return a + b; }\n\
+                     export function bump() { counter += step; }\n\
+                     export default function answer() { return 42; }"
+                }
+                "legacy" => "module.exports = {name: 'legacy'};",
+                "side-effect" => "sideEffect = true;",
+                "thrower" => "import a from './legacy';\n\n\nthrow new Error('line 4');",
+                "dynamic" => "var x = 1;\nimport('./legacy');",
+                _ => return Err(ModuleError::NotFound),
+            };
+            Ok(ModuleSource::Js(source.to_owned()))
+        });
+        let ctx = Context::builder()
+            .with_module_loader(loader)
+            .with_es_modules()
+            .build();
+
+        let value = ctx
+            .eval_string("JSON.stringify(require('main').default)")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String("[null,3,0,1,\"legacy\"]".to_owned()), value);
+        let value = ctx.eval_string("require('main').text").unwrap().to_value();
+        assert_eq!(Value::String("export var nope = 1;".to_owned()), value);
+        let value = ctx
+            .eval_string(
+                "var m = require('main'); \
+                 [m.counter, m.math.counter, m.sum(2, 3), m.total(), typeof m.pattern, \
+                 m.add === m.sum, sideEffect].join()",
+            )
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String("1,1,5,42,object,true,true".to_owned()), value);
+        let value = ctx
+            .eval_string("Object.keys(require('main')).sort().join()")
+            .unwrap()
+            .to_value();
+        assert_eq!(
+            Value::String("add,bump,counter,default,math,pattern,step,sum,text,total".to_owned()),
+            value
+        );
+
+        // Low-memory builds drop the line number tables.
+        #[cfg(not(feature = "low-memory"))]
+        {
+            let value = ctx
+                .eval_string("try { require('thrower') } catch (e) { e.lineNumber }")
+                .unwrap()
+                .to_value();
+            assert_eq!(Value::Number(4.0), value);
+        }
+        let value = ctx.eval_string("require('dynamic')");
+        assert_js_error(
+            &value,
+            JsErrorKind::Error,
+            "cannot load module 'dynamic': line 2: dynamic import is not supported",
+        );
+        ctx.assert_clean();
+
+        let source = "// no modules here\nexports.a = 'import x from \"y\"';";
+        assert_eq!(source, transform_es_module(source).unwrap());
+    }
+
+    #[cfg(feature = "debugger")]
+    #[derive(Clone, Default)]
+    struct MemoryDebugTransport {
+        input: std::rc::Rc<std::cell::RefCell<std::collections::VecDeque<u8>>>,
+        output: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+        detached: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    #[cfg(feature = "debugger")]
+    impl DebugTransport for MemoryDebugTransport {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut input = self.input.borrow_mut();
+            let n = buf.len().min(input.len());
+            for (b, i) in buf.iter_mut().zip(input.drain(..n)) {
+                *b = i;
+            }
+            Ok(n)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn peek(&mut self) -> std::io::Result<usize> {
+            Ok(self.input.borrow().len())
+        }
+
+        fn detached(&mut self) {
+            self.detached.set(true);
+        }
+    }
+
+    #[cfg(feature = "debugger")]
+    #[test]
+    fn attach_debugger() {
+        let _ = env_logger::try_init();
+
+        let ctx = Context::new();
+        let transport = MemoryDebugTransport::default();
+        ctx.attach_debugger(transport.clone());
+
+        let handshake = transport.output.borrow().clone();
+        let version = format!("{} ", duk_sys::DUK_DEBUG_PROTOCOL_VERSION);
+        assert!(handshake.starts_with(version.as_bytes()));
+        assert_eq!(Some(&b'\n'), handshake.last());
+
+        // Attaching pauses execution.  A BasicInfo request (REQ, command 0x10, EOM) is answered
+        // with a REP message after the status notification, and a Resume request resumes.
+        transport.output.borrow_mut().clear();
+        transport.input.borrow_mut().extend(&[0x01, 0x90, 0x00]);
+        ctx.debugger_cooperate();
+        assert!(transport.input.borrow().is_empty());
+        let output = transport.output.borrow().clone();
+        assert!(output.windows(2).any(|w| w == [0x00, 0x02]));
+        assert_eq!(Some(&0x00), output.last());
+        transport.input.borrow_mut().extend(&[0x01, 0x93, 0x00]);
+        ctx.debugger_cooperate();
+
+        let value = ctx.eval_string("1 + 2").unwrap().to_value();
+        assert_eq!(Value::Number(3.0), value);
+        assert!(!transport.detached.get());
+        ctx.detach_debugger();
+        assert!(transport.detached.get());
+
+        let transport = MemoryDebugTransport::default();
+        ctx.attach_debugger(transport.clone());
+        drop(ctx);
+        assert!(transport.detached.get());
+    }
+
+    #[cfg(feature = "debugger")]
+    #[test]
+    fn attach_debugger_again() {
+        let _ = env_logger::try_init();
+
+        let ctx = Context::new();
+        let first = MemoryDebugTransport::default();
+        ctx.attach_debugger(first.clone());
+        let second = MemoryDebugTransport::default();
+        ctx.attach_debugger(second.clone());
+
+        assert!(first.detached.get());
+        assert_eq!(1, std::rc::Rc::strong_count(&first.output));
+        assert!(!second.detached.get());
+        assert!(ctx.debugger_attached());
+        let version = format!("{} ", duk_sys::DUK_DEBUG_PROTOCOL_VERSION);
+        assert!(second.output.borrow().starts_with(version.as_bytes()));
+
+        drop(ctx);
+        assert!(second.detached.get());
+        assert_eq!(1, std::rc::Rc::strong_count(&second.output));
+    }
+
+    #[test]
+    fn eval_string_with_filename() {
+        let ctx = Context::new();
+        let value = ctx
+            .eval_string_with_filename("app.js", "(function f() {}).fileName")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String("app.js".to_owned()), value);
+
+        match ctx.eval_string_with_filename("app.js", "\nthrow new Error('x');") {
+            Err(Error::Js { raw }) => {
+                assert_eq!(Some("app.js".to_owned()), raw.file_name);
+                #[cfg(not(feature = "low-memory"))]
+                assert_eq!(Some(2), raw.line_number);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn load_native_module() {
+        let _ = env_logger::try_init();
+
+        let resolver: Box<ModuleResolver> = Box::new(|a, _| Ok(a[..a.len() - 3].to_owned()));
+        let loader: Box<ModuleLoader> = Box::new(|m| {
+            if m == "foo" {
+                Ok(ModuleSource::Js(
+                    "exports.num = require('fs-lite').num + 1".to_owned(),
+                ))
+            } else {
+                Err(ModuleError::NotFound)
+            }
+        });
+        let ctx = Context::builder()
+            .with_module_resolver(resolver)
+            .with_module_loader(loader)
+            .native_module("fs-lite", |exports| {
+                exports.set("num", &Value::Number(3.0))?;
+                exports.set("name", &Value::String("fs-lite".to_owned()))
+            })
+            .build();
+
+        let value = ctx
+            .eval_string(r#"[require("fs-lite").name, require("foo.js").num]"#)
+            .unwrap()
+            .to_value();
+        assert_eq!(
+            Value::Array(vec![
+                Value::String("fs-lite".to_owned()),
+                Value::Number(4.0)
+            ]),
+            value
+        );
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn load_native_module_without_loader() {
+        let _ = env_logger::try_init();
+
+        let ctx = Context::builder()
+            .native_module("fail", |exports| {
+                exports.get("missing")?.call(&[])?;
+                Ok(())
+            })
+            .build();
+
+        let value = ctx.eval_string(r#"require("other")"#);
+        assert_js_error(&value, JsErrorKind::Error, "cannot find module 'other'");
+        let value = ctx.eval_string(r#"require("fail")"#);
+        assert_js_error(
+            &value,
+            JsErrorKind::Error,
+            "cannot load module 'fail': undefined not callable",
+        );
+        ctx.assert_clean();
+    }
+
+    /// A directory of module files in the temp dir, which is removed when dropped, even if the
+    /// test fails.
+    struct ModuleTree(std::path::PathBuf);
+
+    impl std::ops::Deref for ModuleTree {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for ModuleTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn module_tree(name: &str, files: &[(&str, &str)]) -> ModuleTree {
+        let root = std::env::temp_dir().join(format!("duk-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (file, content) in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        ModuleTree(root)
+    }
+
+    #[test]
+    fn fs_module_resolver() {
+        let _ = env_logger::try_init();
+
+        let tree = module_tree(
+            "resolver",
+            &[
+                ("outside.js", "exports.x = 1"),
+                ("root/main.js", ""),
+                ("root/lib/util.js", ""),
+                ("root/lib/data.json", "{}"),
+                ("root/lib/dir/index.js", ""),
+                (
+                    "root/node_modules/pkg/package.json",
+                    r#"{"name": "pkg", "keywords": ["a", {"b": [-1.5e3, true, null]}],
+                        "main": "src\/entr\u0079"}"#,
+                ),
+                ("root/node_modules/pkg/src/entry.js", ""),
+                (
+                    "root/node_modules/bad/package.json",
+                    r#"{"main": "index.js""#,
+                ),
+                ("root/node_modules/bad/index.js", ""),
+                ("root/node_modules/plain/index.json", "{}"),
+            ],
+        );
+        let root = tree.join("root").canonicalize().unwrap();
+        let resolver = FsModuleResolver::new(&root).unwrap();
+        let main = root.join("main.js");
+        let main = main.to_str().unwrap();
+        let util = root.join("lib/util.js");
+        let util = util.to_str().unwrap();
+
+        assert_eq!(
+            util,
+            resolver
+                .resolve("./lib/util", "")
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(
+            util,
+            resolver
+                .resolve("./lib/util.js", main)
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(
+            root.join("lib/data.json"),
+            resolver.resolve("./data", util).unwrap()
+        );
+        assert_eq!(
+            root.join("lib/dir/index.js"),
+            resolver.resolve("./dir", util).unwrap()
+        );
+        assert_eq!(
+            root.join("node_modules/pkg/src/entry.js"),
+            resolver.resolve("pkg", util).unwrap()
+        );
+        assert_eq!(
+            root.join("node_modules/plain/index.json"),
+            resolver.resolve("plain", util).unwrap()
+        );
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            resolver.resolve("bad", util).unwrap_err().kind()
+        );
+        assert_eq!(
+            std::io::ErrorKind::NotFound,
+            resolver.resolve("./missing", main).unwrap_err().kind()
+        );
+        assert_eq!(
+            std::io::ErrorKind::PermissionDenied,
+            resolver.resolve("../outside", main).unwrap_err().kind()
+        );
+
+        let loader: Box<ModuleLoader> =
+            Box::new(|id| Ok(ModuleSource::Js(std::fs::read_to_string(id)?)));
+        let ctx = Context::builder()
+            .with_module_resolver(
+                FsModuleResolver::new(tree.join("root"))
+                    .unwrap()
+                    .with_root(&*tree)
+                    .unwrap()
+                    .into_module_resolver(),
+            )
+            .with_module_loader(loader)
+            .build();
+        let value = ctx
+            .eval_string(r#"require("../outside").x"#)
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Number(1.0), value);
+    }
+
+    #[cfg_attr(feature = "derive", duktape_fn)]
+    fn test_rust_fn(input: u8) -> String {
+        format!("test {}", input)
+    }
+
+    #[cfg_attr(feature = "derive", duktape_fn)]
+    fn test_rust_complex_fn(input: TestComplexStruct) -> TestComplexStruct {
+        println!("{:?}", input);
+        input
+    }
+
+    #[cfg_attr(feature = "derive", duktape_fn)]
+    fn test_rust_panic_fn() {
+        panic!("panicked successfully")
+    }
+
+    #[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    enum TestEnum {
         A,
     }
 
@@ -1543,4 +2602,1272 @@ mod tests {
 
         assert!(ctx.eval_string(r"test_rust_panic_fn()").is_err());
     }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn native_module_fn() {
+        let ctx = Context::builder()
+            .native_module("rust", |exports| {
+                exports.add_fn::<test_rust_fn::DukFnImpl>()
+            })
+            .build();
+
+        let val = ctx
+            .eval_string(r#"require("rust").test_rust_fn(3)"#)
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String("test 3".to_owned()), val);
+    }
+
+    #[test]
+    fn event_loop_virtual_clock() {
+        let _ = env_logger::try_init();
+
+        let event_loop = EventLoop::with_virtual_clock(Context::new());
+        let ctx = event_loop.context();
+        ctx.eval_string(
+            "var log = [];\n\
+             function record(name) { log.push(name); }\n\
+             setTimeout(record, 30, 'c');\n\
+             setTimeout(record, 10, 'a');\n\
+             setTimeout(record, 10, 'b');\n\
+             clearTimeout(setTimeout(record, 5, 'cleared'));\n\
+             setTimeout(function() { setTimeout(record, 0, 'nested'); }, 20);\n\
+             setTimeout(function() { throw new RangeError('boom'); }, 15);\n\
+             setTimeout(function() { throw new TypeError('bang'); }, 25);",
+        )
+        .unwrap();
+        ctx.assert_clean();
+        assert_eq!(6, event_loop.pending_timers());
+
+        let mut errors = event_loop.run_for(time::Duration::from_millis(20));
+        assert_eq!(time::Duration::from_millis(20), event_loop.now());
+        assert_eq!(1, errors.len());
+        assert_eq!(6, errors[0].id);
+        assert_js_error(
+            &Err::<(), _>(errors.remove(0).error),
+            JsErrorKind::Range,
+            "boom",
+        );
+        assert_eq!(
+            Value::String("a,b,nested".to_owned()),
+            ctx.eval_string("log.join()").unwrap().to_value()
+        );
+
+        let mut errors = event_loop.run_until_idle();
+        assert_eq!(1, errors.len());
+        assert_eq!(7, errors[0].id);
+        assert_js_error(
+            &Err::<(), _>(errors.remove(0).error),
+            JsErrorKind::Type,
+            "bang",
+        );
+        assert_eq!(time::Duration::from_millis(30), event_loop.now());
+        assert_eq!(0, event_loop.pending_timers());
+        assert_eq!(
+            Value::String("a,b,nested,c".to_owned()),
+            ctx.eval_string("log.join()").unwrap().to_value()
+        );
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn event_loop_intervals() {
+        let _ = env_logger::try_init();
+
+        let event_loop = EventLoop::with_virtual_clock(Context::new());
+        let ctx = event_loop.context();
+        ctx.eval_string(
+            "var count = 0;\n\
+             var id = setInterval(function(step) {\n\
+               count += step;\n\
+               if (count >= 3) clearInterval(id);\n\
+             }, 10, 1);",
+        )
+        .unwrap();
+
+        assert!(event_loop
+            .run_for(time::Duration::from_millis(25))
+            .is_empty());
+        assert_eq!(
+            Value::Number(2.0),
+            ctx.eval_string("count").unwrap().to_value()
+        );
+        assert_eq!(1, event_loop.pending_timers());
+
+        assert!(event_loop.run_until_idle().is_empty());
+        assert_eq!(time::Duration::from_millis(30), event_loop.now());
+        assert_eq!(
+            Value::Number(3.0),
+            ctx.eval_string("count").unwrap().to_value()
+        );
+        assert_js_error(
+            &ctx.eval_string("setTimeout('count++', 10)"),
+            JsErrorKind::Type,
+            "timer callback is not callable",
+        );
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn event_loop_real_clock() {
+        let _ = env_logger::try_init();
+
+        let event_loop = EventLoop::new(Context::new());
+        let ctx = event_loop.context();
+        ctx.eval_string("var done = false; setTimeout(function() { done = true; }, 20);")
+            .unwrap();
+
+        assert!(event_loop
+            .run_for(time::Duration::from_millis(1))
+            .is_empty());
+        assert_eq!(
+            Value::Boolean(false),
+            ctx.eval_string("done").unwrap().to_value()
+        );
+        assert!(event_loop.run_until_idle().is_empty());
+        assert!(event_loop.now() >= time::Duration::from_millis(20));
+        assert_eq!(
+            Value::Boolean(true),
+            ctx.eval_string("done").unwrap().to_value()
+        );
+    }
+
+    #[test]
+    fn promises() {
+        let _ = env_logger::try_init();
+
+        let ctx = Context::builder().with_promises().build();
+        let order = ctx
+            .eval_string(
+                "var order = [];\n\
+                 Promise.resolve().then(function() { order.push('then'); });\n\
+                 order.push('sync');\n\
+                 order",
+            )
+            .unwrap();
+        assert_eq!(
+            Value::String("sync".to_owned()),
+            order.call_method("join", &[]).unwrap().to_value()
+        );
+        ctx.run_microtasks().unwrap();
+        assert_eq!(
+            Value::String("sync,then".to_owned()),
+            order.call_method("join", &[]).unwrap().to_value()
+        );
+
+        let answer = ctx
+            .eval_string(
+                "new Promise(function(resolve) { resolve(20); })\n\
+                 .then(function(x) { return Promise.resolve(x + 1); })\n\
+                 .then(function(x) { return x * 2; })",
+            )
+            .unwrap();
+        assert_eq!(
+            Value::Number(42.0),
+            answer.await_promise().unwrap().to_value()
+        );
+        assert_eq!(
+            Value::String("plain".to_owned()),
+            ctx.resolve_promise(&Value::String("plain".to_owned()))
+                .unwrap()
+                .to_value()
+        );
+
+        let rejected = ctx
+            .eval_string("Promise.resolve().then(function() { throw new RangeError('nope'); })")
+            .unwrap();
+        assert_js_error(&rejected.await_promise(), JsErrorKind::Range, "nope");
+        let rejected = ctx.eval_string("Promise.reject(undefined)").unwrap();
+        assert_js_error(&rejected.await_promise(), JsErrorKind::Generic, "undefined");
+
+        let pending = ctx.eval_string("new Promise(function() {})").unwrap();
+        match pending.await_promise() {
+            Err(Error::Promise { ref message }) => assert_eq!("promise is still pending", message),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn promises_disabled() {
+        let plain = Context::new();
+        assert_eq!(
+            Value::String("undefined".to_owned()),
+            plain.eval_string("typeof Promise").unwrap().to_value()
+        );
+        assert!(plain.resolve_promise(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn event_loop_promises() {
+        let _ = env_logger::try_init();
+
+        let event_loop = EventLoop::with_virtual_clock(Context::builder().with_promises().build());
+        let ctx = event_loop.context();
+        let done = ctx
+            .eval_string(
+                "var log = [];\n\
+                 function sleep(ms) {\n\
+                   return new Promise(function(resolve) { setTimeout(resolve, ms); });\n\
+                 }\n\
+                 Promise.resolve().then(function() { log.push('start'); });\n\
+                 setTimeout(function() {\n\
+                   Promise.resolve().then(function() { log.push('microtask'); });\n\
+                 }, 5);\n\
+                 setTimeout(function() { log.push('timer'); }, 5);\n\
+                 sleep(10).then(function() { log.push('slept'); return log.join(); })",
+            )
+            .unwrap();
+        match done.await_promise() {
+            Err(Error::Promise { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        assert!(event_loop.run_until_idle().is_empty());
+        assert_eq!(
+            Value::String("start,microtask,timer,slept".to_owned()),
+            done.await_promise().unwrap().to_value()
+        );
+    }
+
+    /// A future that completes with the value that is sent through the returned cell, and records
+    /// whether it was dropped.
+    struct Manual {
+        value: rc::Rc<cell::RefCell<Option<result::Result<Value, String>>>>,
+        dropped: rc::Rc<cell::Cell<bool>>,
+    }
+
+    impl std::future::Future for Manual {
+        type Output = result::Result<Value, String>;
+
+        fn poll(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Self::Output> {
+            match self.value.borrow_mut().take() {
+                Some(value) => std::task::Poll::Ready(value),
+                None => std::task::Poll::Pending,
+            }
+        }
+    }
+
+    impl Drop for Manual {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    #[test]
+    fn async_fns() {
+        let _ = env_logger::try_init();
+
+        let ctx = Context::builder().with_promises().build();
+        let waker = std::task::Waker::noop();
+        let mut cx = std::task::Context::from_waker(waker);
+
+        let value = rc::Rc::new(cell::RefCell::new(None));
+        let dropped = rc::Rc::new(cell::Cell::new(false));
+        let (value_clone, dropped_clone) = (value.clone(), dropped.clone());
+        ctx.add_async_global_fn("fetch", move |args| {
+            assert_eq!(vec![Value::String("key".to_owned())], args);
+            Manual {
+                value: value_clone.clone(),
+                dropped: dropped_clone.clone(),
+            }
+        })
+        .unwrap();
+        ctx.add_async_global_fn("add", |args| async move {
+            match (args.first(), args.get(1)) {
+                (Some(&Value::Number(a)), Some(&Value::Number(b))) => Ok(Value::Number(a + b)),
+                _ => Err("expected two numbers".to_owned()),
+            }
+        })
+        .unwrap();
+
+        let result = ctx
+            .eval_string(
+                "fetch('key').then(function(v) { return add(v.length, 1); })\n\
+                 .then(function(n) { return add(n, 'x'); })\n\
+                 .catch(function(e) { return e.message; })",
+            )
+            .unwrap();
+        assert_eq!(1, ctx.pending_tasks());
+        assert!(ctx.poll_tasks(&mut cx).is_pending());
+        assert!(!dropped.get());
+
+        *value.borrow_mut() = Some(Ok(Value::String("abc".to_owned())));
+        match ctx.poll_tasks(&mut cx) {
+            std::task::Poll::Ready(Ok(())) => (),
+            other => panic!("Unexpected poll result: {:?}", other),
+        }
+        assert!(dropped.get());
+        assert_eq!(0, ctx.pending_tasks());
+        assert_eq!(
+            Value::String("expected two numbers".to_owned()),
+            result.await_promise().unwrap().to_value()
+        );
+
+        let rejected = ctx.eval_string("fetch('key')").unwrap();
+        *value.borrow_mut() = Some(Err("not found".to_owned()));
+        assert!(ctx.poll_tasks(&mut cx).is_ready());
+        assert_js_error(&rejected.await_promise(), JsErrorKind::Error, "not found");
+        ctx.assert_clean();
+
+        // Pending calls are cancelled with the context.
+        dropped.set(false);
+        let pending = ctx.eval_string("fetch('key')").unwrap();
+        assert!(ctx.poll_tasks(&mut cx).is_pending());
+        drop(pending);
+        drop(rejected);
+        drop(result);
+        assert!(!dropped.get());
+        drop(ctx);
+        assert!(dropped.get());
+
+        let plain = Context::new();
+        assert!(plain
+            .add_async_global_fn("nope", |_| async { Ok(Value::Null) })
+            .is_err());
+    }
+
+    #[test]
+    fn async_fn_script_values() {
+        let _ = env_logger::try_init();
+
+        let ctx = Context::builder().with_promises().build();
+        let waker = std::task::Waker::noop();
+        let mut cx = std::task::Context::from_waker(waker);
+
+        let args = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let args_clone = args.clone();
+        ctx.add_async_global_fn("take", move |values| {
+            *args_clone.borrow_mut() = values;
+            async { Ok(Value::Null) }
+        })
+        .unwrap();
+
+        let result = ctx
+            .eval_string(
+                "take([1,,2], '\\ud800', {get x() { throw new Error('getter'); }, y: 1})\n\
+                 .then(function() { return 'resolved'; })",
+            )
+            .unwrap();
+        assert!(ctx.poll_tasks(&mut cx).is_ready());
+        assert_eq!(
+            Value::String("resolved".to_owned()),
+            result.await_promise().unwrap().to_value()
+        );
+        let mut object = collections::BTreeMap::new();
+        object.insert("x".to_owned(), Value::Foreign("inaccessible"));
+        object.insert("y".to_owned(), Value::Number(1.0));
+        assert_eq!(
+            vec![
+                Value::Array(vec![
+                    Value::Number(1.0),
+                    Value::Undefined,
+                    Value::Number(2.0)
+                ]),
+                Value::String("\u{fffd}\u{fffd}\u{fffd}".to_owned()),
+                Value::Object(object),
+            ],
+            *args.borrow()
+        );
+        ctx.assert_clean();
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn console() {
+        let records = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = records.clone();
+        let ctx = Context::builder()
+            .with_console_target("console-test")
+            .with_log_sink(move |record: LogRecord| sink.borrow_mut().push(record))
+            .build();
+        ctx.eval_string(
+            "console.log('hello', 1, {answer: 42, list: [1, 'x']}, undefined);\n\
+             console.group('outer');\n\
+             console.warn('nested');\n\
+             console.group();\n\
+             console.error(new Error('boom'));\n\
+             console.groupEnd();\n\
+             console.groupEnd();\n\
+             console.groupEnd();\n\
+             console.assert(true, 'never');\n\
+             console.assert(1 > 2, 'math', 'is hard');\n\
+             console.count(); console.count(); console.count('x'); console.countReset();\n\
+             console.count();\n\
+             console.timeEnd('missing');\n\
+             console.table([{a: 1, b: 'x'}, {a: 2}]);\n\
+             console.table('not tabular');\n\
+             console.debug('debug');\n\
+             console.trace('here');",
+        )
+        .unwrap();
+        ctx.eval_string("console.time('t'); console.timeEnd('t');")
+            .unwrap();
+        ctx.assert_clean();
+
+        let messages: Vec<(log::Level, String)> = records
+            .borrow()
+            .iter()
+            .map(|record| (record.level, record.message.clone()))
+            .collect();
+        assert!(records
+            .borrow()
+            .iter()
+            .all(|record| record.source == LogSource::Console && record.logger == "console-test"));
+        assert_eq!(
+            vec![
+                (
+                    log::Level::Info,
+                    "hello 1 {answer:42,list:[1,\"x\"]} undefined".to_owned()
+                ),
+                (log::Level::Info, "outer".to_owned()),
+                (log::Level::Warn, "  nested".to_owned()),
+                (log::Level::Error, "    Error: boom".to_owned()),
+                (
+                    log::Level::Error,
+                    "Assertion failed: math is hard".to_owned()
+                ),
+                (log::Level::Info, "default: 1".to_owned()),
+                (log::Level::Info, "default: 2".to_owned()),
+                (log::Level::Info, "x: 1".to_owned()),
+                (log::Level::Info, "default: 1".to_owned()),
+                (
+                    log::Level::Warn,
+                    "Timer 'missing' does not exist".to_owned()
+                ),
+                (
+                    log::Level::Info,
+                    "(index) | a | b\n--------+---+--\n0       | 1 | x\n1       | 2 |".to_owned()
+                ),
+                (log::Level::Info, "not tabular".to_owned()),
+                (log::Level::Debug, "debug".to_owned()),
+            ],
+            messages[..13].to_vec()
+        );
+        assert_eq!(log::Level::Trace, messages[13].0);
+        assert!(messages[13].1.starts_with("Trace: here\n"));
+        assert!(messages[14].1.starts_with("t: "));
+        assert!(messages[14].1.ends_with("ms"));
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn console_script_values() {
+        let records = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = records.clone();
+        let ctx = Context::builder()
+            .with_log_sink(move |record: LogRecord| sink.borrow_mut().push(record))
+            .build();
+        ctx.eval_string(
+            "console.log('\\ud800');\n\
+             console.table([1,,2]);\n\
+             var o = {get boom() { throw new Error('boom'); }};\n\
+             o.self = o;\n\
+             console.table([o]);",
+        )
+        .unwrap();
+        ctx.assert_clean();
+
+        let records = records.borrow();
+        assert_eq!("\u{FFFD}\u{FFFD}\u{FFFD}", records[0].message);
+        assert_eq!(
+            vec![Value::String("\u{FFFD}\u{FFFD}\u{FFFD}".to_owned())],
+            records[0].args
+        );
+        assert_eq!(
+            "(index) | Values\n--------+----------\n0       | 1\n1       | undefined\n2       | 2",
+            records[1].message
+        );
+        assert_eq!(
+            Value::Array(vec![
+                Value::Number(1.0),
+                Value::Undefined,
+                Value::Number(2.0)
+            ]),
+            records[1].args[0]
+        );
+        let mut object = collections::BTreeMap::new();
+        object.insert("boom".to_owned(), Value::Foreign("inaccessible"));
+        object.insert("self".to_owned(), Value::Foreign("cycle"));
+        assert_eq!(
+            Value::Array(vec![Value::Object(object)]),
+            records[2].args[0]
+        );
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn log_sink() {
+        let records = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = records.clone();
+        let ctx = Context::builder()
+            .with_log_sink(move |record: LogRecord| sink.borrow_mut().push(record))
+            .build();
+        ctx.eval_string_with_filename(
+            "app.js",
+            "var logger = new Duktape.Logger('app');\n\
+             logger.l = 0;\n\
+             logger.debug('user', {id: 7});\n\
+             console.info('done', 2);",
+        )
+        .unwrap();
+        ctx.assert_clean();
+
+        let mut id = collections::BTreeMap::new();
+        id.insert("id".to_owned(), Value::Number(7.0));
+        // Low-memory builds drop the line number tables.
+        let line_number = |line| Some(line).filter(|_| !cfg!(feature = "low-memory"));
+        assert_eq!(
+            vec![
+                LogRecord {
+                    level: log::Level::Debug,
+                    source: LogSource::Logger,
+                    logger: "app".to_owned(),
+                    message: "user [object Object]".to_owned(),
+                    args: vec![Value::String("user".to_owned()), Value::Object(id)],
+                    file_name: Some("app.js".to_owned()),
+                    line_number: line_number(3),
+                },
+                LogRecord {
+                    level: log::Level::Info,
+                    source: LogSource::Console,
+                    logger: DEFAULT_CONSOLE_TARGET.to_owned(),
+                    message: "done 2".to_owned(),
+                    args: vec![Value::String("done".to_owned()), Value::Number(2.0)],
+                    file_name: Some("app.js".to_owned()),
+                    line_number: line_number(4),
+                },
+            ],
+            *records.borrow()
+        );
+        assert_eq!("app: user [object Object]", records.borrow()[0].to_string());
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn log_script_values() {
+        // The small default pool of compressed pointers doesn't fit the deeply nested arrays.
+        let depth = if cfg!(feature = "alloc-pool-ptrcomp") {
+            100
+        } else {
+            100_000
+        };
+        let script = format!(
+            "var deep = [];\n\
+             for (var i = 0; i < {}; i++) {{ deep = [deep]; }}\n\
+             var cyclic = [1,,2];\n\
+             cyclic.push(cyclic);\n\
+             new Duktape.Logger('\\ud800').warn(cyclic, deep);\n\
+             console.log(cyclic, deep);",
+            depth
+        );
+
+        // Without a sink, the arguments only go into the message.
+        let ctx = Context::new();
+        ctx.set_log_level(log::LevelFilter::Off);
+        ctx.eval_string(&script).unwrap();
+        ctx.assert_clean();
+        drop(ctx);
+
+        let records = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = records.clone();
+        let ctx = Context::builder()
+            .with_log_sink(move |record: LogRecord| sink.borrow_mut().push(record))
+            .build();
+        ctx.eval_string(&script).unwrap();
+        ctx.assert_clean();
+
+        let records = records.borrow();
+        assert_eq!(2, records.len());
+        assert_eq!("\u{FFFD}\u{FFFD}\u{FFFD}", records[0].logger);
+        for record in records.iter() {
+            assert_eq!(
+                Value::Array(vec![
+                    Value::Number(1.0),
+                    Value::Undefined,
+                    Value::Number(2.0),
+                    Value::Foreign("cycle"),
+                ]),
+                record.args[0]
+            );
+            let mut deep = &record.args[1];
+            let mut depth = 0;
+            while let Value::Array(ref array) = *deep {
+                deep = &array[0];
+                depth += 1;
+            }
+            assert_eq!(Value::Foreign("nested too deep"), *deep);
+            assert_eq!(16, depth);
+        }
+    }
+
+    #[cfg(feature = "exec-timeout")]
+    #[test]
+    fn exec_deadline() {
+        let ctx = Context::new();
+        ctx.set_exec_deadline(Some(time::Instant::now() + time::Duration::from_millis(20)));
+        let started = time::Instant::now();
+        let result = ctx.eval_string("for (;;) {}");
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+        match result {
+            Err(Error::Js { raw }) => assert_eq!(JsErrorKind::Range, raw.kind),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        ctx.set_exec_deadline(None);
+        assert_eq!(
+            Value::Number(3.0),
+            ctx.eval_string("1 + 2").unwrap().to_value()
+        );
+        ctx.assert_clean();
+
+        // Heaps of other users of duk-sys aren't mistaken for contexts.
+        let mut other = 0u64;
+        assert!(!exec_timeout_check(
+            &mut other as *mut u64 as *mut os::raw::c_void
+        ));
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn log_levels() {
+        let records = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = records.clone();
+        let ctx = Context::builder()
+            .with_log_sink(move |record: LogRecord| sink.borrow_mut().push(record))
+            .build();
+        ctx.eval_string(
+            "var formatted = 0;\n\
+             Duktape.Logger.prototype.fmt = function(v) { formatted++; return 'obj'; };\n\
+             var app = new Duktape.Logger('app');\n\
+             var net = new Duktape.Logger('net');\n\
+             function logAll() {\n\
+                 [app, net].forEach(function(l) {\n\
+                     l.trace({}); l.debug({}); l.info({}); l.warn({}); l.error({}); l.fatal({});\n\
+                 });\n\
+             }",
+        )
+        .unwrap();
+        let run = || {
+            records.borrow_mut().clear();
+            let formatted = ctx
+                .eval_string("formatted = 0; logAll(); formatted")
+                .unwrap()
+                .to_value();
+            let logged: Vec<(String, log::Level)> = records
+                .borrow()
+                .iter()
+                .map(|r| (r.logger.clone(), r.level))
+                .collect();
+            (formatted, logged)
+        };
+
+        ctx.set_log_level(log::LevelFilter::Error);
+        ctx.set_logger_level("app", log::LevelFilter::Debug);
+        let (formatted, logged) = run();
+        assert_eq!(Value::Number(7.0), formatted);
+        assert_eq!(
+            vec![
+                ("app".to_owned(), log::Level::Debug),
+                ("app".to_owned(), log::Level::Info),
+                ("app".to_owned(), log::Level::Warn),
+                ("app".to_owned(), log::Level::Error),
+                ("app".to_owned(), log::Level::Error),
+                ("net".to_owned(), log::Level::Error),
+                ("net".to_owned(), log::Level::Error),
+            ],
+            logged
+        );
+
+        ctx.clear_logger_levels();
+        ctx.set_log_directives("off,duk:net=warn, app , bogus=loud");
+        let (formatted, logged) = run();
+        assert_eq!(Value::Number(9.0), formatted);
+        assert_eq!(6, logged.iter().filter(|(name, _)| name == "app").count());
+        assert_eq!(3, logged.iter().filter(|(name, _)| name == "net").count());
+
+        // Scripts can still set the level of loggers without an override.
+        ctx.eval_string("net.l = 5;").unwrap();
+        ctx.clear_logger_levels();
+        let (formatted, logged) = run();
+        assert_eq!(Value::Number(1.0), formatted);
+        assert_eq!(vec![("net".to_owned(), log::Level::Error)], logged);
+        ctx.assert_clean();
+    }
+
+    /// Mirrors `extras/alloc-pool/test.c`: the heap is created in the pools, and a script that
+    /// prints its arguments runs in them.
+    #[cfg(feature = "alloc-pool")]
+    #[test]
+    fn pool_allocator() {
+        let config = PoolAllocatorConfig::default();
+        let ctx = Context::builder()
+            .with_pool_allocator(config.clone())
+            .build();
+
+        let stats = ctx.pool_stats().unwrap();
+        assert_eq!(12, stats.pools.len());
+        assert!(stats.used_bytes > 0);
+        assert!(stats.used_bytes + stats.free_bytes <= config.size());
+        assert!(stats.waste_bytes < stats.used_bytes);
+        let used_bytes: usize = stats
+            .pools
+            .iter()
+            .map(|pool| pool.block_size * pool.used_count)
+            .sum();
+        assert_eq!(stats.used_bytes, used_bytes);
+
+        let printed = ctx
+            .eval_string(
+                "function print() { return Array.prototype.join.call(arguments, ' '); }\n\
+                 var printed = print('foo', 'bar', 1, 2, 3);\n\
+                 printed",
+            )
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String("foo bar 1 2 3".to_owned()), printed);
+        assert!(ctx.pool_stats().unwrap().used_bytes > stats.used_bytes);
+        ctx.assert_clean();
+    }
+
+    #[cfg(feature = "alloc-pool")]
+    #[test]
+    fn pool_allocator_exhausted() {
+        let ctx = Context::builder()
+            .with_pool_allocator(PoolAllocatorConfig::default())
+            .build();
+        let before = ctx.pool_stats().unwrap();
+        ctx.eval_string(
+            "var chunks = [];\n\
+             function fill() { for (var i = 0; ; i++) chunks.push(new Array(100).join('x') + i); }\n\
+             function release() { var n = chunks.length; chunks = []; return n; }",
+        )
+        .unwrap();
+        match ctx.call_global("fill", &[]) {
+            Err(Error::Js { raw }) => assert_eq!("alloc failed", raw.message),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let exhausted = ctx.pool_stats().unwrap();
+        assert!(exhausted.used_bytes > before.used_bytes);
+
+        let released = ctx.call_global("release", &[]).unwrap().to_value();
+        assert!(matches!(released, Value::Number(n) if n > 0.0));
+        assert!(ctx.pool_stats().unwrap().used_bytes < exhausted.used_bytes);
+        assert_eq!(
+            Value::Number(3.0),
+            ctx.eval_string("1 + 2").unwrap().to_value()
+        );
+        ctx.assert_clean();
+    }
+
+    #[cfg(feature = "alloc-pool")]
+    #[test]
+    #[should_panic(expected = "the pools don't fit")]
+    fn pool_allocator_too_small() {
+        let config = PoolAllocatorConfig::new(1024).with_pool(4096, 1);
+        Context::builder().with_pool_allocator(config).build();
+    }
+
+    #[test]
+    fn gc() {
+        let ctx = Context::new();
+        ctx.eval_string(
+            "var finalized = 0;\n\
+             (function() {\n\
+                 var a = {}, b = {a: a};\n\
+                 a.b = b;\n\
+                 Duktape.fin(a, function() { finalized++; });\n\
+             })();",
+        )
+        .unwrap();
+        assert_eq!(
+            Value::Number(0.0),
+            ctx.eval_string("finalized").unwrap().to_value()
+        );
+
+        ctx.gc();
+        ctx.gc_compact();
+        assert_eq!(
+            Value::Number(1.0),
+            ctx.eval_string("finalized").unwrap().to_value()
+        );
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn heap_info() {
+        let ctx = Context::new();
+        let object = ctx
+            .eval_string(
+                "var o = {};\n\
+                 for (var i = 0; i < 100; i++) o['k' + i] = i;\n\
+                 for (var i = 0; i < 90; i++) delete o['k' + i];\n\
+                 var copy = o;\n\
+                 o",
+            )
+            .unwrap();
+        let before = object.heap_info().unwrap();
+        assert_eq!(Some(100), before.entry_next);
+        assert!(before.refcount.unwrap() >= 2);
+        assert!(before.allocated_bytes() > before.header_bytes);
+
+        object.compact();
+        let after = object.heap_info().unwrap();
+        assert_eq!(Some(10), after.entry_next);
+        assert!(after.property_bytes < before.property_bytes);
+
+        // The small default pool of compressed pointers doesn't fit long strings.
+        let len = if cfg!(feature = "alloc-pool-ptrcomp") {
+            100
+        } else {
+            1000
+        };
+        let string = ctx
+            .eval_string(&format!("new Array({}).join('x')", len + 1))
+            .unwrap();
+        assert!(string.heap_info().unwrap().header_bytes > len);
+        assert_eq!(None, string.heap_info().unwrap().class);
+        assert_eq!(None, ctx.eval_string("true").unwrap().heap_info());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn reference_handles_are_reused() {
+        let ctx = Context::new();
+        let refs = (0..10)
+            .map(|i| Value::Number(f64::from(i)).to_reference(&ctx))
+            .collect::<Vec<_>>();
+        let mut handles = refs.iter().map(|r| r.handle).collect::<Vec<_>>();
+        let (odd, even): (Vec<_>, Vec<_>) = refs.into_iter().partition(|r| r.handle % 2 == 1);
+        drop(odd);
+
+        let reused = (10..15)
+            .map(|i| Value::Number(f64::from(i)).to_reference(&ctx))
+            .collect::<Vec<_>>();
+        for (i, r) in even.iter().enumerate() {
+            assert_eq!(Value::Number(2.0 * i as f64), r.to_value());
+        }
+        for (i, r) in reused.iter().enumerate() {
+            assert_eq!(Value::Number(10.0 + i as f64), r.to_value());
+        }
+        let mut now = even
+            .iter()
+            .chain(&reused)
+            .map(|r| r.handle)
+            .collect::<Vec<_>>();
+        now.sort_unstable();
+        handles.sort_unstable();
+        assert_eq!(handles, now);
+        drop(even);
+        drop(reused);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn scope_locals() {
+        let ctx = Context::new();
+        let reference = ctx.scope(|s| {
+            let object = s.eval_string("({a: {b: 'c'}, add: function(x, y) { return x + y; }})")?;
+            assert_eq!(
+                Value::String("c".to_owned()),
+                object.get("a")?.get("b")?.to_value()
+            );
+            let one = Value::Number(1.0);
+            let sum = object.call_method("add", &[&one, &Value::Number(2.0)])?;
+            assert_eq!(Value::Number(3.0), sum.to_value());
+            object.set("sum", &sum)?;
+            let add = object.get("add")?;
+            assert_eq!(Value::Number(6.0), add.call(&[&sum, &sum])?.to_value());
+
+            assert_js_error(
+                &sum.set("x", &sum),
+                JsErrorKind::Type,
+                "value is not an object",
+            );
+            assert_js_error(
+                &s.push(&Value::Null).get("x"),
+                JsErrorKind::Type,
+                "value is not object coercible",
+            );
+            assert_js_error(
+                &s.eval_string("throw new RangeError('range')"),
+                JsErrorKind::Range,
+                "range",
+            );
+            Ok::<_, Error>(object.persist(&ctx))
+        });
+        ctx.assert_clean();
+        let reference = reference.unwrap();
+        assert_eq!(Value::Number(3.0), reference.get("sum").unwrap().to_value());
+    }
+
+    #[test]
+    fn scope_nested() {
+        let ctx = Context::new();
+        ctx.scope(|s| {
+            let array = s.eval_string("var array = []; array").unwrap();
+            let append = s.eval_string("(function(x) { array.push(x); })").unwrap();
+            // The small default pool of compressed pointers doesn't fit a large array.
+            let count = if cfg!(feature = "alloc-pool-ptrcomp") {
+                100
+            } else {
+                1000
+            };
+            for i in 0..count {
+                ctx.scope(|s| {
+                    // Locals of outer scopes can be passed as arguments in inner scopes.
+                    let value = s.push(&Value::Number(f64::from(i)));
+                    s.push(&append).call(&[&value]).unwrap();
+                    s.push(&array).call_method("push", &[&value]).unwrap();
+                });
+            }
+            assert_eq!(2, unsafe { duk_sys::duk_get_top(ctx.raw) });
+            assert_eq!(
+                Value::Number(f64::from(2 * count)),
+                array.get("length").unwrap().to_value()
+            );
+        });
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn scope_script_values() {
+        let ctx = Context::new();
+        ctx.scope(|s| {
+            assert_eq!(
+                Value::Array(vec![
+                    Value::Number(1.0),
+                    Value::Undefined,
+                    Value::Number(2.0)
+                ]),
+                s.eval_string("[1,,2]").unwrap().to_value()
+            );
+            assert_eq!(
+                Value::String("\u{fffd}\u{fffd}\u{fffd}".to_owned()),
+                s.eval_string("'\\ud800'").unwrap().to_value()
+            );
+        });
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn scope_set_in_inner_scope() {
+        let ctx = Context::new();
+        ctx.scope(|s| {
+            let object = s.eval_string("({})").unwrap();
+            for n in 0..300 {
+                ctx.scope(|s| {
+                    for i in 0..n {
+                        s.push(&Value::Number(f64::from(i)));
+                    }
+                    // Setting properties of outer locals doesn't create locals.
+                    object.set("n", &Value::Number(f64::from(n))).unwrap();
+                });
+            }
+            assert_eq!(Value::Number(299.0), object.get("n").unwrap().to_value());
+        });
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[should_panic(expected = "locals can only be created in the innermost scope")]
+    fn scope_outer_local_created_in_inner_scope() {
+        let ctx = Context::new();
+        ctx.scope(|outer| {
+            ctx.scope(|_| {
+                outer.global_object();
+            })
+        });
+    }
+
+    #[test]
+    fn stack_push_get_require() {
+        let ctx = Context::new();
+        let mut stack = ctx.stack();
+        stack.push_undefined();
+        stack.push_null();
+        stack.push_boolean(true);
+        stack.push_int(-3);
+        stack.push_uint(7);
+        stack.push_string("foo");
+        stack.push_bytes(b"bar");
+        stack.push_pointer(ptr::null_mut());
+        stack.push_object();
+        stack.push_array();
+        stack.push(&Value::Number(1.5));
+        stack.push_global_object();
+        assert_eq!(12, stack.len());
+
+        let types = (0..12)
+            .map(|i| stack.type_of(i).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Type::Undefined,
+                Type::Null,
+                Type::Boolean,
+                Type::Number,
+                Type::Number,
+                Type::String,
+                Type::Buffer,
+                Type::Pointer,
+                Type::Object,
+                Type::Object,
+                Type::Number,
+                Type::Object,
+            ],
+            types
+        );
+        stack.require_undefined(0).unwrap();
+        stack.require_null(1).unwrap();
+        assert!(stack.is_undefined(0) && stack.is_null(-11));
+        assert!(stack.require_boolean(2).unwrap());
+        assert_eq!(-3.0, stack.require_number(3).unwrap());
+        assert_eq!(Some(7.0), stack.get_number(4));
+        assert_eq!("foo", stack.require_string(5).unwrap());
+        assert_eq!(b"bar".to_vec(), stack.require_bytes(6).unwrap());
+        assert!(stack.require_pointer(7).unwrap().is_null());
+        assert_eq!(
+            Value::Object(collections::BTreeMap::new()),
+            stack.require_object(8).unwrap().to_value()
+        );
+        assert_eq!(Some(Value::Array(Vec::new())), stack.get_value(9));
+        let global = stack.require_object(-1).unwrap();
+        global.set("x", &Value::Number(2.0)).unwrap();
+        let print = ctx.eval_string("Math.max").unwrap();
+        stack.push(&print);
+        stack.require_function(-1).unwrap();
+
+        assert_eq!(None, stack.get_boolean(3));
+        assert_eq!(None, stack.get_string(12));
+        assert!(stack.get_function(8).is_none());
+        assert_js_error(
+            &stack.require_string(0),
+            JsErrorKind::Type,
+            "string required, found undefined (stack index 0)",
+        );
+        assert_js_error(
+            &stack.require_number(13),
+            JsErrorKind::Range,
+            "invalid stack index 13",
+        );
+        assert_eq!(None, stack.type_of(-14));
+        stack.pop_n(2);
+        stack.pop();
+        assert_eq!(10, stack.len());
+        drop(stack);
+        drop(print);
+        drop(global);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn stack_nested() {
+        let ctx = Context::new();
+        let mut outer = ctx.stack();
+        outer.push_number(1.0);
+        {
+            let mut inner = ctx.stack();
+            assert_eq!(None, inner.type_of(0));
+            assert_eq!(None, inner.type_of(-1));
+            // The small default pool of compressed pointers doesn't fit a large value stack, but
+            // both sizes are more than the initial value stack holds.
+            let count = if cfg!(feature = "alloc-pool-ptrcomp") {
+                300
+            } else {
+                10_000
+            };
+            for i in 0..count {
+                inner.push_number(f64::from(i));
+            }
+            assert_eq!(Some(f64::from(count - 1)), inner.get_number(-1));
+        }
+        assert_eq!(1, outer.len());
+        assert_eq!(Some(1.0), outer.get_number(-1));
+        drop(outer);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn stack_invalid_string() {
+        let ctx = Context::new();
+        let string = ctx.eval_string("'\\ud800'").unwrap();
+        let mut stack = ctx.stack();
+        stack.push(&string);
+        assert_js_error(
+            &stack.require_string(0),
+            JsErrorKind::Type,
+            "string without unpaired surrogates required (stack index 0)",
+        );
+        assert_eq!(None, stack.get_string(0));
+        stack.pop();
+    }
+
+    #[test]
+    fn stack_script_values() {
+        let ctx = Context::new();
+        let mut stack = ctx.stack();
+        stack.push(&ctx.eval_string("[1,,2]").unwrap());
+        stack.push(&ctx.eval_string("'\\ud800'").unwrap());
+        assert_eq!(
+            Some(Value::Array(vec![
+                Value::Number(1.0),
+                Value::Undefined,
+                Value::Number(2.0)
+            ])),
+            stack.get_value(0)
+        );
+        assert_eq!(
+            Some(Value::String("\u{fffd}\u{fffd}\u{fffd}".to_owned())),
+            stack.get_value(1)
+        );
+        stack.pop_n(2);
+        drop(stack);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[should_panic(expected = "the value stack can't grow by 1 values")]
+    fn stack_overflow() {
+        let ctx = Context::new();
+        let mut stack = ctx.stack();
+        loop {
+            stack.push_null();
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "can't pop 1 values from a stack guard with 0")]
+    fn stack_pop_empty() {
+        let ctx = Context::new();
+        let mut outer = ctx.stack();
+        outer.push_null();
+        ctx.stack().pop();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "the value stack is unbalanced")]
+    fn stack_unbalanced() {
+        let ctx = Context::new();
+        let mut stack = ctx.stack();
+        stack.push_null();
+        unsafe { duk_sys::duk_push_null(ctx.raw) };
+    }
+
+    struct DropCounter(rc::Rc<cell::Cell<u32>>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn host_object() {
+        let ctx = Context::new();
+        let drops = rc::Rc::new(cell::Cell::new(0));
+        let object = ctx.push_host_object(DropCounter(drops.clone()));
+        assert!(rc::Rc::ptr_eq(
+            &drops,
+            &object.host_data::<DropCounter>().unwrap().0
+        ));
+        assert!(object.host_data::<String>().is_none());
+        assert!(ctx
+            .eval_string("({})")
+            .unwrap()
+            .host_data::<DropCounter>()
+            .is_none());
+        assert!(ctx
+            .eval_string("42")
+            .unwrap()
+            .host_data::<DropCounter>()
+            .is_none());
+
+        // Scripts can't see the id, and copies of the object share the value.
+        ctx.global_object().set("host", &object).unwrap();
+        assert_eq!(
+            Value::Number(0.0),
+            ctx.eval_string("Object.getOwnPropertyNames(host).length")
+                .unwrap()
+                .to_value()
+        );
+        let copy = ctx.eval_string("host").unwrap();
+        assert!(copy.host_data::<DropCounter>().is_some());
+        drop(copy);
+        drop(object);
+        assert_eq!(0, drops.get());
+
+        ctx.eval_string("host = null;").unwrap();
+        assert_eq!(1, drops.get());
+        ctx.gc();
+        ctx.gc();
+        assert_eq!(1, drops.get());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn host_object_dropped_with_context() {
+        let drops = rc::Rc::new(cell::Cell::new(0));
+        {
+            let ctx = Context::new();
+            let object = ctx.push_host_object(DropCounter(drops.clone()));
+            ctx.global_object().set("host", &object).unwrap();
+            // A cycle, so only mark-and-sweep or heap destruction can free it.
+            ctx.eval_string("host.self = host;").unwrap();
+            drop(object);
+            assert_eq!(0, drops.get());
+        }
+        assert_eq!(1, drops.get());
+    }
+
+    #[test]
+    fn host_object_finalizer_called_by_script() {
+        let ctx = Context::new();
+        let drops = rc::Rc::new(cell::Cell::new(0));
+        let object = ctx.push_host_object(DropCounter(drops.clone()));
+        ctx.global_object().set("host", &object).unwrap();
+
+        let data = object.host_data::<DropCounter>().unwrap();
+        ctx.eval_string("Duktape.fin(host)(host, false);").unwrap();
+        assert_eq!(0, drops.get());
+        assert_eq!(0, data.0.get());
+        drop(object);
+
+        ctx.eval_string("Duktape.fin(host)(host, false);").unwrap();
+        assert_eq!(1, drops.get());
+        ctx.eval_string("Duktape.fin(host)(host, false); host = null;")
+            .unwrap();
+        assert_eq!(1, drops.get());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn host_object_inherited() {
+        let ctx = Context::new();
+        let drops = rc::Rc::new(cell::Cell::new(0));
+        let object = ctx.push_host_object(DropCounter(drops.clone()));
+        ctx.global_object().set("host", &object).unwrap();
+        drop(object);
+
+        let child = ctx.eval_string("Object.create(host)").unwrap();
+        assert!(child.host_data::<DropCounter>().is_none());
+        drop(child);
+        ctx.eval_string("Duktape.fin(host)(Object.create(host), false);")
+            .unwrap();
+        ctx.gc();
+        assert_eq!(0, drops.get());
+
+        let host = ctx.global_object().get("host").unwrap();
+        assert!(host.host_data::<DropCounter>().is_some());
+        drop(host);
+        ctx.eval_string("host = null;").unwrap();
+        ctx.gc();
+        assert_eq!(1, drops.get());
+        ctx.assert_clean();
+    }
 }
@@ -0,0 +1,212 @@
+//! Routing and filtering of the messages that scripts log, with `Duktape.Logger` or the `console`
+//! global.
+use std::fmt;
+
+use crate::Context;
+use crate::Value;
+
+/// The prefix of the log targets of `Duktape.Logger` instances, which is followed by their name.
+const LOGGER_TARGET_PREFIX: &str = "duk:";
+
+/// Receives the messages that scripts log, as installed with `ContextBuilder::with_log_sink`.
+pub type LogSink = dyn Fn(LogRecord);
+
+/// A message logged by a script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    pub level: log::Level,
+    /// Where the message was logged.
+    pub source: LogSource,
+    /// The name of the `Duktape.Logger`, or the log target of the console.
+    pub logger: String,
+    /// The arguments of the logging call, formatted and separated by spaces.
+    pub message: String,
+    /// The arguments of the logging call, as they were passed, up to a limited depth.
+    pub args: Vec<Value>,
+    /// The file name of the function that logged the message, if known.
+    pub file_name: Option<String>,
+    /// The line that the message was logged from, if known.
+    pub line_number: Option<usize>,
+}
+
+/// The API that a script logged a message with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogSource {
+    /// A `Duktape.Logger` instance.
+    Logger,
+    /// The `console` global.
+    Console,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            LogSource::Logger => write!(f, "{}: {}", self.logger, self.message),
+            LogSource::Console => f.write_str(&self.message),
+        }
+    }
+}
+
+impl Context {
+    /// Sets the level of the `Duktape.Logger` instances that have no level of their own.
+    ///
+    /// This is the `l` property of `Duktape.Logger.prototype`, which defaults to `Info`.  Messages
+    /// below the level are dropped before their arguments are formatted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// // Don't format messages that the `log` crate would discard anyway.
+    /// ctx.set_log_level(log::max_level());
+    /// ```
+    pub fn set_log_level(&self, level: log::LevelFilter) {
+        unsafe {
+            duk_sys::duk_get_global_string(self.raw, crate::nul_str(b"Duktape\0"));
+            if 1 == duk_sys::duk_is_object(self.raw, -1) {
+                duk_sys::duk_get_prop_string(self.raw, -1, crate::nul_str(b"Logger\0"));
+                if 1 == duk_sys::duk_is_object(self.raw, -1) {
+                    duk_sys::duk_get_prop_string(self.raw, -1, crate::nul_str(b"prototype\0"));
+                    duk_sys::duk_push_int(self.raw, duk_level(level));
+                    duk_sys::duk_put_prop_string(self.raw, -2, crate::nul_str(b"l\0"));
+                    duk_sys::duk_pop(self.raw);
+                }
+                duk_sys::duk_pop(self.raw);
+            }
+            duk_sys::duk_pop(self.raw);
+        }
+    }
+
+    /// Sets the level of the `Duktape.Logger` instances with the specified name, overriding any
+    /// level that scripts set on them.
+    pub fn set_logger_level(&self, name: &str, level: log::LevelFilter) {
+        self.shared
+            .logger_levels
+            .borrow_mut()
+            .insert(name.to_owned(), level);
+    }
+
+    /// Removes the levels that were set with `set_logger_level`.
+    pub fn clear_logger_levels(&self) {
+        self.shared.logger_levels.borrow_mut().clear();
+    }
+
+    /// Configures levels from comma-separated directives in the format of `RUST_LOG`.
+    ///
+    /// A directive that is only a level sets the default level, like `set_log_level`, and
+    /// directives like `name=level` set the level of a logger, like `set_logger_level`.  A name
+    /// without a level enables all messages of that logger.  Names may include the `duk:` prefix
+    /// of log targets, so the value of `RUST_LOG` can be used as it is.  Invalid directives are
+    /// ignored with a warning.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.set_log_directives("warn,app=debug,duk:net=off");
+    /// ```
+    pub fn set_log_directives(&self, directives: &str) {
+        // Regular expression filters of `env_logger` don't apply to script loggers.
+        let directives = directives.split('/').next().unwrap_or_default();
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            match directive.split_once('=') {
+                None => match directive.parse() {
+                    Ok(level) => self.set_log_level(level),
+                    Err(_) => {
+                        self.set_logger_level(logger_name(directive), log::LevelFilter::Trace)
+                    }
+                },
+                Some((name, level)) => match level.trim().parse() {
+                    Ok(level) => self.set_logger_level(logger_name(name.trim()), level),
+                    Err(_) => log::warn!("ignoring invalid log directive: {}", directive),
+                },
+            }
+        }
+    }
+}
+
+/// Strips the log target prefix from a logger name in a directive.
+fn logger_name(name: &str) -> &str {
+    name.strip_prefix(LOGGER_TARGET_PREFIX).unwrap_or(name)
+}
+
+/// The Duktape log level that corresponds to a level filter.  `Off` is above the highest level.
+fn duk_level(level: log::LevelFilter) -> i32 {
+    let level = match level {
+        log::LevelFilter::Off => return duk_sys::DUK_LOG_FATAL as i32 + 1,
+        log::LevelFilter::Error => duk_sys::DUK_LOG_ERROR,
+        log::LevelFilter::Warn => duk_sys::DUK_LOG_WARN,
+        log::LevelFilter::Info => duk_sys::DUK_LOG_INFO,
+        log::LevelFilter::Debug => duk_sys::DUK_LOG_DEBUG,
+        log::LevelFilter::Trace => duk_sys::DUK_LOG_TRACE,
+    };
+    level as i32
+}
+
+/// The Duktape log level that was set from Rust for the logger with the specified name, if any.
+pub(crate) unsafe fn logger_level(ctx: *mut duk_sys::duk_context, name: &str) -> Option<i32> {
+    Context::with_raw(ctx, |ctx| {
+        ctx.shared
+            .logger_levels
+            .borrow()
+            .get(name)
+            .map(|&level| duk_level(level))
+    })
+}
+
+/// Hands a record to the log sink of the context, or to the `log` crate if there is none.
+///
+/// Records of loggers go to the target `duk:<name>`, and console records to the console target.
+pub(crate) unsafe fn emit(ctx: *mut duk_sys::duk_context, record: LogRecord) {
+    Context::with_raw(ctx, |ctx| match ctx.shared.log_sink {
+        Some(ref sink) => sink(record),
+        None => {
+            let target = match record.source {
+                LogSource::Logger => format!("{}{}", LOGGER_TARGET_PREFIX, record.logger),
+                LogSource::Console => record.logger.clone(),
+            };
+            log::log!(target: &target, record.level, "{}", record);
+        }
+    })
+}
+
+/// Reads the values at the bottom of the stack of the running native function, which hold the
+/// arguments of the call.  Only log sinks receive them, so nothing is copied without one.
+pub(crate) unsafe fn get_args(
+    ctx: *mut duk_sys::duk_context,
+    nargs: duk_sys::duk_idx_t,
+) -> Vec<Value> {
+    if !Context::with_raw(ctx, |ctx| ctx.shared.log_sink.is_some()) {
+        return Vec::new();
+    }
+    let mut args = Vec::with_capacity(nargs as usize);
+    for i in 0..nargs {
+        args.push(crate::copy::get_value(ctx, i));
+    }
+    args
+}
+
+/// The file name and line number of the function that called the running native function.
+pub(crate) unsafe fn caller_location(
+    ctx: *mut duk_sys::duk_context,
+) -> (Option<String>, Option<usize>) {
+    duk_sys::duk_inspect_callstack_entry(ctx, -2);
+    if 0 == duk_sys::duk_is_object(ctx, -1) {
+        duk_sys::duk_pop(ctx);
+        return (None, None);
+    }
+    let line_number = crate::get_number_property(ctx, -1, "lineNumber")
+        .filter(|&n| n > 0.0)
+        .map(|n| n as usize);
+    duk_sys::duk_get_prop_string(ctx, -1, crate::nul_str(b"function\0"));
+    let file_name = if 1 == duk_sys::duk_is_object(ctx, -1) {
+        crate::get_string_property(ctx, -1, "fileName").filter(|n| !n.is_empty())
+    } else {
+        None
+    };
+    duk_sys::duk_pop_2(ctx);
+    (file_name, line_number)
+}
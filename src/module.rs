@@ -0,0 +1,580 @@
+//! Support for loading modules with `require`, on top of Duktape's `module-node` extra.
+use std::collections;
+use std::ffi;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os;
+use std::path;
+use std::result;
+
+use crate::Context;
+use crate::Reference;
+use crate::Result;
+use crate::Value;
+
+/// Resolves the id passed to `require` (first argument) from the module with the specified id
+/// (second argument) to the id of the module to load.
+pub type ModuleResolver = dyn Fn(String, String) -> result::Result<String, ModuleError>;
+/// Loads the module with the specified resolved id.
+pub type ModuleLoader = dyn Fn(String) -> result::Result<ModuleSource, ModuleError>;
+/// Initializes the `exports` object of a module that is implemented in Rust.
+pub type NativeModule = dyn Fn(&Reference) -> Result<()>;
+/// Turns the Javascript source loaded for the module with the specified resolved id into the
+/// source that is actually loaded.
+pub type ModuleTransformer = dyn Fn(&str, String) -> result::Result<ModuleSource, ModuleError>;
+
+/// The source of a module, as returned by a `ModuleLoader`.
+pub enum ModuleSource {
+    /// CommonJS source code, evaluated with `exports`, `require`, `module`, `__filename` and
+    /// `__dirname` in scope.
+    Js(String),
+    /// A JSON document, which becomes the module's exports.
+    Json(String),
+    /// Bytecode of a CommonJS module, as produced by `Context::compile_module`.
+    ///
+    /// Duktape does not validate bytecode, so it must only ever come from a trusted source.
+    Bytecode(Vec<u8>),
+    /// A module implemented in Rust, which is called with the module's `exports` object.
+    Native(Box<NativeModule>),
+}
+
+/// The reasons why a module cannot be resolved or loaded.
+///
+/// These are thrown as an `Error` into the Javascript code that called `require`, with a message
+/// that includes the id of the module.
+#[derive(Debug, failure::Fail)]
+pub enum ModuleError {
+    /// There is no module with the requested id.
+    #[fail(display = "module not found")]
+    NotFound,
+    /// The module could not be read.
+    #[fail(display = "{}", raw)]
+    Io { raw: io::Error },
+    /// The module could not be resolved or loaded for some other reason.
+    #[fail(display = "{}", message)]
+    Other { message: String },
+}
+
+/// The module hooks used by the `require` implementation.
+#[derive(Default)]
+pub(crate) struct Modules {
+    pub(crate) resolver: Option<Box<ModuleResolver>>,
+    pub(crate) loader: Option<Box<ModuleLoader>>,
+    pub(crate) native: collections::HashMap<String, Box<NativeModule>>,
+    pub(crate) transformers: collections::HashMap<String, Box<ModuleTransformer>>,
+    pub(crate) es_modules: bool,
+}
+
+/// The file extensions that are probed for when a module id does not name a file.
+const EXTENSIONS: &[&str] = &["js", "json"];
+
+impl Context {
+    /// Lists the resolved ids of all modules in the `require` cache.
+    pub fn cached_module_ids(&self) -> Vec<String> {
+        let mut ids = Vec::new();
+        unsafe {
+            if push_require_cache(self.raw) {
+                duk_sys::duk_enum(self.raw, -1, duk_sys::DUK_ENUM_OWN_PROPERTIES_ONLY);
+                while 1 == duk_sys::duk_next(self.raw, -1, 0) {
+                    ids.push(crate::get_string(self.raw, -1));
+                    duk_sys::duk_pop(self.raw);
+                }
+                duk_sys::duk_pop(self.raw);
+            }
+            duk_sys::duk_pop(self.raw);
+        }
+        ids
+    }
+
+    /// Retrieves the `module` object of a cached module, which holds its `exports`.
+    pub fn cached_module(&self, id: &str) -> Option<Reference<'_>> {
+        unsafe {
+            if push_require_cache(self.raw) {
+                duk_sys::duk_push_lstring(self.raw, id.as_ptr() as *const i8, id.len());
+                let found = 1 == duk_sys::duk_get_prop(self.raw, -2);
+                duk_sys::duk_remove(self.raw, -2);
+                if found {
+                    return Some(self.pop_reference());
+                }
+            }
+            duk_sys::duk_pop(self.raw);
+            None
+        }
+    }
+
+    /// Removes a module from the `require` cache, so that the next `require` of the module loads
+    /// it again.  Returns whether the module was cached.
+    ///
+    /// Modules that already hold on to the old module's exports keep using them.
+    pub fn evict_module(&self, id: &str) -> bool {
+        unsafe {
+            let mut evicted = false;
+            if push_require_cache(self.raw) {
+                duk_sys::duk_push_lstring(self.raw, id.as_ptr() as *const i8, id.len());
+                evicted = 1 == duk_sys::duk_has_prop(self.raw, -2);
+                duk_sys::duk_push_lstring(self.raw, id.as_ptr() as *const i8, id.len());
+                duk_sys::duk_del_prop(self.raw, -2);
+            }
+            duk_sys::duk_pop(self.raw);
+            evicted
+        }
+    }
+
+    /// Removes all modules from the `require` cache.
+    pub fn clear_module_cache(&self) {
+        for id in self.cached_module_ids() {
+            self.evict_module(&id);
+        }
+    }
+}
+
+/// Pushes the `require` cache, or `undefined` if `require` is not installed.
+unsafe fn push_require_cache(ctx: *mut duk_sys::duk_context) -> bool {
+    duk_sys::duk_push_global_stash(ctx);
+    duk_sys::duk_get_prop_string(ctx, -1, crate::nul_str(b"\xffrequireCache\0"));
+    duk_sys::duk_remove(ctx, -2);
+    1 == duk_sys::duk_is_object(ctx, -1)
+}
+
+impl fmt::Debug for ModuleSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModuleSource::Js(ref source) => f.debug_tuple("Js").field(source).finish(),
+            ModuleSource::Json(ref source) => f.debug_tuple("Json").field(source).finish(),
+            ModuleSource::Bytecode(ref bytecode) => {
+                f.debug_tuple("Bytecode").field(bytecode).finish()
+            }
+            ModuleSource::Native(_) => f.debug_tuple("Native").field(&"..").finish(),
+        }
+    }
+}
+
+impl From<io::Error> for ModuleError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            ModuleError::NotFound
+        } else {
+            ModuleError::Io { raw: e }
+        }
+    }
+}
+
+impl From<crate::Error> for ModuleError {
+    fn from(e: crate::Error) -> Self {
+        let message = match e {
+            crate::Error::Js { raw } => raw.message,
+            e => e.to_string(),
+        };
+        ModuleError::Other { message }
+    }
+}
+
+impl Modules {
+    /// Whether `require` should be available at all.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.resolver.is_some() || self.loader.is_some() || !self.native.is_empty()
+    }
+
+    /// Installs `require` into the specified context, returning the pointer that must be released
+    /// once the heap is destroyed.
+    pub(crate) unsafe fn install(self, raw: *mut duk_sys::duk_context) -> *mut Modules {
+        let modules_ptr = Box::into_raw(Box::new(self));
+        duk_sys::duk_push_object(raw);
+
+        duk_sys::duk_push_c_function(raw, Some(module_resolve_handler), duk_sys::DUK_VARARGS);
+        duk_sys::duk_push_pointer(raw, modules_ptr as *mut os::raw::c_void);
+        duk_sys::duk_put_prop_string(raw, -2, crate::nul_str(b"closure\0"));
+        duk_sys::duk_put_prop_string(raw, -2, crate::nul_str(b"resolve\0"));
+
+        duk_sys::duk_push_c_function(raw, Some(module_load_handler), duk_sys::DUK_VARARGS);
+        duk_sys::duk_push_pointer(raw, modules_ptr as *mut os::raw::c_void);
+        duk_sys::duk_put_prop_string(raw, -2, crate::nul_str(b"closure\0"));
+        duk_sys::duk_put_prop_string(raw, -2, crate::nul_str(b"load\0"));
+
+        duk_sys::duk_module_node_init(raw);
+
+        modules_ptr
+    }
+
+    /// Resolves a module id with the configured resolver, or with `resolve_relative_id` if there
+    /// is none.
+    fn resolve(
+        &self,
+        requested_id: String,
+        parent_id: String,
+    ) -> result::Result<String, ModuleError> {
+        if self.native.contains_key(&requested_id) {
+            Ok(requested_id)
+        } else if let Some(ref resolve) = self.resolver {
+            resolve(requested_id, parent_id)
+        } else {
+            resolve_relative_id(&requested_id, &parent_id)
+        }
+    }
+
+    /// Loads a module with the configured loader, or fails if there is none.
+    fn load(&self, id: &str) -> result::Result<ModuleSource, ModuleError> {
+        let source = match self.loader {
+            Some(ref load) => self.transform(id, load(id.to_owned())?)?,
+            None => return Err(ModuleError::NotFound),
+        };
+        match source {
+            ModuleSource::Js(ref source) if self.es_modules => {
+                Ok(ModuleSource::Js(crate::esm::transform_es_module(source)?))
+            }
+            source => Ok(source),
+        }
+    }
+
+    /// Applies the transformer registered for the extension of `id`, if any, to a Javascript
+    /// source.
+    fn transform(
+        &self,
+        id: &str,
+        source: ModuleSource,
+    ) -> result::Result<ModuleSource, ModuleError> {
+        match source {
+            ModuleSource::Js(source) => {
+                let extension = path::Path::new(id)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or("");
+                match self.transformers.get(extension) {
+                    Some(transform) => transform(id, source),
+                    None if extension == "json" => Ok(ModuleSource::Json(source)),
+                    None => Ok(ModuleSource::Js(source)),
+                }
+            }
+            source => Ok(source),
+        }
+    }
+}
+
+/// Resolves relative module ids (`./x`, `../x`) against the id of the parent module, treating ids
+/// as `/`-separated paths.  Other ids are left as they are.
+fn resolve_relative_id(requested_id: &str, parent_id: &str) -> result::Result<String, ModuleError> {
+    if !requested_id.starts_with("./") && !requested_id.starts_with("../") {
+        return Ok(requested_id.to_owned());
+    }
+
+    let mut parts = parent_id.split('/').collect::<Vec<_>>();
+    parts.pop();
+    for part in requested_id.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(ModuleError::NotFound);
+                }
+            }
+            part => parts.push(part),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+unsafe extern "C" fn module_resolve_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    match resolve_module(ctx) {
+        Ok(resolved_id) => {
+            Value::String(resolved_id).push(ctx);
+            1
+        }
+        Err(message) => crate::throw_error(ctx, duk_sys::DUK_ERR_ERROR as i32, message),
+    }
+}
+
+unsafe fn resolve_module(ctx: *mut duk_sys::duk_context) -> result::Result<String, String> {
+    let requested_id = crate::get_string(ctx, 0);
+    let parent_id = crate::get_string(ctx, 1);
+    duk_sys::duk_pop_2(ctx);
+
+    let modules = &*crate::get_closure::<Modules>(ctx);
+
+    // Ensure clear stack before entering the Rust wild west
+    modules
+        .resolve(requested_id.clone(), parent_id)
+        .map_err(|e| error_message("resolve", &requested_id, e))
+}
+
+unsafe extern "C" fn module_load_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    match load_module(ctx) {
+        Ok(Some(source)) => {
+            Value::String(source).push(ctx);
+            1
+        }
+        Ok(None) => 0,
+        Err(message) => crate::throw_error(ctx, duk_sys::DUK_ERR_ERROR as i32, message),
+    }
+}
+
+/// Loads a module, returning the Javascript source if `module-node` should evaluate it, or `None`
+/// if the module was fully initialized from Rust.
+unsafe fn load_module(ctx: *mut duk_sys::duk_context) -> result::Result<Option<String>, String> {
+    // Stack: [ resolved_id exports module ]
+    let resolved_id = crate::get_string(ctx, 0);
+    let modules = &*crate::get_closure::<Modules>(ctx);
+
+    let result = if let Some(init) = modules.native.get(&resolved_id) {
+        // Native modules are only borrowed, so they are initialized in place.
+        duk_sys::duk_dup(ctx, 2);
+        Context::with_raw(ctx, |context| {
+            let module = context.pop_reference();
+            init(&module.get("exports")?)?;
+            module.set("loaded", &Value::Boolean(true))
+        })
+        .map(|()| None)
+        .map_err(ModuleError::from)
+    } else {
+        // Ensure clear stack before entering the Rust wild west
+        match modules.load(&resolved_id) {
+            Ok(ModuleSource::Js(source)) => Ok(Some(source)),
+            Ok(source) => {
+                duk_sys::duk_dup(ctx, 2);
+                Context::with_raw(ctx, |context| {
+                    define_module(context, &resolved_id, &context.pop_reference(), source)
+                })
+                .map(|()| None)
+                .map_err(ModuleError::from)
+            }
+            Err(e) => Err(e),
+        }
+    };
+
+    result.map_err(|e| error_message("load", &resolved_id, e))
+}
+
+/// Formats the message of the error that is thrown into Javascript for a module error.
+fn error_message(action: &str, id: &str, error: ModuleError) -> String {
+    match error {
+        ModuleError::NotFound => format!("cannot find module '{}'", id),
+        e => format!("cannot {} module '{}': {}", action, id, e),
+    }
+}
+
+/// Initializes a `module` object from a source that `module-node` does not understand itself.
+fn define_module(
+    context: &Context,
+    id: &str,
+    module: &Reference,
+    source: ModuleSource,
+) -> Result<()> {
+    match source {
+        ModuleSource::Js(_) => unreachable!("Javascript modules are evaluated by module-node"),
+        ModuleSource::Json(json) => {
+            let exports = context
+                .global_object()
+                .get("JSON")?
+                .call_method("parse", &[&Value::String(json)])?;
+            module.set("exports", &exports)?;
+        }
+        ModuleSource::Bytecode(bytecode) => {
+            let function = context.load_bytecode(&bytecode)?;
+            function.call(&[
+                &module.get("exports")?,
+                &module.get("require")?,
+                module,
+                &Value::String(id.to_owned()),
+                &Value::Undefined,
+            ])?;
+        }
+        ModuleSource::Native(init) => init(&module.get("exports")?)?,
+    }
+    module.set("loaded", &Value::Boolean(true))
+}
+
+/// A module resolver that implements the Node.js module resolution algorithm over one or more root
+/// directories.
+///
+/// Resolved module ids are canonical absolute paths to files.  Relative ids (`./x`, `../x`) are
+/// resolved against the directory of the requiring module, and bare ids (`x`) are looked up in
+/// `node_modules` directories from the requiring module's directory upwards.  For every candidate,
+/// the file itself, the file with a `.js` or `.json` extension, the `main` file of a directory's
+/// `package.json` and finally the directory's `index.js` or `index.json` are tried in order.
+///
+/// Resolution is jailed: a module that resolves to a file outside of all of the configured roots
+/// is refused, even if it is reached through `..` or a symbolic link.
+#[derive(Clone, Debug)]
+pub struct FsModuleResolver {
+    roots: Vec<path::PathBuf>,
+}
+
+impl FsModuleResolver {
+    /// Creates a resolver that resolves modules within the specified root directory.
+    ///
+    /// Top-level `require` calls resolve relative to this directory.
+    pub fn new<P: AsRef<path::Path>>(root: P) -> io::Result<FsModuleResolver> {
+        Ok(FsModuleResolver {
+            roots: vec![root.as_ref().canonicalize()?],
+        })
+    }
+
+    /// Allows modules to also be resolved within the specified root directory.
+    pub fn with_root<P: AsRef<path::Path>>(mut self, root: P) -> io::Result<FsModuleResolver> {
+        self.roots.push(root.as_ref().canonicalize()?);
+        Ok(self)
+    }
+
+    /// The canonical root directories that modules may be resolved within.
+    pub fn roots(&self) -> &[path::PathBuf] {
+        &self.roots
+    }
+
+    /// Resolves the module id passed to `require` from the module with id `parent_id` to the path
+    /// of the module's file.
+    pub fn resolve(&self, requested_id: &str, parent_id: &str) -> io::Result<path::PathBuf> {
+        let parent_dir = path::Path::new(parent_id)
+            .parent()
+            .filter(|dir| self.is_within_roots(dir))
+            .unwrap_or(&self.roots[0]);
+
+        let found = if is_path_id(requested_id) {
+            let target = parent_dir.join(requested_id);
+            match self.load_as_file(&target)? {
+                Some(found) => Some(found),
+                None => self.load_as_directory(&target)?,
+            }
+        } else {
+            self.load_node_modules(requested_id, parent_dir)?
+        };
+
+        found.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cannot find module '{}'", requested_id),
+            )
+        })
+    }
+
+    /// Converts this resolver into a boxed `ModuleResolver` for use with a `ContextBuilder`.
+    pub fn into_module_resolver(self) -> Box<ModuleResolver> {
+        Box::new(move |requested_id, parent_id| {
+            let path = self.resolve(&requested_id, &parent_id)?;
+            Ok(path.to_string_lossy().into_owned())
+        })
+    }
+
+    fn load_as_file(&self, target: &path::Path) -> io::Result<Option<path::PathBuf>> {
+        if let Some(found) = self.candidate(target)? {
+            return Ok(Some(found));
+        }
+
+        if let Some(file_name) = target.file_name() {
+            for extension in EXTENSIONS {
+                let mut with_extension = file_name.to_os_string();
+                with_extension.push(".");
+                with_extension.push(extension);
+                if let Some(found) = self.candidate(&target.with_file_name(with_extension))? {
+                    return Ok(Some(found));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn load_as_directory(&self, target: &path::Path) -> io::Result<Option<path::PathBuf>> {
+        if !target.is_dir() {
+            return Ok(None);
+        }
+
+        if let Some(main) = package_main(target)? {
+            let main = target.join(main);
+            if let Some(found) = self.load_as_file(&main)? {
+                return Ok(Some(found));
+            }
+            if let Some(found) = self.load_index(&main)? {
+                return Ok(Some(found));
+            }
+        }
+
+        self.load_index(target)
+    }
+
+    fn load_index(&self, target: &path::Path) -> io::Result<Option<path::PathBuf>> {
+        for extension in EXTENSIONS {
+            let index = target.join(format!("index.{}", extension));
+            if let Some(found) = self.candidate(&index)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    fn load_node_modules(
+        &self,
+        requested_id: &str,
+        parent_dir: &path::Path,
+    ) -> io::Result<Option<path::PathBuf>> {
+        for dir in parent_dir.ancestors() {
+            if !self.is_within_roots(dir) {
+                break;
+            }
+            if dir.file_name() == Some(ffi::OsStr::new("node_modules")) {
+                continue;
+            }
+
+            let target = dir.join("node_modules").join(requested_id);
+            if let Some(found) = self.load_as_file(&target)? {
+                return Ok(Some(found));
+            }
+            if let Some(found) = self.load_as_directory(&target)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks whether `path` is an existing file, and if so ensures that it lies within the roots.
+    fn candidate(&self, path: &path::Path) -> io::Result<Option<path::PathBuf>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let canonical = path.canonicalize()?;
+        if self.is_within_roots(&canonical) {
+            Ok(Some(canonical))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "module '{}' is outside of the module roots",
+                    canonical.display()
+                ),
+            ))
+        }
+    }
+
+    fn is_within_roots(&self, path: &path::Path) -> bool {
+        self.roots.iter().any(|root| path.starts_with(root))
+    }
+}
+
+/// Whether a module id is a relative or absolute path, as opposed to a bare package name.
+fn is_path_id(id: &str) -> bool {
+    id == "."
+        || id == ".."
+        || id.starts_with("./")
+        || id.starts_with("../")
+        || path::Path::new(id).is_absolute()
+}
+
+/// Reads the `main` field of the `package.json` file in the specified directory, if any.
+fn package_main(dir: &path::Path) -> io::Result<Option<String>> {
+    let text = match fs::read_to_string(dir.join("package.json")) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let package: serde_json::Value = serde_json::from_str(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid package.json: {}", e),
+        )
+    })?;
+    Ok(package
+        .get("main")
+        .and_then(serde_json::Value::as_str)
+        .map(String::from))
+}
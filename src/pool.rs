@@ -0,0 +1,318 @@
+//! Heaps that allocate from a fixed memory region, with the pool allocator from the Duktape
+//! distribution, instead of the system allocator.
+use std::os;
+#[cfg(feature = "alloc-pool-ptrcomp")]
+use std::sync::atomic;
+
+use crate::Context;
+use crate::Shared;
+
+/// Whether a context with compressed pointers exists, since they are relative to a single global
+/// pool.
+#[cfg(feature = "alloc-pool-ptrcomp")]
+static COMPRESSED_POOL_IN_USE: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// The memory region and pools of a pool allocator, as set with
+/// `ContextBuilder::with_pool_allocator`.
+///
+/// The region is divided into pools of fixed-size blocks.  A pool for blocks of `block_size`
+/// bytes gets `(a * t + b) / block_size` blocks, where `t` is the largest scale for which all
+/// pools fit in the region, and any bytes that are left over go to the pools with the largest
+/// blocks.  Allocations are served from the pool with the smallest blocks that fit and still has
+/// free blocks, and fail once there are none.
+///
+/// With the `alloc-pool-ptrcomp` feature, Duktape stores heap pointers in 16 bits, relative to
+/// the region.  The region can then be at most `MAX_COMPRESSED_SIZE` bytes, only one context can
+/// exist at a time, and contexts that are built without a pool allocator use the default one.
+/// Tests that create contexts must then run on one thread, as with
+/// `cargo test --features alloc-pool-ptrcomp -- --test-threads=1`.
+///
+/// # Examples
+///
+/// ```
+/// let config = duk::PoolAllocatorConfig::new(240 * 1024)
+///     .with_scaled_pool(32, 40, 1000)
+///     .with_scaled_pool(48, 60, 30000)
+///     .with_scaled_pool(64, 60, 30000)
+///     .with_scaled_pool(96, 60, 45000)
+///     .with_scaled_pool(256, 100, 12000)
+///     .with_pool(1024, 24)
+///     .with_pool(2048, 6)
+///     .with_scaled_pool(8192, 100, 16384);
+/// let ctx = duk::Context::builder().with_pool_allocator(config).build();
+/// assert_eq!(duk::Value::Number(3.0), ctx.eval_string("1 + 2").unwrap().to_value());
+///
+/// let stats = ctx.pool_stats().unwrap();
+/// assert!(stats.used_bytes > 0);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolAllocatorConfig {
+    size: usize,
+    pools: Vec<duk_sys::duk_pool_config>,
+}
+
+/// Statistics for all pools of a context, as returned by `Context::pool_stats`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolAllocatorStats {
+    /// The bytes in blocks that are allocated.
+    pub used_bytes: usize,
+    /// The bytes in blocks that are free.
+    pub free_bytes: usize,
+    /// An estimate of the bytes that allocated blocks don't use.
+    pub waste_bytes: usize,
+    /// The statistics of each pool, by ascending block size.
+    pub pools: Vec<PoolStats>,
+}
+
+/// Statistics for the pool of one block size.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolStats {
+    pub block_size: usize,
+    /// The number of blocks that are allocated.
+    pub used_count: usize,
+    /// The number of blocks that are free.
+    pub free_count: usize,
+    /// An estimate of the bytes that allocated blocks don't use.
+    pub waste_bytes: usize,
+}
+
+/// The state of a pool allocator, which the allocation functions of the heap use.
+///
+/// The allocator keeps pointers into the boxes, so they must not be dropped before the heap is
+/// destroyed.
+pub(crate) struct Pool {
+    global: Box<duk_sys::duk_pool_global>,
+    _states: Box<[duk_sys::duk_pool_state]>,
+    _configs: Box<[duk_sys::duk_pool_config]>,
+    // `u64` for alignment.
+    _region: Box<[u64]>,
+}
+
+impl PoolAllocatorConfig {
+    /// The largest region that compressed pointers can address.
+    pub const MAX_COMPRESSED_SIZE: usize = 4 * 0xffff;
+
+    /// Whether Duktape was built with compressed pointers, with the `alloc-pool-ptrcomp` feature.
+    pub const POINTER_COMPRESSION: bool = cfg!(feature = "alloc-pool-ptrcomp");
+
+    /// Creates a configuration for a region of `size` bytes, without any pools yet.
+    pub fn new(size: usize) -> Self {
+        PoolAllocatorConfig {
+            size,
+            pools: Vec::new(),
+        }
+    }
+
+    /// Adds a pool with `count` blocks of `block_size` bytes.
+    pub fn with_pool(self, block_size: u32, count: u32) -> Self {
+        self.with_scaled_pool(block_size, 0, block_size * count)
+    }
+
+    /// Adds a pool for blocks of `block_size` bytes that gets `a * t + b` bytes, for the scale `t`
+    /// that fills the region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is not divisible by 4, or smaller than a pointer.
+    pub fn with_scaled_pool(mut self, block_size: u32, a: u32, b: u32) -> Self {
+        assert!(
+            block_size.is_multiple_of(4) && block_size as usize >= std::mem::size_of::<usize>(),
+            "the block size {} is not divisible by 4 or smaller than a pointer",
+            block_size
+        );
+        self.pools.push(duk_sys::duk_pool_config {
+            size: block_size,
+            a,
+            b,
+        });
+        self
+    }
+
+    /// The size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// The block sizes and scale parameters of the default pools.  Compressed pointers make objects
+/// smaller, so they need more small blocks.
+#[cfg(feature = "alloc-pool-ptrcomp")]
+const DEFAULT_POOLS: &[(u32, u32, u32)] = &[
+    (16, 20, 400),
+    (32, 40, 12800),
+    (40, 60, 30000),
+    (48, 60, 4000),
+    (56, 60, 20000),
+    (64, 60, 1000),
+    (80, 60, 4000),
+    (256, 100, 26000),
+    (1024, 20, 36000),
+    (2048, 20, 14000),
+    (4096, 100, 20480),
+    (8192, 100, 32768),
+];
+#[cfg(not(feature = "alloc-pool-ptrcomp"))]
+const DEFAULT_POOLS: &[(u32, u32, u32)] = &[
+    (16, 20, 200),
+    (32, 40, 1000),
+    (40, 60, 15000),
+    (48, 60, 15000),
+    (56, 60, 30000),
+    (64, 60, 1000),
+    (80, 60, 45000),
+    (256, 100, 12000),
+    (1024, 20, 24000),
+    (2048, 20, 12000),
+    (4096, 100, 8192),
+    (8192, 100, 16384),
+];
+
+/// Pools like those of `extras/alloc-pool/test.c` from the Duktape distribution, with base
+/// allocations measured for a context with the default features on a 64-bit target, in the
+/// largest region that compressed pointers allow.  With compressed pointers, a context with
+/// promises fits too.  They are not tuned for any particular workload, so production
+/// configurations should be based on measurements.
+impl Default for PoolAllocatorConfig {
+    fn default() -> Self {
+        DEFAULT_POOLS.iter().fold(
+            PoolAllocatorConfig::new(PoolAllocatorConfig::MAX_COMPRESSED_SIZE),
+            |config, &(block_size, a, b)| config.with_scaled_pool(block_size, a, b),
+        )
+    }
+}
+
+impl Pool {
+    /// Divides a region into the configured pools.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pools don't fit in the region, or if compressed pointers can't be used for
+    /// it.
+    pub(crate) fn new(config: PoolAllocatorConfig) -> Pool {
+        let mut configs = config.pools.into_boxed_slice();
+        configs.sort_by_key(|pool| pool.size);
+
+        #[cfg(feature = "alloc-pool-ptrcomp")]
+        {
+            assert!(
+                config.size <= PoolAllocatorConfig::MAX_COMPRESSED_SIZE,
+                "a region of {} bytes is too large for compressed pointers",
+                config.size
+            );
+            assert!(
+                !COMPRESSED_POOL_IN_USE.swap(true, atomic::Ordering::SeqCst),
+                "only one context can exist at a time with compressed pointers"
+            );
+        }
+
+        let mut region = vec![0u64; config.size.div_ceil(8)].into_boxed_slice();
+        let mut states = vec![
+            duk_sys::duk_pool_state {
+                first: std::ptr::null_mut(),
+                alloc_end: std::ptr::null_mut(),
+                size: 0,
+                count: 0,
+            };
+            configs.len()
+        ]
+        .into_boxed_slice();
+        let mut global = Box::new(duk_sys::duk_pool_global {
+            num_pools: 0,
+            states: std::ptr::null_mut(),
+        });
+        let udata = unsafe {
+            duk_sys::duk_alloc_pool_init(
+                region.as_mut_ptr() as *mut os::raw::c_char,
+                config.size,
+                configs.as_ptr(),
+                states.as_mut_ptr(),
+                configs.len() as os::raw::c_int,
+                &mut *global,
+            )
+        };
+        let pool = Pool {
+            global,
+            _states: states,
+            _configs: configs,
+            _region: region,
+        };
+        assert!(
+            !udata.is_null(),
+            "the pools don't fit in a region of {} bytes",
+            config.size
+        );
+        pool
+    }
+
+    fn stats(&self) -> PoolAllocatorStats {
+        let global = &*self.global as *const _ as *mut duk_sys::duk_pool_global;
+        let mut total = duk_sys::duk_pool_global_stats::default();
+        unsafe { duk_sys::duk_alloc_pool_get_global_stats(global, &mut total) };
+
+        let pools = (0..self.global.num_pools as usize)
+            .map(|i| {
+                let mut stats = duk_sys::duk_pool_stats::default();
+                unsafe {
+                    let state = self.global.states.add(i);
+                    duk_sys::duk_alloc_pool_get_pool_stats(state, &mut stats);
+                    PoolStats {
+                        block_size: (*state).size as usize,
+                        used_count: stats.used_count,
+                        free_count: stats.free_count,
+                        waste_bytes: stats.waste_bytes,
+                    }
+                }
+            })
+            .collect();
+
+        PoolAllocatorStats {
+            used_bytes: total.used_bytes,
+            free_bytes: total.free_bytes,
+            waste_bytes: total.waste_bytes,
+            pools,
+        }
+    }
+}
+
+#[cfg(feature = "alloc-pool-ptrcomp")]
+impl Drop for Pool {
+    fn drop(&mut self) {
+        COMPRESSED_POOL_IN_USE.store(false, atomic::Ordering::SeqCst);
+    }
+}
+
+impl Context {
+    /// The statistics of the pool allocator of this context, or `None` if it uses the system
+    /// allocator.
+    pub fn pool_stats(&self) -> Option<PoolAllocatorStats> {
+        self.shared.pool.as_ref().map(Pool::stats)
+    }
+}
+
+/// The user data of the pool allocator of the context whose shared state is the heap user data.
+unsafe fn pool_udata(udata: *mut os::raw::c_void) -> *mut os::raw::c_void {
+    let shared = &*(udata as *const Shared);
+    match shared.pool {
+        Some(ref pool) => &*pool.global as *const _ as *mut os::raw::c_void,
+        None => unreachable!("the pool allocator is used without a pool"),
+    }
+}
+
+pub(crate) unsafe extern "C" fn alloc(
+    udata: *mut os::raw::c_void,
+    size: duk_sys::duk_size_t,
+) -> *mut os::raw::c_void {
+    duk_sys::duk_alloc_pool(pool_udata(udata), size)
+}
+
+pub(crate) unsafe extern "C" fn realloc(
+    udata: *mut os::raw::c_void,
+    ptr: *mut os::raw::c_void,
+    size: duk_sys::duk_size_t,
+) -> *mut os::raw::c_void {
+    duk_sys::duk_realloc_pool(pool_udata(udata), ptr, size)
+}
+
+pub(crate) unsafe extern "C" fn free(udata: *mut os::raw::c_void, ptr: *mut os::raw::c_void) {
+    duk_sys::duk_free_pool(pool_udata(udata), ptr)
+}
@@ -0,0 +1,157 @@
+//! Promise support, based on the Promise polyfill from the Duktape distribution.
+//!
+//! The polyfill keeps its own job queue, which is only drained when the host asks for it, so
+//! Promise reactions run exactly when Rust calls `Context::run_microtasks`.
+use crate::Argument;
+use crate::Context;
+use crate::Error;
+use crate::JsError;
+use crate::JsErrorKind;
+use crate::Reference;
+use crate::Result;
+
+const DEFER_PROMISE: &str = "(function() {\n\
+    var deferred = {};\n\
+    deferred.promise = new Promise(function(resolve, reject) {\n\
+        deferred.resolve = resolve;\n\
+        deferred.reject = reject;\n\
+    });\n\
+    return deferred;\n\
+})";
+
+impl Context {
+    /// Loads the Promise polyfill, and keeps a reference to the `Promise` constructor in the global
+    /// stash, where the host looks it up, along with a helper that creates a promise together with
+    /// its resolving functions.
+    pub(crate) fn install_promises(&self) {
+        self.eval_string_with_filename("promise.js", duk_sys::PROMISE_POLYFILL)
+            .expect("the Promise polyfill failed to load");
+        let defer = self
+            .eval_string(DEFER_PROMISE)
+            .expect("the Promise helpers failed to load");
+        unsafe {
+            duk_sys::duk_push_global_stash(self.raw);
+            duk_sys::duk_get_global_string(self.raw, crate::nul_str(b"Promise\0"));
+            duk_sys::duk_put_prop_string(self.raw, -2, crate::nul_str(b"Promise\0"));
+            defer.push();
+            duk_sys::duk_put_prop_string(self.raw, -2, crate::nul_str(b"deferPromise\0"));
+            duk_sys::duk_pop(self.raw);
+        }
+    }
+
+    /// Whether this context was built with `ContextBuilder::with_promises`.
+    pub fn promises_enabled(&self) -> bool {
+        self.shared.promises
+    }
+
+    /// Runs Promise reactions until no more are pending.
+    ///
+    /// Reactions are only ever run by this function, and by the functions that wait for promises
+    /// to settle.  It does nothing for contexts that were built without promise support.
+    pub fn run_microtasks(&self) -> Result<()> {
+        if !self.promises_enabled() {
+            return Ok(());
+        }
+        unsafe {
+            push_promise_constructor(self.raw);
+            duk_sys::duk_push_string(self.raw, crate::nul_str(b"runQueue\0"));
+            let ret = duk_sys::duk_pcall_prop(self.raw, -2, 0);
+            duk_sys::duk_remove(self.raw, -2);
+            self.pop_reference_or_error(ret).map(|_| ())
+        }
+    }
+
+    /// Resolves a promise with the specified value, like `Promise.resolve(value)`, runs microtasks
+    /// until the promise has settled, and returns the value that it was fulfilled with.
+    ///
+    /// Values that are not promises or thenables are returned as they are.  If the promise is
+    /// rejected, the rejection reason is returned as an `Error::Js`.  Fails with `Error::Promise`
+    /// if promise support is not enabled, or if the promise is still pending once no more
+    /// microtasks are left, for example because it waits for a timer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder().with_promises().build();
+    /// let promise = ctx
+    ///     .eval_string("Promise.resolve(20).then(function(x) { return x + 22; })")
+    ///     .unwrap();
+    /// let value = ctx.resolve_promise(&promise).unwrap().to_value();
+    /// assert_eq!(duk::Value::Number(42.0), value);
+    /// ```
+    pub fn resolve_promise(&self, value: &dyn Argument) -> Result<Reference<'_>> {
+        if !self.promises_enabled() {
+            return Err(Error::Promise {
+                message: "promise support is not enabled".to_owned(),
+            });
+        }
+        let promise = unsafe {
+            push_promise_constructor(self.raw);
+            duk_sys::duk_push_string(self.raw, crate::nul_str(b"resolve\0"));
+            value.push_to_context(self);
+            let ret = duk_sys::duk_pcall_prop(self.raw, -3, 1);
+            duk_sys::duk_remove(self.raw, -2);
+            self.pop_reference_or_error(ret)?
+        };
+        self.run_microtasks()?;
+        promise.with_value(|| unsafe { settled_value(self) })
+    }
+}
+
+impl<'a> Reference<'a> {
+    /// Waits for the promise that this reference points to to settle, by running microtasks, and
+    /// returns the value that it was fulfilled with.
+    ///
+    /// Behaves like `Context::resolve_promise(self)`.
+    pub fn await_promise(&self) -> Result<Reference<'a>> {
+        self.ctx.resolve_promise(self)
+    }
+}
+
+unsafe fn push_promise_constructor(raw: *mut duk_sys::duk_context) {
+    duk_sys::duk_push_global_stash(raw);
+    duk_sys::duk_get_prop_string(raw, -1, crate::nul_str(b"Promise\0"));
+    duk_sys::duk_remove(raw, -2);
+}
+
+/// Returns the outcome of the polyfill promise on top of the stack, which the polyfill keeps in
+/// its `state` (`undefined` while pending, then `true` or `false`) and `value` properties.
+unsafe fn settled_value(ctx: &Context) -> Result<Reference<'_>> {
+    let raw = ctx.raw;
+    duk_sys::duk_get_prop_string(raw, -1, crate::nul_str(b"state\0"));
+    let state = if 1 == duk_sys::duk_is_boolean(raw, -1) {
+        Some(1 == duk_sys::duk_get_boolean(raw, -1))
+    } else {
+        None
+    };
+    duk_sys::duk_pop(raw);
+
+    match state {
+        None => Err(Error::Promise {
+            message: "promise is still pending".to_owned(),
+        }),
+        Some(fulfilled) => {
+            duk_sys::duk_get_prop_string(raw, -1, crate::nul_str(b"value\0"));
+            if fulfilled {
+                Ok(ctx.pop_reference())
+            } else if 1 == duk_sys::duk_is_undefined(raw, -1) || 1 == duk_sys::duk_is_null(raw, -1)
+            {
+                // Errors are read from properties, which these values can't have.
+                duk_sys::duk_to_string(raw, -1);
+                let reason = crate::get_string(raw, -1);
+                duk_sys::duk_pop(raw);
+                Err(Error::Js {
+                    raw: JsError {
+                        kind: JsErrorKind::Generic,
+                        message: reason,
+                        file_name: None,
+                        line_number: None,
+                        stack: None,
+                    },
+                })
+            } else {
+                Err(ctx.pop_error())
+            }
+        }
+    }
+}
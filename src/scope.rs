@@ -0,0 +1,297 @@
+//! Values that live on the value stack for the duration of a scope, which are cheaper than
+//! `Reference`s for intermediate values.
+use std::ffi;
+use std::marker;
+
+use crate::Argument;
+use crate::Context;
+use crate::Error;
+use crate::Reference;
+use crate::Result;
+use crate::Shared;
+use crate::StackRAII;
+use crate::Value;
+
+/// A scope for `Local` values, as entered with `Context::scope`.
+///
+/// The lifetime is unique to each scope, so that the compiler rejects locals that would outlive
+/// it.
+#[derive(Debug)]
+pub struct Scope<'s> {
+    ctx: &'s Context,
+    depth: usize,
+    _invariant: marker::PhantomData<fn(&'s ()) -> &'s ()>,
+}
+
+/// A value on the value stack, which is popped when its `Scope` is left.
+///
+/// Locals are copied freely, and creating one only pushes the value, so intermediate values of
+/// hot loops don't pay for the handle table of `Reference`.  Locals accumulate until their scope
+/// is left, so loops with many iterations should enter a scope for each iteration.
+#[derive(Clone, Copy, Debug)]
+pub struct Local<'s> {
+    scope: &'s Scope<'s>,
+    idx: duk_sys::duk_idx_t,
+}
+
+/// Leaves a scope, even if it is left by a panic.
+struct ScopeGuard<'c> {
+    shared: &'c Shared,
+    _stack: StackRAII,
+}
+
+impl Context {
+    /// Runs `action` in a new scope for `Local` values, and pops them when it returns.
+    ///
+    /// Locals can't escape the scope, except as a `Reference` with `Local::persist`.  Only the
+    /// innermost scope can create locals, so that none are popped early.  Locals of outer scopes
+    /// can still be read and passed as arguments in it, or pushed with `Scope::push` to create new
+    /// locals from them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("var points = [{x: 1, y: 2}, {x: 3, y: 4}];").unwrap();
+    /// let sum = ctx.scope(|s| {
+    ///     let points = s.global_object().get("points").unwrap();
+    ///     let mut sum = 0.0;
+    ///     for i in 0..2 {
+    ///         // The locals of each point are popped after each iteration.
+    ///         sum += ctx.scope(|s| {
+    ///             let point = s.push(&points).get(&i.to_string()).unwrap();
+    ///             match point.get("x").unwrap().to_value() {
+    ///                 duk::Value::Number(x) => x,
+    ///                 _ => 0.0,
+    ///             }
+    ///         });
+    ///     }
+    ///     sum
+    /// });
+    /// assert_eq!(4.0, sum);
+    /// ```
+    ///
+    /// Locals can't be returned from the scope:
+    ///
+    /// ```compile_fail
+    /// let ctx = duk::Context::new();
+    /// let local = ctx.scope(|s| s.global_object());
+    /// ```
+    pub fn scope<F, R>(&self, action: F) -> R
+    where
+        F: for<'s> FnOnce(&'s Scope<'s>) -> R,
+    {
+        let depth = self.shared.scope_depth.get() + 1;
+        self.shared.scope_depth.set(depth);
+        let _guard = ScopeGuard {
+            shared: &self.shared,
+            _stack: unsafe { StackRAII::new(self.raw) },
+        };
+        let scope = Scope {
+            ctx: self,
+            depth,
+            _invariant: marker::PhantomData,
+        };
+        action(&scope)
+    }
+}
+
+impl<'s> Scope<'s> {
+    /// Pushes a copy of a value, which may be a `Value`, `Reference` or another `Local`.
+    pub fn push(&'s self, value: &dyn Argument) -> Local<'s> {
+        unsafe {
+            self.reserve(1);
+            value.push_to_context(self.ctx);
+            self.pop_local()
+        }
+    }
+
+    /// Pushes the global object.
+    pub fn global_object(&'s self) -> Local<'s> {
+        unsafe {
+            self.reserve(1);
+            duk_sys::duk_push_global_object(self.ctx.raw);
+            self.pop_local()
+        }
+    }
+
+    /// Evaluates a string like `Context::eval_string`, and pushes the result.
+    pub fn eval_string(&'s self, string: &str) -> Result<Local<'s>> {
+        unsafe {
+            self.reserve(1);
+            let ret =
+                duk_sys::duk_peval_lstring(self.ctx.raw, string.as_ptr().cast(), string.len());
+            self.pop_local_or_error(ret)
+        }
+    }
+
+    /// Makes room for values that are pushed to create a local.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this is not the innermost scope, since leaving the inner scope would pop the
+    /// local, or if the value stack can't grow.
+    fn reserve(&self, extra: usize) {
+        assert_eq!(
+            self.depth,
+            self.ctx.shared.scope_depth.get(),
+            "locals can only be created in the innermost scope, push outer locals into it first"
+        );
+        self.grow(extra);
+    }
+
+    /// Makes room for temporary values, which are popped again before returning, also when this
+    /// is an outer scope.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value stack can't grow.
+    fn grow(&self, extra: usize) {
+        let extra = extra as duk_sys::duk_idx_t;
+        assert!(
+            1 == unsafe { duk_sys::duk_check_stack(self.ctx.raw, extra) },
+            "the value stack can't grow by {} values",
+            extra
+        );
+    }
+
+    /// Turns the value at the top of the stack into a local.
+    unsafe fn pop_local(&'s self) -> Local<'s> {
+        Local {
+            scope: self,
+            idx: duk_sys::duk_get_top_index(self.ctx.raw),
+        }
+    }
+
+    unsafe fn pop_local_or_error(&'s self, ret: duk_sys::duk_ret_t) -> Result<Local<'s>> {
+        if ret == 0 {
+            Ok(self.pop_local())
+        } else {
+            Err(self.ctx.pop_error())
+        }
+    }
+}
+
+impl<'s> Local<'s> {
+    /// Copies this local to a `Value` without throwing or panicking, like `Stack::get_value`.
+    pub fn to_value(&self) -> Value {
+        unsafe { crate::copy::get_value(self.scope.ctx.raw, self.idx) }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_deserialize<'de, T: serde::Deserialize<'de>>(&self) -> Result<T> {
+        unsafe { crate::deserialize_from_stack(self.scope.ctx.raw, self.idx) }
+            .map_err(|e| Error::De { raw: e })
+    }
+
+    /// Gets the property with the specified key, provided that this local is object coercible.
+    pub fn get(&self, name: &str) -> Result<Local<'s>> {
+        let raw = self.scope.ctx.raw;
+        unsafe {
+            self.scope.reserve(1);
+            if 0 == duk_sys::duk_is_object_coercible(raw, self.idx) {
+                Err(type_error(self.scope.ctx, "value is not object coercible"))
+            } else {
+                duk_sys::duk_push_lstring(raw, name.as_ptr().cast(), name.len());
+                duk_sys::duk_get_prop(raw, self.idx);
+                Ok(self.scope.pop_local())
+            }
+        }
+    }
+
+    /// Sets the property with the specified key to the specified value, provided that this local
+    /// is an object.
+    pub fn set(&self, name: &str, value: &dyn Argument) -> Result<()> {
+        let raw = self.scope.ctx.raw;
+        unsafe {
+            self.scope.grow(2);
+            if 0 == duk_sys::duk_is_object(raw, self.idx) {
+                Err(type_error(self.scope.ctx, "value is not an object"))
+            } else {
+                duk_sys::duk_push_lstring(raw, name.as_ptr().cast(), name.len());
+                value.push_to_context(self.scope.ctx);
+                duk_sys::duk_put_prop(raw, self.idx);
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls the function that this local points to without a `this` binding, like
+    /// `Reference::call`.
+    pub fn call(&self, args: &[&dyn Argument]) -> Result<Local<'s>> {
+        let raw = self.scope.ctx.raw;
+        unsafe {
+            self.scope.reserve(args.len() + 1);
+            duk_sys::duk_dup(raw, self.idx);
+            for arg in args {
+                arg.push_to_context(self.scope.ctx);
+            }
+            let ret = duk_sys::duk_pcall(raw, args.len() as duk_sys::duk_idx_t);
+            self.scope.pop_local_or_error(ret)
+        }
+    }
+
+    /// Calls a method on the object that this local points to, like `Reference::call_method`.
+    pub fn call_method(&self, name: &str, args: &[&dyn Argument]) -> Result<Local<'s>> {
+        let raw = self.scope.ctx.raw;
+        unsafe {
+            self.scope.reserve(args.len() + 1);
+            duk_sys::duk_push_lstring(raw, name.as_ptr().cast(), name.len());
+            for arg in args {
+                arg.push_to_context(self.scope.ctx);
+            }
+            let ret = duk_sys::duk_pcall_prop(raw, self.idx, args.len() as duk_sys::duk_idx_t);
+            self.scope.pop_local_or_error(ret)
+        }
+    }
+
+    /// Creates a `Reference` to the value of this local, which outlives the scope.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `context` is not the context of this local.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let reference = ctx.scope(|s| s.eval_string("[1, 2]").unwrap().persist(&ctx));
+    /// assert_eq!(
+    ///     duk::Value::Array(vec![duk::Value::Number(1.0), duk::Value::Number(2.0)]),
+    ///     reference.to_value()
+    /// );
+    /// ```
+    pub fn persist<'a>(&self, context: &'a Context) -> Reference<'a> {
+        if context.raw != self.scope.ctx.raw {
+            panic!("Tried to mix references coming from different contexts");
+        }
+        unsafe {
+            duk_sys::duk_dup(context.raw, self.idx);
+            context.pop_reference()
+        }
+    }
+}
+
+impl<'s> Argument for Local<'s> {
+    unsafe fn push_to_context(&self, context: &Context) {
+        if context.raw != self.scope.ctx.raw {
+            panic!("Tried to mix references coming from different contexts");
+        }
+
+        duk_sys::duk_dup(context.raw, self.idx);
+    }
+}
+
+impl<'c> Drop for ScopeGuard<'c> {
+    fn drop(&mut self) {
+        self.shared
+            .scope_depth
+            .set(self.shared.scope_depth.get() - 1);
+    }
+}
+
+unsafe fn type_error(ctx: &Context, message: &str) -> Error {
+    let msg = ffi::CString::new(message).unwrap();
+    duk_sys::duk_push_error_object(ctx.raw, duk_sys::DUK_ERR_TYPE_ERROR as i32, msg.as_ptr());
+    ctx.pop_error()
+}
@@ -426,6 +426,11 @@ impl SerializeStruct for MapSer {
     }
 }
 
+/// Serializes `value` and pushes the result onto the value stack of `ctx`.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context.
 pub unsafe fn serialize_to_stack<T: Serialize + ?Sized>(
     ctx: *mut duk_sys::duk_context,
     value: &T,
@@ -0,0 +1,464 @@
+//! Safe, typed access to the value stack, for values that `Reference`s or `Local`s don't fit.
+use std::fmt;
+use std::os;
+use std::slice;
+use std::str;
+use std::thread;
+
+use crate::Argument;
+use crate::Context;
+use crate::Error;
+use crate::JsError;
+use crate::JsErrorKind;
+use crate::Reference;
+use crate::Result;
+use crate::Value;
+
+/// A guard for the values that are pushed onto the value stack through it, as returned by
+/// `Context::stack`.
+///
+/// Indices are relative to the height of the stack when the guard was created, so values below
+/// it can't be read or popped.  Negative indices count from the top.  The stack grows as needed
+/// while pushing, and the values of the guard are popped when it is dropped.
+///
+/// Guards don't see what other code pushes or pops, so in debug builds, dropping a guard asserts
+/// that the stack has the height that the guard expects.  This catches raw `duk_sys` calls that
+/// leave the stack unbalanced, and guards that are used out of order.
+///
+/// # Examples
+///
+/// ```
+/// let ctx = duk::Context::new();
+/// let mut stack = ctx.stack();
+/// stack.push_number(1.5);
+/// stack.push_string("foo");
+/// assert_eq!(2, stack.len());
+/// assert_eq!(Some(duk::Type::Number), stack.type_of(0));
+/// assert_eq!(Some(1.5), stack.get_number(0));
+/// assert_eq!("foo", stack.require_string(-1).unwrap());
+///
+/// match stack.require_boolean(1) {
+///     Err(duk::Error::Js { raw }) => {
+///         assert_eq!(duk::JsErrorKind::Type, raw.kind);
+///         assert_eq!("boolean required, found string (stack index 1)", raw.message);
+///     }
+///     _ => unreachable!(),
+/// }
+/// ```
+pub struct Stack<'a> {
+    ctx: &'a Context,
+    /// The height of the stack when the guard was created.
+    base: duk_sys::duk_idx_t,
+    /// The number of values that were pushed through the guard and not popped.
+    len: duk_sys::duk_idx_t,
+}
+
+/// The types of the values on the value stack, as reported by `duk_get_type`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Type {
+    Undefined,
+    Null,
+    Boolean,
+    Number,
+    String,
+    Object,
+    /// A plain buffer, as opposed to buffer objects like `Uint8Array`.
+    Buffer,
+    Pointer,
+    /// A native function without an object representation, which most code can treat like a
+    /// function object.
+    LightFunc,
+}
+
+impl Context {
+    /// Creates a guard for pushing and reading values on the value stack, which pops them when it
+    /// is dropped.
+    pub fn stack(&self) -> Stack<'_> {
+        Stack {
+            ctx: self,
+            base: unsafe { duk_sys::duk_get_top(self.raw) },
+            len: 0,
+        }
+    }
+}
+
+impl<'a> Stack<'a> {
+    /// The number of values that were pushed through this guard and not popped.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_undefined(&mut self) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_undefined(raw) })
+    }
+
+    pub fn push_null(&mut self) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_null(raw) })
+    }
+
+    pub fn push_boolean(&mut self, value: bool) {
+        self.push_with(|raw| unsafe {
+            duk_sys::duk_push_boolean(raw, value as duk_sys::duk_bool_t)
+        })
+    }
+
+    pub fn push_number(&mut self, value: f64) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_number(raw, value) })
+    }
+
+    pub fn push_int(&mut self, value: i32) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_int(raw, value) })
+    }
+
+    pub fn push_uint(&mut self, value: u32) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_uint(raw, value) })
+    }
+
+    pub fn push_string(&mut self, value: &str) {
+        self.push_with(|raw| unsafe {
+            duk_sys::duk_push_lstring(raw, value.as_ptr().cast(), value.len());
+        })
+    }
+
+    /// Pushes a plain buffer with a copy of `value`.
+    pub fn push_bytes(&mut self, value: &[u8]) {
+        self.push_with(|raw| unsafe {
+            let data = duk_sys::duk_push_fixed_buffer(raw, value.len()) as *mut u8;
+            if !value.is_empty() {
+                data.copy_from_nonoverlapping(value.as_ptr(), value.len());
+            }
+        })
+    }
+
+    /// Pushes an opaque pointer, which scripts can pass around but not dereference.
+    // Duktape only stores the pointer.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn push_pointer(&mut self, value: *mut os::raw::c_void) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_pointer(raw, value) })
+    }
+
+    /// Pushes a new empty object.
+    pub fn push_object(&mut self) {
+        self.push_with(|raw| unsafe {
+            duk_sys::duk_push_object(raw);
+        })
+    }
+
+    /// Pushes a new empty array.
+    pub fn push_array(&mut self) {
+        self.push_with(|raw| unsafe {
+            duk_sys::duk_push_array(raw);
+        })
+    }
+
+    pub fn push_global_object(&mut self) {
+        self.push_with(|raw| unsafe { duk_sys::duk_push_global_object(raw) })
+    }
+
+    /// Pushes a copy of a value, which may be a `Value`, `Reference` or `Local`.
+    pub fn push(&mut self, value: &dyn Argument) {
+        let ctx = self.ctx;
+        self.push_with(|_| unsafe { value.push_to_context(ctx) })
+    }
+
+    /// Pops the value at the top.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this guard has no values.
+    pub fn pop(&mut self) {
+        self.pop_n(1)
+    }
+
+    /// Pops `count` values from the top.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this guard has fewer values.
+    pub fn pop_n(&mut self, count: usize) {
+        let count = count as duk_sys::duk_idx_t;
+        assert!(
+            count <= self.len && count <= self.top() - self.base,
+            "can't pop {} values from a stack guard with {}",
+            count,
+            self.len
+        );
+        unsafe { duk_sys::duk_pop_n(self.ctx.raw, count) };
+        self.len -= count;
+    }
+
+    /// The type of the value at `index`, or `None` if there is no value at `index`.
+    pub fn type_of(&self, index: i32) -> Option<Type> {
+        let idx = self.index(index)?;
+        let t = unsafe { duk_sys::duk_get_type(self.ctx.raw, idx) } as u32;
+        match t {
+            duk_sys::DUK_TYPE_UNDEFINED => Some(Type::Undefined),
+            duk_sys::DUK_TYPE_NULL => Some(Type::Null),
+            duk_sys::DUK_TYPE_BOOLEAN => Some(Type::Boolean),
+            duk_sys::DUK_TYPE_NUMBER => Some(Type::Number),
+            duk_sys::DUK_TYPE_STRING => Some(Type::String),
+            duk_sys::DUK_TYPE_OBJECT => Some(Type::Object),
+            duk_sys::DUK_TYPE_BUFFER => Some(Type::Buffer),
+            duk_sys::DUK_TYPE_POINTER => Some(Type::Pointer),
+            duk_sys::DUK_TYPE_LIGHTFUNC => Some(Type::LightFunc),
+            _ => None,
+        }
+    }
+
+    pub fn is_undefined(&self, index: i32) -> bool {
+        self.type_of(index) == Some(Type::Undefined)
+    }
+
+    pub fn is_null(&self, index: i32) -> bool {
+        self.type_of(index) == Some(Type::Null)
+    }
+
+    pub fn get_boolean(&self, index: i32) -> Option<bool> {
+        self.require_boolean(index).ok()
+    }
+
+    pub fn get_number(&self, index: i32) -> Option<f64> {
+        self.require_number(index).ok()
+    }
+
+    pub fn get_string(&self, index: i32) -> Option<String> {
+        self.require_string(index).ok()
+    }
+
+    /// Copies the data of a plain buffer or a buffer object, like `Uint8Array`.
+    pub fn get_bytes(&self, index: i32) -> Option<Vec<u8>> {
+        self.require_bytes(index).ok()
+    }
+
+    pub fn get_pointer(&self, index: i32) -> Option<*mut os::raw::c_void> {
+        self.require_pointer(index).ok()
+    }
+
+    pub fn get_object(&self, index: i32) -> Option<Reference<'a>> {
+        self.require_object(index).ok()
+    }
+
+    pub fn get_function(&self, index: i32) -> Option<Reference<'a>> {
+        self.require_function(index).ok()
+    }
+
+    /// Copies the value at `index` to a `Value`, without throwing or panicking.
+    ///
+    /// Holes of arrays become `Undefined`, and unpaired surrogates are replaced.  Values that
+    /// contain themselves, are nested too deeply or have throwing getters become `Value::Foreign`.
+    pub fn get_value(&self, index: i32) -> Option<Value> {
+        let idx = self.index(index)?;
+        Some(unsafe { crate::copy::get_value(self.ctx.raw, idx) })
+    }
+
+    pub fn require_undefined(&self, index: i32) -> Result<()> {
+        self.require(index, Type::Undefined).map(|_| ())
+    }
+
+    pub fn require_null(&self, index: i32) -> Result<()> {
+        self.require(index, Type::Null).map(|_| ())
+    }
+
+    pub fn require_boolean(&self, index: i32) -> Result<bool> {
+        let idx = self.require(index, Type::Boolean)?;
+        Ok(1 == unsafe { duk_sys::duk_get_boolean(self.ctx.raw, idx) })
+    }
+
+    pub fn require_number(&self, index: i32) -> Result<f64> {
+        let idx = self.require(index, Type::Number)?;
+        Ok(unsafe { duk_sys::duk_get_number(self.ctx.raw, idx) })
+    }
+
+    /// Requires a string, which Rust can only represent without unpaired surrogates.
+    pub fn require_string(&self, index: i32) -> Result<String> {
+        let idx = self.require(index, Type::String)?;
+        let bytes = unsafe {
+            let mut len = 0;
+            let data = duk_sys::duk_get_lstring(self.ctx.raw, idx, &mut len);
+            slice::from_raw_parts(data as *const u8, len)
+        };
+        str::from_utf8(bytes).map(str::to_owned).map_err(|_| {
+            js_error(
+                JsErrorKind::Type,
+                format!(
+                    "string without unpaired surrogates required (stack index {})",
+                    index
+                ),
+            )
+        })
+    }
+
+    /// Copies the data of a plain buffer or a buffer object, like `Uint8Array`.
+    pub fn require_bytes(&self, index: i32) -> Result<Vec<u8>> {
+        let idx = self.valid_index(index)?;
+        unsafe {
+            if 0 == duk_sys::duk_is_buffer_data(self.ctx.raw, idx) {
+                return Err(self.type_error(index, idx, "buffer"));
+            }
+            let mut size = 0;
+            let data = duk_sys::duk_get_buffer_data(self.ctx.raw, idx, &mut size);
+            if data.is_null() {
+                Ok(Vec::new())
+            } else {
+                Ok(slice::from_raw_parts(data as *const u8, size).to_vec())
+            }
+        }
+    }
+
+    pub fn require_pointer(&self, index: i32) -> Result<*mut os::raw::c_void> {
+        let idx = self.require(index, Type::Pointer)?;
+        Ok(unsafe { duk_sys::duk_get_pointer(self.ctx.raw, idx) })
+    }
+
+    pub fn require_object(&self, index: i32) -> Result<Reference<'a>> {
+        let idx = self.require(index, Type::Object)?;
+        Ok(self.reference(idx))
+    }
+
+    /// Requires a function object or a lightfunc.
+    pub fn require_function(&self, index: i32) -> Result<Reference<'a>> {
+        let idx = self.valid_index(index)?;
+        if 0 == unsafe { duk_sys::duk_is_callable(self.ctx.raw, idx) } {
+            return Err(self.type_error(index, idx, "function"));
+        }
+        Ok(self.reference(idx))
+    }
+
+    fn push_with<F>(&mut self, push: F)
+    where
+        F: FnOnce(*mut duk_sys::duk_context),
+    {
+        self.reserve(1);
+        push(self.ctx.raw);
+        self.len += 1;
+    }
+
+    /// Grows the value stack for `extra` more values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value stack can't grow.
+    fn reserve(&self, extra: duk_sys::duk_idx_t) {
+        assert!(
+            1 == unsafe { duk_sys::duk_check_stack(self.ctx.raw, extra) },
+            "the value stack can't grow by {} values",
+            extra
+        );
+    }
+
+    fn top(&self) -> duk_sys::duk_idx_t {
+        unsafe { duk_sys::duk_get_top(self.ctx.raw) }
+    }
+
+    /// The absolute index of a value of this guard.
+    fn index(&self, index: i32) -> Option<duk_sys::duk_idx_t> {
+        let top = self.top();
+        let idx = if index >= 0 {
+            self.base.checked_add(index)?
+        } else {
+            top.checked_add(index)?
+        };
+        if idx >= self.base && idx < top {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    fn valid_index(&self, index: i32) -> Result<duk_sys::duk_idx_t> {
+        self.index(index)
+            .ok_or_else(|| js_error(JsErrorKind::Range, format!("invalid stack index {}", index)))
+    }
+
+    fn require(&self, index: i32, expected: Type) -> Result<duk_sys::duk_idx_t> {
+        let idx = self.valid_index(index)?;
+        if self.type_of(index) == Some(expected) {
+            Ok(idx)
+        } else {
+            Err(self.type_error(index, idx, expected.name()))
+        }
+    }
+
+    fn type_error(&self, index: i32, idx: duk_sys::duk_idx_t, expected: &str) -> Error {
+        let found = match self.type_of(idx - self.base) {
+            Some(t) => t.name(),
+            None => "none",
+        };
+        js_error(
+            JsErrorKind::Type,
+            format!(
+                "{} required, found {} (stack index {})",
+                expected, found, index
+            ),
+        )
+    }
+
+    fn reference(&self, idx: duk_sys::duk_idx_t) -> Reference<'a> {
+        unsafe {
+            self.reserve(1);
+            duk_sys::duk_dup(self.ctx.raw, idx);
+            self.ctx.pop_reference()
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Stack<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stack")
+            .field("base", &self.base)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<'a> Drop for Stack<'a> {
+    fn drop(&mut self) {
+        let top = self.top();
+        if !thread::panicking() {
+            debug_assert_eq!(
+                self.base + self.len,
+                top,
+                "the value stack is unbalanced, a stack guard with {} values above {} found {}",
+                self.len,
+                self.base,
+                top
+            );
+        }
+        if top > self.base {
+            unsafe { duk_sys::duk_pop_n(self.ctx.raw, top - self.base) }
+        }
+    }
+}
+
+impl Type {
+    /// The name of the type in Duktape error messages.
+    fn name(self) -> &'static str {
+        match self {
+            Type::Undefined => "undefined",
+            Type::Null => "null",
+            Type::Boolean => "boolean",
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Object => "object",
+            Type::Buffer => "buffer",
+            Type::Pointer => "pointer",
+            Type::LightFunc => "lightfunc",
+        }
+    }
+}
+
+fn js_error(kind: JsErrorKind, message: String) -> Error {
+    Error::Js {
+        raw: JsError {
+            kind,
+            message,
+            file_name: None,
+            line_number: None,
+            stack: None,
+        },
+    }
+}
@@ -0,0 +1,232 @@
+//! Host functions that are implemented by Rust futures, and exposed to scripts as functions that
+//! return promises.
+use std::future;
+use std::mem;
+use std::os;
+use std::pin;
+use std::result;
+use std::task;
+
+use crate::Context;
+use crate::Error;
+use crate::Result;
+use crate::Value;
+
+/// An asynchronous host function, as registered with `Context::add_async_global_fn`.
+pub(crate) type AsyncFn = dyn Fn(Vec<Value>) -> TaskFuture;
+
+/// The boxed future of a call to an asynchronous host function.
+pub(crate) type TaskFuture =
+    pin::Pin<Box<dyn future::Future<Output = result::Result<Value, String>>>>;
+
+/// A call to an asynchronous host function whose promise has not settled yet.
+pub(crate) struct Task {
+    id: u32,
+    future: TaskFuture,
+}
+
+/// A future that drives the asynchronous host functions of a context, as returned by
+/// `Context::drive_tasks`.
+///
+/// It completes once no calls to asynchronous host functions are pending anymore.
+#[derive(Debug)]
+pub struct TaskDriver<'a> {
+    ctx: &'a Context,
+}
+
+impl Context {
+    /// Registers a global function that is implemented by a Rust future.
+    ///
+    /// Calling the function from Javascript calls `f` with the arguments of the call, and returns
+    /// a promise that settles when the returned future completes.  The promise is fulfilled with
+    /// the value that the future resolves to, or rejected with an `Error` with the message that
+    /// the future fails with.
+    ///
+    /// The arguments are copied without throwing: holes of arrays become `Undefined`, unpaired
+    /// surrogates are replaced, and values that contain themselves, are nested too deeply or have
+    /// throwing getters become `Value::Foreign`.
+    ///
+    /// Futures are only polled while the context is driven, by polling `drive_tasks` on any
+    /// executor or by calling `poll_tasks`.  Futures that are still pending when the context is
+    /// dropped are cancelled by dropping them.  This requires promise support, and fails with
+    /// `Error::Promise` otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder().with_promises().build();
+    /// ctx.add_async_global_fn("double", |args| async move {
+    ///     match args.first() {
+    ///         Some(duk::Value::Number(n)) => Ok(duk::Value::Number(n * 2.0)),
+    ///         _ => Err("expected a number".to_owned()),
+    ///     }
+    /// })
+    /// .unwrap();
+    ///
+    /// let promise = ctx.eval_string("double(21)").unwrap();
+    /// let waker = std::task::Waker::noop();
+    /// let mut cx = std::task::Context::from_waker(&waker);
+    /// assert!(ctx.poll_tasks(&mut cx).is_ready());
+    /// assert_eq!(duk::Value::Number(42.0), promise.await_promise().unwrap().to_value());
+    /// ```
+    pub fn add_async_global_fn<F, Fut>(&self, name: &str, f: F) -> Result<()>
+    where
+        F: Fn(Vec<Value>) -> Fut + 'static,
+        Fut: future::Future<Output = result::Result<Value, String>> + 'static,
+    {
+        if !self.promises_enabled() {
+            return Err(Error::Promise {
+                message: "promise support is not enabled".to_owned(),
+            });
+        }
+        let f: Box<Box<AsyncFn>> = Box::new(Box::new(move |args| Box::pin(f(args))));
+        let f_ptr = &*f as *const Box<AsyncFn> as *mut os::raw::c_void;
+        self.shared.async_fns.borrow_mut().push(f);
+        unsafe {
+            duk_sys::duk_push_c_function(self.raw, Some(async_fn_handler), duk_sys::DUK_VARARGS);
+            duk_sys::duk_push_pointer(self.raw, f_ptr);
+            duk_sys::duk_put_prop_string(self.raw, -2, crate::nul_str(b"closure\0"));
+            duk_sys::duk_put_global_lstring(self.raw, name.as_ptr() as *const i8, name.len());
+        }
+        Ok(())
+    }
+
+    /// The number of calls to asynchronous host functions whose futures have not completed yet.
+    pub fn pending_tasks(&self) -> usize {
+        self.shared.tasks.borrow().len()
+    }
+
+    /// Returns a future that drives the asynchronous host functions of this context until none
+    /// are pending.  It works with any executor, since it only relies on the wakers of the host
+    /// futures.
+    pub fn drive_tasks(&self) -> TaskDriver<'_> {
+        TaskDriver { ctx: self }
+    }
+
+    /// Polls the futures of pending calls to asynchronous host functions once, settles the
+    /// promises of those that completed, and runs microtasks, until that makes no more progress.
+    ///
+    /// Returns `Poll::Ready` once no calls are pending.  Otherwise, the futures arrange for the
+    /// waker of `cx` to be woken when this should be called again.
+    pub fn poll_tasks(&self, cx: &mut task::Context) -> task::Poll<Result<()>> {
+        loop {
+            let tasks = mem::take(&mut *self.shared.tasks.borrow_mut());
+            if tasks.is_empty() {
+                return task::Poll::Ready(Ok(()));
+            }
+
+            let mut pending = Vec::with_capacity(tasks.len());
+            let mut settled = false;
+            for mut task in tasks {
+                match task.future.as_mut().poll(cx) {
+                    task::Poll::Ready(result) => {
+                        unsafe { self.settle(task.id, result) };
+                        settled = true;
+                    }
+                    task::Poll::Pending => pending.push(task),
+                }
+            }
+            if let Err(e) = self.run_microtasks() {
+                self.shared.tasks.borrow_mut().splice(0..0, pending);
+                return task::Poll::Ready(Err(e));
+            }
+
+            // Calls made while settling and running microtasks need to be polled at least once.
+            let mut tasks = self.shared.tasks.borrow_mut();
+            let added = !tasks.is_empty();
+            tasks.splice(0..0, pending);
+            if !settled && !added {
+                return task::Poll::Pending;
+            }
+        }
+    }
+
+    /// Cancels pending calls by dropping their futures, before the context is destroyed.
+    pub(crate) fn cancel_tasks(&self) {
+        let tasks = mem::take(&mut *self.shared.tasks.borrow_mut());
+        drop(tasks);
+    }
+
+    /// Resolves or rejects the promise of the call with the specified id.
+    unsafe fn settle(&self, id: u32, result: result::Result<Value, String>) {
+        let raw = self.raw;
+        push_deferreds(raw);
+        duk_sys::duk_get_prop_index(raw, -1, id);
+        duk_sys::duk_del_prop_index(raw, -2, id);
+        // Stack: [ deferreds deferred ]
+        let name: &[u8] = if result.is_ok() {
+            b"resolve\0"
+        } else {
+            b"reject\0"
+        };
+        duk_sys::duk_get_prop_string(raw, -1, crate::nul_str(name));
+        match result {
+            Ok(value) => value.push(raw),
+            Err(message) => {
+                let message = std::ffi::CString::new(message).unwrap_or_default();
+                duk_sys::duk_push_error_object_raw(
+                    raw,
+                    duk_sys::DUK_ERR_ERROR as i32,
+                    std::ptr::null(),
+                    0,
+                    crate::nul_str(b"%s\0"),
+                    message.as_ptr(),
+                );
+            }
+        }
+        // The resolving functions of the polyfill only queue reactions, and don't throw.
+        duk_sys::duk_pcall(raw, 1);
+        duk_sys::duk_pop_3(raw);
+    }
+}
+
+impl<'a> future::Future for TaskDriver<'a> {
+    type Output = Result<()>;
+
+    fn poll(self: pin::Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        self.ctx.poll_tasks(cx)
+    }
+}
+
+/// Pushes the object that holds the promises and resolving functions of pending calls by id.
+unsafe fn push_deferreds(raw: *mut duk_sys::duk_context) {
+    duk_sys::duk_push_global_stash(raw);
+    if 0 == duk_sys::duk_get_prop_string(raw, -1, crate::nul_str(b"asyncTasks\0")) {
+        duk_sys::duk_pop(raw);
+        duk_sys::duk_push_object(raw);
+        duk_sys::duk_dup_top(raw);
+        duk_sys::duk_put_prop_string(raw, -3, crate::nul_str(b"asyncTasks\0"));
+    }
+    duk_sys::duk_remove(raw, -2);
+}
+
+unsafe extern "C" fn async_fn_handler(ctx: *mut duk_sys::duk_context) -> duk_sys::duk_ret_t {
+    let nargs = duk_sys::duk_get_top(ctx);
+
+    // Creating the promise is the only step that can throw, so it goes first.
+    duk_sys::duk_push_global_stash(ctx);
+    duk_sys::duk_get_prop_string(ctx, -1, crate::nul_str(b"deferPromise\0"));
+    duk_sys::duk_remove(ctx, -2);
+    duk_sys::duk_call(ctx, 0);
+
+    let mut args = Vec::with_capacity(nargs as usize);
+    for i in 0..nargs {
+        args.push(crate::copy::get_value(ctx, i));
+    }
+    let f = &*crate::get_closure::<Box<AsyncFn>>(ctx);
+    let future = f(args);
+    let id = Context::with_raw(ctx, |ctx| {
+        let id = ctx.shared.next_task_id.get();
+        ctx.shared.next_task_id.set(id.wrapping_add(1));
+        ctx.shared.tasks.borrow_mut().push(Task { id, future });
+        id
+    });
+
+    // Stack: [ args... deferred ]
+    push_deferreds(ctx);
+    duk_sys::duk_dup(ctx, -2);
+    duk_sys::duk_put_prop_index(ctx, -2, id);
+    duk_sys::duk_pop(ctx);
+    duk_sys::duk_get_prop_string(ctx, -1, crate::nul_str(b"promise\0"));
+    1
+}
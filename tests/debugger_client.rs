@@ -0,0 +1,254 @@
+//! Drives a debugged context with the Rust debug client.
+#![cfg(feature = "debugger")]
+
+use std::thread;
+
+const SCRIPT: &str = "function add(a, b) {
+    var sum = a + b;
+    return sum;
+}
+var x = 1;
+var y = add(x, 2);
+y * 2;
+";
+
+/// Evaluates scripts as `script.js` in a new context on another thread, with a debugger attached
+/// that the returned client is connected to.  The target thread returns the result of the last
+/// script.
+fn debug(
+    scripts: &'static [&'static str],
+) -> (
+    duk::Debugger,
+    thread::JoinHandle<Vec<Result<duk::Value, String>>>,
+) {
+    let listener = duk::TcpDebugListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let target = thread::spawn(move || {
+        let ctx = duk::Context::new();
+        ctx.attach_debugger(listener.accept().unwrap());
+        scripts
+            .iter()
+            .map(|script| {
+                ctx.eval_string_with_filename("script.js", script)
+                    .map(|r| r.to_value())
+                    .map_err(|e| e.to_string())
+            })
+            .collect()
+    });
+    (duk::Debugger::connect(addr).unwrap(), target)
+}
+
+#[test]
+fn breakpoints_and_stepping() {
+    let (mut debugger, target) = debug(&[SCRIPT]);
+    assert!(debugger.version_line().starts_with("2 "));
+    assert_eq!(20500, debugger.basic_info().unwrap().version / 100 * 100);
+
+    // Execution starts out paused.
+    assert!(debugger.wait_for_pause().unwrap().paused);
+    assert_eq!(0, debugger.add_breakpoint("script.js", 2).unwrap());
+    assert_eq!(1, debugger.add_breakpoint("script.js", 7).unwrap());
+    let breakpoints = debugger.breakpoints().unwrap();
+    assert_eq!(2, breakpoints.len());
+    assert_eq!("script.js", breakpoints[0].file_name);
+    assert_eq!(2, breakpoints[0].line);
+    debugger.resume().unwrap();
+
+    let status = debugger.wait_for_pause().unwrap();
+    assert_eq!(
+        ("script.js", "add", 2),
+        (&*status.file_name, &*status.function_name, status.line)
+    );
+    let stack = debugger.call_stack().unwrap();
+    assert_eq!("add", stack[0].function_name);
+    assert_eq!(2, stack[0].line);
+    assert_eq!(6, stack[1].line);
+
+    let mut locals = debugger.locals(0).unwrap();
+    locals.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        vec![
+            ("a".to_owned(), duk::DValue::Integer(1)),
+            ("b".to_owned(), duk::DValue::Integer(2)),
+            ("sum".to_owned(), duk::DValue::Undefined),
+        ],
+        locals
+    );
+    assert_eq!(
+        Ok(duk::DValue::Integer(3)),
+        debugger.eval(Some(0), "a + b").unwrap()
+    );
+    assert_eq!(
+        Ok(duk::DValue::Integer(1)),
+        debugger.eval(Some(1), "x").unwrap()
+    );
+    let error = debugger.eval(Some(0), "nope").unwrap().unwrap_err();
+    assert!(error.starts_with("ReferenceError"), "{}", error);
+
+    debugger.step_over().unwrap();
+    assert_eq!(3, debugger.wait_for_pause().unwrap().line);
+    assert_eq!(
+        Some(duk::DValue::Integer(3)),
+        debugger.get_var(0, "sum").unwrap()
+    );
+    assert_eq!(None, debugger.get_var(0, "missing").unwrap());
+    debugger
+        .put_var(0, "sum", duk::DValue::Integer(10))
+        .unwrap();
+
+    debugger.step_out().unwrap();
+    let status = debugger.wait_for_pause().unwrap();
+    assert_eq!(6, status.line);
+    debugger.remove_breakpoint(0).unwrap();
+    assert!(debugger.remove_breakpoint(5).is_err());
+    debugger.resume().unwrap();
+
+    assert_eq!(7, debugger.wait_for_pause().unwrap().line);
+    assert_eq!(
+        Ok(duk::DValue::Integer(10)),
+        debugger.eval(None, "y").unwrap()
+    );
+    debugger.resume().unwrap();
+
+    assert_eq!(vec![Ok(duk::Value::Number(20.0))], target.join().unwrap());
+}
+
+#[test]
+fn pause_on_exception() {
+    const THROWING: &str = "try { throw new Error('caught'); } catch (e) {}\n\
+                            throw new Error('uncaught');";
+    let (mut debugger, target) = debug(&[THROWING, THROWING, "debugger;"]);
+    assert!(debugger.wait_for_pause().unwrap().paused);
+    debugger.resume().unwrap();
+
+    // Only the uncaught error pauses.
+    assert_eq!(2, debugger.wait_for_pause().unwrap().line);
+    debugger.set_pause_on_exception(false);
+    debugger.resume().unwrap();
+
+    // The second uncaught error is resumed by the client, so the next pause is the debugger
+    // statement.
+    let status = debugger.wait_for_pause().unwrap();
+    assert_eq!(1, status.line);
+    debugger.resume().unwrap();
+
+    let results = target.join().unwrap();
+    assert!(results[0].as_ref().unwrap_err().contains("uncaught"));
+    assert!(results[1].as_ref().unwrap_err().contains("uncaught"));
+    assert_eq!(Ok(duk::Value::Undefined), results[2]);
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+enum PluginRequest {
+    Metadata { name: String },
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct PluginMetadata {
+    name: String,
+    version: u32,
+}
+
+#[test]
+fn app_notify_and_request() {
+    let listener = duk::TcpDebugListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let target = thread::spawn(move || {
+        let ctx = duk::Context::new();
+        ctx.set_debugger_request_handler(|request: PluginRequest| match request {
+            PluginRequest::Metadata { ref name } if name == "greeter" => Ok(PluginMetadata {
+                name: name.clone(),
+                version: 3,
+            }),
+            PluginRequest::Metadata { name } => Err(format!("no plugin named {}", name)),
+        });
+        assert!(!ctx.debugger_notify(&"not attached").unwrap());
+        ctx.attach_debugger(listener.accept().unwrap());
+        let loaded = PluginMetadata {
+            name: "greeter".to_owned(),
+            version: 3,
+        };
+        assert!(ctx.debugger_notify(&loaded).unwrap());
+        let done = ctx.eval_string("'done'").unwrap().to_value();
+        done
+    });
+    let mut debugger = duk::Debugger::connect(addr).unwrap();
+
+    let notification = debugger.next_notification().unwrap();
+    let loaded: PluginMetadata = notification.app_payload().unwrap().unwrap();
+    assert_eq!("greeter", loaded.name);
+
+    assert!(debugger.wait_for_pause().unwrap().paused);
+    let metadata: PluginMetadata = debugger
+        .app_request(&PluginRequest::Metadata {
+            name: "greeter".to_owned(),
+        })
+        .unwrap();
+    assert_eq!(3, metadata.version);
+    let error = debugger
+        .app_request::<_, PluginMetadata>(&PluginRequest::Metadata {
+            name: "other".to_owned(),
+        })
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("no plugin named other"),
+        "{}",
+        error
+    );
+    debugger.resume().unwrap();
+
+    assert_eq!(
+        duk::Value::String("done".to_owned()),
+        target.join().unwrap()
+    );
+}
+
+/// A stream that replaces every `~` that the client writes with a byte that is never valid UTF-8.
+struct NonUtf8Stream(std::net::TcpStream);
+
+impl std::io::Read for NonUtf8Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl std::io::Write for NonUtf8Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let buf: Vec<u8> = buf
+            .iter()
+            .map(|&b| if b == b'~' { 0xff } else { b })
+            .collect();
+        self.0.write(&buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[test]
+fn app_request_not_utf8() {
+    let listener = duk::TcpDebugListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let target = thread::spawn(move || {
+        let ctx = duk::Context::new();
+        ctx.set_debugger_request_handler(|request: String| -> Result<String, String> {
+            panic!("unexpected request {:?}", request)
+        });
+        ctx.attach_debugger(listener.accept().unwrap());
+        let done = ctx.eval_string("'done'").unwrap().to_value();
+        done
+    });
+    let stream = std::net::TcpStream::connect(addr).unwrap();
+    let mut debugger = duk::Debugger::new(NonUtf8Stream(stream)).unwrap();
+
+    assert!(debugger.wait_for_pause().unwrap().paused);
+    let error = debugger.app_request::<_, String>(&"~").unwrap_err();
+    assert!(error.to_string().contains("not valid UTF-8"), "{}", error);
+    debugger.resume().unwrap();
+
+    assert_eq!(
+        duk::Value::String("done".to_owned()),
+        target.join().unwrap()
+    );
+}
@@ -0,0 +1,76 @@
+//! Drives the TCP debug transport with a loopback client, like `duk_debug.js` would.
+#![cfg(feature = "debugger")]
+
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+use std::thread;
+use std::time;
+
+const RESUME: &[u8] = &[0x01, 0x93, 0x00];
+const DETACH: &[u8] = &[0x01, 0x9f, 0x00];
+
+/// Connects to the debugger and checks the version identification line.
+fn connect(addr: net::SocketAddr) -> (net::TcpStream, String) {
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    (stream, line)
+}
+
+/// Calls `Context::debugger_cooperate` until the debugger is attached or detached.
+fn cooperate_until(ctx: &duk::Context, attached: bool) {
+    for _ in 0..500 {
+        ctx.debugger_cooperate();
+        if ctx.debugger_attached() == attached {
+            return;
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    panic!("debugger did not become attached={}", attached);
+}
+
+#[test]
+fn attach_detach_reattach() {
+    let ctx = duk::Context::new();
+    let listener = duk::TcpDebugListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let (mut stream, line) = connect(addr);
+        stream.write_all(RESUME).unwrap();
+        stream.write_all(DETACH).unwrap();
+        // Duktape answers with REP messages and closes the connection once detached.
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        line
+    });
+    let transport = listener.accept().unwrap();
+    ctx.attach_debugger(transport);
+    assert!(ctx.debugger_attached());
+    cooperate_until(&ctx, false);
+    let line = client.join().unwrap();
+    let version = format!("{} ", duk_sys::DUK_DEBUG_PROTOCOL_VERSION);
+    assert!(line.starts_with(&version), "{:?}", line);
+
+    // A second client can attach once the first one is gone, and detaches by disconnecting.
+    assert!(!listener.poll_attach(&ctx).unwrap());
+    let client = thread::spawn(move || {
+        let (mut stream, line) = connect(addr);
+        stream.write_all(RESUME).unwrap();
+        line
+    });
+    for _ in 0..500 {
+        if listener.poll_attach(&ctx).unwrap() {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    assert!(ctx.debugger_attached());
+    assert!(client.join().unwrap().starts_with(&version));
+    let value = ctx.eval_string("[1, 2, 3].length").unwrap().to_value();
+    assert_eq!(duk::Value::Number(3.0), value);
+    cooperate_until(&ctx, false);
+}